@@ -0,0 +1,61 @@
+//! Compares the compression algorithms on a large chain shaped like the ones
+//! the REPL makes: mostly empty blocks, with a transaction now and then.
+//! Prints how big the chain is under each before timing them.
+
+#[macro_use]
+extern crate criterion;
+extern crate bincode;
+extern crate naivechain_rs;
+
+use bincode::{serialize, Infinite};
+use criterion::Criterion;
+
+use naivechain_rs::block::{Block, Hash32Byte};
+use naivechain_rs::compression::Compression;
+use naivechain_rs::keys::Keypair;
+use naivechain_rs::transaction::{BlockBody, Transaction, TransactionKind, pack_block};
+
+const BLOCKS: u64 = 10_000;
+
+fn large_chain() -> Vec<Block> {
+    let keypair = Keypair::from_seed(&[7; 32]);
+    let mut chain = vec![Block::genesis(Hash32Byte([0; 32]))];
+    for height in 1..BLOCKS {
+        let transactions = if height % 10 == 0 {
+            let payload = format!("record {}", height).into_bytes();
+            vec![Transaction::new(&keypair, height / 10, 0, TransactionKind::Data(payload))]
+        } else {
+            Vec::new()
+        };
+        let body = BlockBody { reward: None, transactions };
+        let data = pack_block(&body).unwrap();
+        let block = Block::new(chain.last().unwrap(), height * 1_000_000_000, Hash32Byte([0; 32]),
+                               body.transactions_root(), data);
+        chain.push(block);
+    }
+    chain
+}
+
+const ALGORITHMS: [(&str, Compression); 3] = [
+    ("none", Compression::None),
+    ("lz4", Compression::Lz4),
+    ("zstd", Compression::Zstd),
+];
+
+fn compression(c: &mut Criterion) {
+    let serialized = serialize(&large_chain(), Infinite).unwrap();
+    for &(name, algorithm) in &ALGORITHMS {
+        let compressed = algorithm.compress(&serialized);
+        println!("{} blocks with {}: {} bytes, {:.1}% of uncompressed",
+            BLOCKS, name, compressed.len(), 100.0 * compressed.len() as f64 / serialized.len() as f64);
+    }
+
+    for &(name, algorithm) in &ALGORITHMS {
+        c.bench_function(&format!("compress chain ({})", name), |b| b.iter(|| algorithm.compress(&serialized)));
+        let compressed = algorithm.compress(&serialized);
+        c.bench_function(&format!("decompress chain ({})", name), |b| b.iter(|| algorithm.decompress(&compressed, serialized.len()).unwrap()));
+    }
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -0,0 +1,172 @@
+//! Addresses of other nodes, so a node can find peers without the
+//! nameserver. Addresses come from the seed list, the nameserver and peers'
+//! `Addr` messages, and each records when we last managed to connect to
+//! it. Changes are only written out when the book is saved, so they can be
+//! saved together every so often.
+
+use bincode::{serialize, deserialize, Infinite};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use time;
+
+use atomic::write_atomically;
+
+
+/// Most addresses kept. Once full, the least recently seen go first.
+pub const MAX_ADDRESSES: usize = 1000;
+
+/// Most addresses sent in, or taken from, one `Addr` message.
+pub const MAX_ADDR: usize = 100;
+
+/// `addr` with IPv4 addresses that came in over IPv6 turned back into
+/// plain IPv4, so each node is only in the book once.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub struct AddressBook {
+    /// When we last connected to each address, in seconds since the
+    /// epoch, or 0 if we only know of it.
+    last_seen: BTreeMap<SocketAddr, u64>,
+    path: Option<PathBuf>,
+    /// Whether there are changes that haven't been saved.
+    dirty: bool,
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook { last_seen: BTreeMap::new(), path: None, dirty: false }
+    }
+
+    /// Loads the addresses saved at `path`, if there are any, and saves
+    /// changes to them there.
+    pub fn load(path: &Path) -> io::Result<AddressBook> {
+        let mut book = AddressBook::new();
+        book.path = Some(path.to_path_buf());
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut serialized = Vec::new();
+                file.read_to_end(&mut serialized)?;
+                book.last_seen = deserialize(&serialized)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Not an address book"))?;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(book)
+    }
+
+    /// Saves the addresses, if they've changed since they last were.
+    pub fn save(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(ref path) = self.path {
+            write_atomically(path, &serialize(&self.last_seen, Infinite).unwrap())?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Notes an address we've heard of, if it's new.
+    pub fn add(&mut self, addr: SocketAddr) {
+        let addr = canonical(addr);
+        if let Entry::Vacant(entry) = self.last_seen.entry(addr) {
+            entry.insert(0);
+            self.dirty = true;
+            self.evict();
+        }
+    }
+
+    /// Notes that we've just connected to `addr`.
+    pub fn seen(&mut self, addr: SocketAddr) {
+        self.last_seen.insert(canonical(addr), time::get_time().sec as u64);
+        self.dirty = true;
+        self.evict();
+    }
+
+    /// Forgets `addr`, say because it turned out to be our own.
+    pub fn remove(&mut self, addr: SocketAddr) {
+        self.dirty |= self.last_seen.remove(&canonical(addr)).is_some();
+    }
+
+    fn evict(&mut self) {
+        while self.last_seen.len() > MAX_ADDRESSES {
+            let oldest = *self.last_seen.iter().min_by_key(|&(_, seen)| *seen).unwrap().0;
+            self.last_seen.remove(&oldest);
+        }
+    }
+
+    /// Up to `count` addresses, most recently seen first.
+    pub fn recent(&self, count: usize) -> Vec<SocketAddr> {
+        let mut addrs: Vec<(&SocketAddr, &u64)> = self.last_seen.iter().collect();
+        addrs.sort_by(|a, b| b.1.cmp(a.1));
+        addrs.into_iter().take(count).map(|(addr, _)| *addr).collect()
+    }
+}
+
+impl Default for AddressBook {
+    fn default() -> AddressBook {
+        AddressBook::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32)), 8000)
+    }
+
+    #[test]
+    fn ipv4_over_ipv6_is_the_same_address() {
+        let mut book = AddressBook::new();
+        book.add("10.0.0.1:8000".parse().unwrap());
+        book.add("[::ffff:10.0.0.1]:8000".parse().unwrap());
+        assert_eq!(book.recent(MAX_ADDR), vec![addr(1)]);
+        book.remove("[::ffff:10.0.0.1]:8000".parse().unwrap());
+        assert!(book.recent(MAX_ADDR).is_empty());
+    }
+
+    #[test]
+    fn addresses_never_connected_to_go_first() {
+        let mut book = AddressBook::new();
+        book.seen(addr(0));
+        for i in 1..MAX_ADDRESSES + 10 {
+            book.add(addr(i));
+        }
+        let recent = book.recent(MAX_ADDRESSES + 10);
+        assert_eq!(recent.len(), MAX_ADDRESSES);
+        assert_eq!(recent[0], addr(0));
+    }
+
+    #[test]
+    fn saved_addresses_load_again() {
+        let path = env::temp_dir().join(format!("addrbook-test-{}.peers", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut book = AddressBook::load(&path).unwrap();
+        assert!(book.recent(MAX_ADDR).is_empty());
+        book.add(addr(1));
+        book.seen(addr(2));
+        book.save().unwrap();
+        assert_eq!(AddressBook::load(&path).unwrap().recent(MAX_ADDR), vec![addr(2), addr(1)]);
+
+        // unchanged books aren't written out again
+        fs::remove_file(&path).unwrap();
+        book.save().unwrap();
+        assert!(!path.exists());
+
+        fs::write(&path, b"not an address book").unwrap();
+        assert_eq!(AddressBook::load(&path).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        fs::remove_file(&path).unwrap();
+    }
+}
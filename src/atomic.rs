@@ -0,0 +1,58 @@
+//! Saving files so that a crash or a full disk partway through leaves the
+//! old contents in place rather than half of the new ones. The new contents
+//! go to a file beside the old one, which is synced and then renamed over
+//! it.
+
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+
+/// Replaces the file at `path` with `contents`, all at once.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temporary = OsString::from(path.as_os_str());
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let written = File::create(&temporary).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()
+    });
+    if let Err(e) = written.and_then(|()| fs::rename(&temporary, path)) {
+        let _ = fs::remove_file(&temporary);
+        return Err(e);
+    }
+
+    // the rename only survives a crash once the directory is synced too
+    let directory = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    File::open(directory)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn files_are_replaced_whole() {
+        let path = env::temp_dir().join(format!("atomic-test-{}", std::process::id()));
+        fs::write(&path, b"old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+        fs::remove_file(&path).unwrap();
+
+        // a file can't replace a directory, and nothing is left behind trying
+        fs::create_dir_all(path.join("inside")).unwrap();
+        assert!(write_atomically(&path, b"new").is_err());
+        assert!(path.join("inside").is_dir());
+        assert!(!Path::new(&format!("{}.tmp", path.display())).exists());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+//! Exponential backoff for dialing. Each failure to reach an address
+//! doubles how long we leave it before trying again, up to a limit, and
+//! reaching it starts it over.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+
+/// How long to leave an address after it first fails.
+pub const INITIAL_DELAY: Duration = Duration::from_secs(5);
+
+/// The longest an address is left, however often it's failed.
+pub const MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+
+pub struct Backoff {
+    /// How many times in a row each address has failed, and when it can be
+    /// tried again.
+    failures: HashMap<SocketAddr, (u32, Instant)>,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { failures: HashMap::new() }
+    }
+
+    /// Whether it's time to try `addr` again.
+    pub fn ready(&self, addr: &SocketAddr) -> bool {
+        self.failures.get(addr).is_none_or(|&(_, retry)| Instant::now() >= retry)
+    }
+
+    pub fn failed(&mut self, addr: SocketAddr) {
+        let failures = self.failures.get(&addr).map_or(0, |&(failures, _)| failures) + 1;
+        let delay = INITIAL_DELAY.checked_mul(1 << (failures - 1).min(31))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY));
+        self.failures.insert(addr, (failures, Instant::now() + delay));
+    }
+
+    pub fn succeeded(&mut self, addr: &SocketAddr) {
+        self.failures.remove(addr);
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long `addr` is left for after it last failed.
+    fn delay(backoff: &Backoff, addr: &SocketAddr, failed_at: Instant) -> Duration {
+        backoff.failures[addr].1.duration_since(failed_at)
+    }
+
+    #[test]
+    fn failures_double_the_delay_up_to_the_limit() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+        let mut backoff = Backoff::new();
+        assert!(backoff.ready(&addr));
+        let mut expected = INITIAL_DELAY;
+        for _ in 0..12 {
+            let before = Instant::now();
+            backoff.failed(addr);
+            assert!(!backoff.ready(&addr));
+            let delay = delay(&backoff, &addr, before);
+            assert!(delay >= expected && delay < expected + Duration::from_secs(1));
+            expected = (expected * 2).min(MAX_DELAY);
+        }
+        assert_eq!(expected, MAX_DELAY);
+        for _ in 0..40 {
+            backoff.failed(addr);
+        }
+        assert!(delay(&backoff, &addr, Instant::now()) <= MAX_DELAY);
+    }
+
+    #[test]
+    fn success_starts_over() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+        let other = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let mut backoff = Backoff::new();
+        backoff.failed(addr);
+        backoff.failed(addr);
+        assert!(backoff.ready(&other));
+        backoff.succeeded(&addr);
+        assert!(backoff.ready(&addr));
+        let before = Instant::now();
+        backoff.failed(addr);
+        assert!(delay(&backoff, &addr, before) < INITIAL_DELAY + Duration::from_secs(1));
+    }
+}
@@ -1,6 +1,5 @@
 extern crate serde;
 
-#[macro_use]
 extern crate serde_derive;
 extern crate crypto;
 extern crate byteorder;
@@ -10,36 +9,59 @@ extern crate time;
 extern crate getopts;
 
 use std::io::prelude::*;
-use std::error::Error;
+use std::io::SeekFrom;
 use std::fs::File;
 use std::path::Path;
 use std::fs::OpenOptions;
-use std::net::{TcpStream, TcpListener};
+use std::collections::{HashMap, HashSet};
+use std::net::{TcpStream, TcpListener, SocketAddr};
 use std::net::ToSocketAddrs;
-use std::time::Duration;
 use std::thread;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
-use bincode::{serialize, deserialize, Infinite};
 
 extern crate naivechain_rs;
-use naivechain_rs::block::{check_chain, Block};
+use naivechain_rs::block::{check_chain, check_timestamp, decode_chain, encode_chain, median_time_past, ns, Block, Hash32Byte};
+use naivechain_rs::checkpoint::Checkpoints;
+use naivechain_rs::compression::{Compression, negotiate, pack_chainfile, unpack_chainfile};
+use naivechain_rs::contract::{BlockContext, FUEL_PER_FEE, MAX_FUEL, contract_address};
+use naivechain_rs::chunks::{reassemble, split};
+use naivechain_rs::envelope::{Envelope, addressed_to};
+use naivechain_rs::notary::{Receipt, digest_file};
+use naivechain_rs::script::parse_hex;
+use naivechain_rs::transaction::{Transaction, TransactionKind, TimeLock, Reward, BlockBody, pack_block, BLOCK_CAPACITY};
+use naivechain_rs::mempool::Mempool;
+use naivechain_rs::state::{Ledger, AccountLedger, parse_allocation};
+use naivechain_rs::utxo::UtxoLedger;
+use naivechain_rs::keys::{Address, Keypair};
+use naivechain_rs::wallet::{Wallet, build_payment, read_password};
 
 use naivechain_rs::message;
-use message::{ClientMessage, ClientToNameserverMessage, NameserverToClientMessage};
+use message::{CHAIN_PAGE, ClientMessage, ClientToNameserverMessage, NameserverToClientMessage};
 
 use naivechain_rs::connection;
 use connection::Connection;
 
+use naivechain_rs::network::{Direction, Event, Network, Peer, RateLimits};
+use naivechain_rs::gossip::{Gossip, DEFAULT_FANOUT, MAX_INVENTORY, REQUEST_TIMEOUT};
+use naivechain_rs::reputation::{Misbehavior, PeerKey, Reputation, DEFAULT_BAN_SECONDS};
+use naivechain_rs::ratelimit::Slots;
+use naivechain_rs::addrbook::{AddressBook, canonical, MAX_ADDR, MAX_ADDRESSES};
+use naivechain_rs::backoff::Backoff;
+use naivechain_rs::kademlia::{Contact, Target, K};
+use naivechain_rs::dht::Dht;
+
 
 fn print_usage(program: &str, opts: getopts::Options) {
     let brief = format!("Usage: {} OPTIONS", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn is_chain_better(their_chain: &Vec<Block>, my_chain: &Vec<Block>) -> bool {
-    if check_chain(their_chain) {
+fn is_chain_better(their_chain: &Vec<Block>, my_chain: &Vec<Block>, checkpoints: &Checkpoints) -> bool {
+    if check_chain(their_chain) && checkpoints.accepts_chain(their_chain, my_chain) {
         if their_chain.len() > my_chain.len() {
             return true;
         }
@@ -60,85 +82,785 @@ fn ns_to_spec(ns: u64) -> time::Timespec {
     }
 }
 
-fn handle_peer(connection: Arc<Mutex<Connection>>, chain: Arc<Mutex<Vec<Block>>>) {
-    {
-        let connection = connection.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_millis(100));
-                // println!("reading!");
-                let mut connection = connection.lock().unwrap();
-                let incoming = connection.read_message();
-                match incoming {
-                    Ok(msg) => match msg {
-                        Some(ClientMessage::QueryChain) => {
-                            let chain = chain.lock().unwrap();
-                            connection.write_message(&ClientMessage::Chain(chain.clone()));
-                        },
-                        Some(ClientMessage::Chain(their_chain)) => {
-                            {
-                                let mut my_chain = chain.lock().unwrap();
-                                if is_chain_better(&their_chain, &my_chain) {
-                                    println!("Accepted new chain from {}", connection.peer_addr().unwrap());
-                                    *my_chain = their_chain;
-                                }
-                            }
-                        },
-                        Some(ClientMessage::NewBlock(block)) => {
-                            let mut chain = chain.lock().unwrap();
-                            if block.previous_hash == chain.last().unwrap().hash {
-                                println!("Received block {} from {}", block.block_num, connection.peer_addr().unwrap());
-                                chain.push(block);
-                            } else {
-                                // something weird is going on, better check their whole chain
-                                println!("Received a block which doesn't match our chain");
-                                connection.write_message(&ClientMessage::QueryChain);
-                            }
+const MEMPOOL_SIZE: usize = 1024 * 1024;
+
+/// Most chains we'll be sending to peers at once, since each is framed
+/// separately.
+const MAX_CHAIN_REPLIES: usize = 8;
+
+const DEFAULT_MAX_INBOUND: usize = 64;
+const DEFAULT_MESSAGE_RATE: f64 = 100.0;
+const DEFAULT_BYTE_RATE: f64 = 1024.0 * 1024.0;
+
+/// How many of the most recently seen addresses in the address book are
+/// tried at startup.
+const BOOTSTRAP_PEERS: usize = 8;
+
+/// How many connections we keep to peers we dialed, unless the node says
+/// otherwise.
+const DEFAULT_OUTBOUND: usize = 8;
+
+/// How long to give a peer to accept a connection.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often we check we're connected to enough peers.
+const MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often we ask the nameserver for peers.
+const NAMESERVER_REFRESH: Duration = Duration::from_secs(60);
+
+/// How long the nameserver gets to accept a connection or answer, so it
+/// can't hold up keeping us connected to peers.
+const NAMESERVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What handling peers' messages needs.
+#[derive(Clone)]
+struct Node {
+    chain: Arc<Mutex<Vec<Block>>>,
+    checkpoints: Arc<Checkpoints>,
+    ledger: Arc<Mutex<Box<dyn Ledger>>>,
+    mempool: Arc<Mutex<Mempool>>,
+    gossip: Arc<Mutex<Gossip>>,
+    reputation: Arc<Mutex<Reputation>>,
+    network: Network,
+    addresses: Arc<Mutex<AddressBook>>,
+    dht: Dht,
+    keypair: Keypair,
+    allowed_keys: Arc<Vec<Address>>,
+    compression: Compression,
+    /// The port we listen on.
+    port: u16,
+    chain_replies: Slots,
+}
+
+/// What we've asked a peer for and not had yet, so we can tell when it
+/// sends us things we didn't ask for, and the one page of our chain it can
+/// be waiting on from us. Asking again while that's on its way gets
+/// nothing more.
+struct Requests {
+    /// The pages of the peer's chain we've had so far, while we're asking
+    /// for it.
+    chain: Option<Vec<Block>>,
+    /// Blocks we've asked for and when, at most `MAX_INVENTORY` at a time.
+    /// Ones that haven't come within `REQUEST_TIMEOUT` are given up on.
+    blocks: HashMap<Hash32Byte, Instant>,
+    addrs: bool,
+    chain_reply: Slots,
+}
+
+impl Requests {
+    fn new() -> Requests {
+        Requests { chain: None, blocks: HashMap::new(), addrs: false, chain_reply: Slots::new(1) }
+    }
+}
+
+/// Handles what peers say, in the order they said it, until the network
+/// stops.
+fn handle_events(events: Receiver<Event>, node: Node) {
+    let mut requests: HashMap<SocketAddr, Requests> = HashMap::new();
+    for event in events {
+        match event {
+            Event::Connected(peer) => {
+                peer.send(&ClientMessage::Hello { compression: node.compression.offer(), port: node.port });
+                peer.send(&ClientMessage::QueryChain(0));
+                peer.send(&ClientMessage::GetAddr);
+                let mut peer_requests = Requests::new();
+                peer_requests.chain = Some(Vec::new());
+                peer_requests.addrs = true;
+                requests.insert(peer.addr(), peer_requests);
+            },
+            Event::Message(peer, message) => {
+                let requests = requests.entry(peer.addr()).or_insert_with(Requests::new);
+                handle_message(&peer, *message, &node, requests);
+            },
+            Event::Malformed(peer, e) => {
+                println!("Bad message from {}: {}", peer.addr(), e);
+                punish(&node, &peer, Misbehavior::MalformedFrame);
+            },
+            Event::RateLimited(peer) => punish(&node, &peer, Misbehavior::Flooding),
+            Event::Disconnected(peer) => {
+                requests.remove(&peer.addr());
+                println!("Lost {}", peer.addr());
+                if let Some(id) = peer.id() {
+                    node.dht.failed(&id);
+                }
+            },
+        }
+    }
+}
+
+/// Charges `peer` for misbehaving, and drops it if that gets it banned.
+fn punish(node: &Node, peer: &Peer, misbehavior: Misbehavior) {
+    let key = PeerKey::new(peer.addr(), peer.id());
+    let banned = node.reputation.lock().unwrap().misbehaved(peer.addr(), peer.id(), misbehavior);
+    match banned {
+        Ok(false) => return,
+        Ok(true) => println!("Banned {} at {} for {:?}", key, peer.addr().ip(), misbehavior),
+        Err(e) => println!("Banned {} at {} for {:?}, but couldn't save the ban list: {}", key, peer.addr().ip(), misbehavior, e),
+    }
+    peer.disconnect();
+}
+
+/// Tells a few peers about a block we've just accepted, other than the one
+/// it came from.
+fn announce(network: &Network, gossip: &Mutex<Gossip>, hash: Hash32Byte, from: Option<&Peer>) {
+    let peers = network.peers().into_iter()
+        .filter(|peer| from.is_none_or(|from| peer.addr() != from.addr()))
+        .collect();
+    let targets = gossip.lock().unwrap().relay_targets(peers);
+    for peer in targets {
+        peer.send(&ClientMessage::Inv(vec![hash]));
+    }
+}
+
+fn handle_message(peer: &Peer, message: ClientMessage, node: &Node, requests: &mut Requests) {
+    let Node { ref chain, ref checkpoints, ref ledger, ref mempool, ref gossip, ref network, compression, .. } = *node;
+    let addr = peer.addr();
+    match message {
+        ClientMessage::Hello { compression: theirs, port } => {
+            peer.set_compression(negotiate(&compression.offer(), &theirs));
+            if let Some(id) = peer.id() {
+                node.dht.routing().insert(Contact { id, addr: canonical(SocketAddr::new(addr.ip(), port)) });
+            }
+            node.addresses.lock().unwrap().add(SocketAddr::new(addr.ip(), port));
+        },
+        ClientMessage::GetAddr => {
+            let addrs = node.addresses.lock().unwrap().recent(MAX_ADDR);
+            peer.send(&ClientMessage::Addr(addrs));
+        },
+        ClientMessage::Addr(addrs) => {
+            if !requests.addrs {
+                return punish(node, peer, Misbehavior::Unsolicited);
+            }
+            requests.addrs = false;
+            let mut addresses = node.addresses.lock().unwrap();
+            for addr in addrs.into_iter().take(MAX_ADDR) {
+                addresses.add(addr);
+            }
+        },
+        ClientMessage::QueryChain(start) => {
+            // peers ask for the next page once they have the last one
+            let slot = match requests.chain_reply.acquire() {
+                Some(slot) => slot,
+                None => return,
+            };
+            let global_slot = match node.chain_replies.acquire() {
+                Some(slot) => slot,
+                None => return println!("Too busy to send our chain to {}", addr),
+            };
+            let page = chain.lock().unwrap().iter().skip(start as usize).take(CHAIN_PAGE).cloned().collect();
+            peer.send_holding(&ClientMessage::Chain(page), vec![slot, global_slot]);
+        },
+        ClientMessage::Chain(page) => {
+            let mut their_chain = match requests.chain.take() {
+                Some(their_chain) => their_chain,
+                None => return punish(node, peer, Misbehavior::Unsolicited),
+            };
+            if page.len() > CHAIN_PAGE {
+                println!("Rejected chain from {}: a page is too long", addr);
+                return punish(node, peer, Misbehavior::InvalidChain);
+            }
+            let follows = match (their_chain.last(), page.first()) {
+                (Some(last), Some(first)) => first.previous_hash == last.hash,
+                _ => true,
+            };
+            if !follows {
+                // their chain changed between pages, so start again
+                peer.send(&ClientMessage::QueryChain(0));
+                requests.chain = Some(Vec::new());
+                return;
+            }
+            let more = page.len() == CHAIN_PAGE;
+            their_chain.extend(page);
+            if more {
+                peer.send(&ClientMessage::QueryChain(their_chain.len() as u64));
+                requests.chain = Some(their_chain);
+                return;
+            }
+            if !check_chain(&their_chain) {
+                println!("Rejected chain from {}: it doesn't check out", addr);
+                return punish(node, peer, Misbehavior::InvalidChain);
+            }
+            if !their_chain.first().is_none_or(|genesis| checkpoints.accepts_block(genesis)) {
+                println!("Rejected chain from {}: it starts from a different genesis block", addr);
+                return punish(node, peer, Misbehavior::InvalidChain);
+            }
+            let mut my_chain = chain.lock().unwrap();
+            if is_chain_better(&their_chain, &my_chain, checkpoints) {
+                let mut ledger = ledger.lock().unwrap();
+                match ledger.reorg(&my_chain, &their_chain) {
+                    Ok(()) => {
+                        println!("Accepted new chain from {}", addr);
+                        mempool.lock().unwrap().reorg(&my_chain, &their_chain, &**ledger);
+                        // so announcements of them aren't fetched again
+                        let common = my_chain.iter().zip(their_chain.iter()).take_while(|&(a, b)| a.hash == b.hash).count();
+                        let mut gossip = gossip.lock().unwrap();
+                        for block in &their_chain[common..] {
+                            gossip.see(block.hash);
                         }
-                        None => {
-                            break;
-                        },
+                        gossip.index(&their_chain[common..], &my_chain[common..]);
+                        *my_chain = their_chain;
                     },
                     Err(e) => {
-                        if e.kind() == std::io::ErrorKind::WouldBlock {
-                            continue;
-                        } else {
-                            println!("breaking: {:?}", e.kind());
-                            break
-                        }
+                        println!("Rejected chain from {}: {}", addr, e);
+                        drop(ledger);
+                        drop(my_chain);
+                        punish(node, peer, Misbehavior::InvalidChain);
+                    },
+                }
+            }
+        },
+        ClientMessage::NewBlock(block) => {
+            let hash = block.hash;
+            if requests.blocks.remove(&hash).is_none() {
+                return punish(node, peer, Misbehavior::Unsolicited);
+            }
+            if !block.has_valid_hash() {
+                println!("Rejected block {} from {}: bad hash", block.block_num, addr);
+                return punish(node, peer, Misbehavior::InvalidBlock);
+            }
+            let fresh = gossip.lock().unwrap().see(hash);
+            if !fresh {
+                return;
+            }
+            let mut chain = chain.lock().unwrap();
+            if !checkpoints.accepts_block(&block) {
+                println!("Rejected block {} from {}: conflicts with a checkpoint",
+                    block.block_num, addr);
+            } else if block.previous_hash == chain.last().unwrap().hash {
+                // clocks differ, so this alone doesn't count against the peer
+                if let Err(e) = check_timestamp(&block, &chain, ns(&time::now_utc())) {
+                    return println!("Rejected block from {}: {}", addr, e);
+                }
+                let mut ledger = ledger.lock().unwrap();
+                match ledger.apply_block(&block) {
+                    Ok(()) => {
+                        println!("Received block {} from {}", block.block_num, addr);
+                        mempool.lock().unwrap().remove_included(std::slice::from_ref(&block), &**ledger);
+                        drop(ledger);
+                        gossip.lock().unwrap().index(std::slice::from_ref(&block), &[]);
+                        chain.push(block);
+                        announce(network, gossip, hash, Some(peer));
+                    },
+                    Err(e) => {
+                        println!("Rejected block from {}: {}", addr, e);
+                        drop(ledger);
+                        drop(chain);
+                        punish(node, peer, Misbehavior::InvalidBlock);
+                    },
+                }
+            } else {
+                // something weird is going on, better check their whole chain
+                println!("Received a block which doesn't match our chain");
+                if requests.chain.is_none() {
+                    peer.send(&ClientMessage::QueryChain(0));
+                    requests.chain = Some(Vec::new());
+                }
+            }
+        }
+        ClientMessage::Inv(hashes) => {
+            let now = Instant::now();
+            requests.blocks.retain(|_, &mut asked| now.duration_since(asked) < REQUEST_TIMEOUT);
+            let room = MAX_INVENTORY - requests.blocks.len();
+            let wanted = gossip.lock().unwrap().wanted(&hashes[..hashes.len().min(room)], now);
+            if !wanted.is_empty() {
+                requests.blocks.extend(wanted.iter().map(|&hash| (hash, now)));
+                peer.send(&ClientMessage::GetData(wanted));
+            }
+        }
+        ClientMessage::GetData(hashes) => {
+            let blocks: Vec<Block> = {
+                let chain = chain.lock().unwrap();
+                let gossip = gossip.lock().unwrap();
+                hashes.iter().take(MAX_INVENTORY)
+                    .filter_map(|hash| gossip.height(hash).and_then(|height| chain.get(height as usize)).cloned())
+                    .collect()
+            };
+            for block in blocks {
+                peer.send(&ClientMessage::NewBlock(block));
+            }
+        }
+        ClientMessage::QueryAccount(address) => {
+            let info = ledger.lock().unwrap().account_info(&address);
+            let info = mempool.lock().unwrap().account_info(info);
+            peer.send(&ClientMessage::Account(info));
+        }
+        ClientMessage::Account(_) => punish(node, peer, Misbehavior::Unsolicited),
+        ClientMessage::FindNode(id) => {
+            let target = Target::Node(id);
+            let contacts = node.dht.routing().closest(&target, K);
+            peer.send(&ClientMessage::Nodes(target, contacts));
+        }
+        ClientMessage::FindValue(hash) => {
+            let block = {
+                let chain = chain.lock().unwrap();
+                let height = gossip.lock().unwrap().height(&hash);
+                height.and_then(|height| chain.get(height as usize)).cloned()
+            };
+            match block {
+                Some(block) => peer.send(&ClientMessage::Value(block)),
+                None => {
+                    let target = Target::Value(hash);
+                    let contacts = node.dht.routing().closest(&target, K);
+                    peer.send(&ClientMessage::Nodes(target, contacts));
+                },
+            }
+        }
+        ClientMessage::Nodes(target, contacts) => {
+            let answered = peer.id().is_some_and(|id| node.dht.answered(&id, target, contacts));
+            if !answered {
+                punish(node, peer, Misbehavior::Unsolicited);
+            }
+        }
+        ClientMessage::Value(block) => {
+            if !block.has_valid_hash() {
+                return punish(node, peer, Misbehavior::InvalidBlock);
+            }
+            let answered = peer.id().is_some_and(|id| node.dht.found(&id, block));
+            if !answered {
+                punish(node, peer, Misbehavior::Unsolicited);
+            }
+        }
+        ClientMessage::NewTransaction(transaction) => {
+            let ledger = ledger.lock().unwrap();
+            let inserted = mempool.lock().unwrap().insert(transaction.clone(), &**ledger);
+            drop(ledger);
+            match inserted {
+                Ok(hash) => {
+                    println!("Received transaction {:?} from {}", hash, addr);
+                    if gossip.lock().unwrap().relay_transaction(hash) {
+                        network.broadcast_except(&ClientMessage::NewTransaction(transaction), addr);
                     }
+                },
+                Err(e) => println!("Rejected transaction from {}: {}", addr, e),
+            }
+        }
+        ClientMessage::PartialTransaction(transaction) => {
+            let ledger = ledger.lock().unwrap();
+            let merged = mempool.lock().unwrap().add_partial(transaction, addr.ip(), &**ledger);
+            match merged {
+                Ok(merged) => match merged.signatures_needed() {
+                    Ok(0) => println!("Received the last signature for transaction {:?} from {}",
+                        merged.hash(), addr),
+                    Ok(needed) => println!("Received signatures for transaction {:?} from {}, {} more needed",
+                        merged.signing_hash(), addr, needed),
+                    Err(e) => println!("Rejected signatures from {}: {}", addr, e),
+                },
+                Err(e) => println!("Rejected signatures from {}: {}", addr, e),
+            }
+        }
+    }
+}
+
+/// Signs a transaction of `kind` from this node with the next free nonce,
+/// pools it and tells our peers.
+fn submit(keypair: &Keypair, fee: u64, kind: TransactionKind, ledger: &Mutex<Box<dyn Ledger>>, mempool: &Mutex<Mempool>,
+          network: &Network) -> Result<Transaction, String> {
+    let transaction = {
+        let ledger = ledger.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        let nonce = mempool.next_nonce(&keypair.address(), ledger.nonce(&keypair.address()));
+        let transaction = Transaction::new(keypair, nonce, fee, kind);
+        mempool.insert(transaction.clone(), &**ledger)?;
+        transaction
+    };
+    network.broadcast(&ClientMessage::NewTransaction(transaction.clone()));
+    Ok(transaction)
+}
+
+/// Like `submit` for a transaction of each of `kinds`, but pools either all
+/// of them or, if they wouldn't all fit in the mempool or each in a block of
+/// `block_budget` bytes, or the mempool turns one down, none.
+fn submit_all(keypair: &Keypair, fee: u64, kinds: Vec<TransactionKind>, block_budget: usize,
+              ledger: &Mutex<Box<dyn Ledger>>, mempool: &Mutex<Mempool>, network: &Network) -> Result<Vec<Transaction>, String> {
+    let transactions = {
+        let ledger = ledger.lock().unwrap();
+        let mut mempool = mempool.lock().unwrap();
+        let nonce = mempool.next_nonce(&keypair.address(), ledger.nonce(&keypair.address()));
+        let transactions: Vec<Transaction> = kinds.into_iter().enumerate()
+            .map(|(i, kind)| Transaction::new(keypair, nonce + i as u64, fee, kind))
+            .collect();
+        if let Some(biggest) = transactions.iter().map(Transaction::size).max().filter(|&size| size > block_budget) {
+            return Err(format!("A {} byte transaction won't fit in a {} byte block", biggest, block_budget));
+        }
+        let size: usize = transactions.iter().map(Transaction::size).sum();
+        if size > MEMPOOL_SIZE - mempool.size() {
+            return Err(format!("{} bytes of transactions won't fit in the {} bytes left in the mempool",
+                size, MEMPOOL_SIZE - mempool.size()));
+        }
+        for (i, transaction) in transactions.iter().enumerate() {
+            if let Err(e) = mempool.insert(transaction.clone(), &**ledger) {
+                for pooled in &transactions[..i] {
+                    mempool.remove(&pooled.hash());
+                }
+                return Err(e);
+            }
+        }
+        transactions
+    };
+    for transaction in &transactions {
+        network.broadcast(&ClientMessage::NewTransaction(transaction.clone()));
+    }
+    Ok(transactions)
+}
+
+/// Overwrites the chainfile with `chain`.
+fn save_chain(chainfile: &mut File, chain: &[Block], compression: Compression) -> std::io::Result<()> {
+    chainfile.seek(SeekFrom::Start(0))?;
+    chainfile.set_len(0)?;
+    chainfile.write_all(&pack_chainfile(compression, &encode_chain(chain)))?;
+    chainfile.sync_all()
+}
+
+/// Whether a secured connection's peer may stay connected. An empty
+/// allowlist allows everyone.
+fn allowed(connection: &Connection, allowed_keys: &[Address]) -> bool {
+    allowed_keys.is_empty() || connection.peer_id().is_some_and(|id| allowed_keys.contains(&id))
+}
+
+/// Connects to the node at `addr` and hands it to the network, unless it's
+/// us, one we're already connected to, or one we won't talk to.
+fn dial(addr: SocketAddr, node: &Node) -> Result<Arc<Peer>, String> {
+    let connection = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)
+        .and_then(|stream| Connection::handshake(stream, &node.keypair, true))
+        .map_err(|e| format!("{} is dead: {}", addr, e))?;
+    let id = connection.peer_id().unwrap();
+    if id == node.keypair.address() {
+        node.addresses.lock().unwrap().remove(addr);
+        return Err(format!("{} is us", addr));
+    }
+    if node.network.peers().iter().any(|peer| peer.id() == Some(id)) {
+        return Err(format!("Already connected to {}", id));
+    }
+    if !allowed(&connection, &node.allowed_keys) {
+        return Err(format!("{} isn't an allowed key, disconnecting", id));
+    }
+    if !node.reputation.lock().unwrap().allows(addr, Some(id)) {
+        return Err(format!("{} is banned, disconnecting", id));
+    }
+    let peer = node.network.add(connection, Direction::Outbound).map_err(|e| format!("Couldn't add {}: {}", addr, e))?;
+    node.dht.routing().insert(Contact { id, addr });
+    node.addresses.lock().unwrap().seen(addr);
+    Ok(peer)
+}
+
+/// Keeps us connected to `target` peers we dialed, dialing addresses from
+/// the address book as connections drop, keeps the book topped up from the
+/// nameserver, if there is one, and saves it when it's changed.
+fn maintain_peers(node: Node, target: usize, nameserver: Option<String>, mut nameserver_connection: Option<Connection>) {
+    let mut backoff = Backoff::new();
+    let mut refreshed = Instant::now();
+    loop {
+        thread::sleep(MAINTAIN_INTERVAL);
+        if let Some(ref nameserver) = nameserver {
+            if refreshed.elapsed() >= NAMESERVER_REFRESH {
+                refreshed = Instant::now();
+                refresh_from_nameserver(&node, nameserver, &mut nameserver_connection);
+            }
+        }
+        if let Err(e) = node.addresses.lock().unwrap().save() {
+            println!("Couldn't save the address book: {}", e);
+        }
+
+        let peers = node.network.peers();
+        let outbound = peers.iter().filter(|peer| peer.is_outbound()).count();
+        if outbound >= target {
+            continue;
+        }
+        // peers that dialed us are known by where they listen, not where they dialed from
+        let mut connected = HashSet::new();
+        {
+            let routing = node.dht.routing();
+            for peer in &peers {
+                connected.insert(canonical(peer.addr()));
+                if let Some(contact) = peer.id().and_then(|id| routing.get(&id)) {
+                    connected.insert(contact.addr);
                 }
             }
+        }
+        let candidates: Vec<SocketAddr> = node.addresses.lock().unwrap().recent(MAX_ADDRESSES).into_iter()
+            .filter(|addr| !connected.contains(addr) && backoff.ready(addr))
+            .collect();
+        let mut needed = target - outbound;
+        for addr in candidates {
+            if needed == 0 {
+                break;
+            }
+            match dial(addr, &node) {
+                Ok(_) => {
+                    println!("Connected to {}", addr);
+                    backoff.succeeded(&addr);
+                    needed -= 1;
+                },
+                Err(e) => {
+                    println!("{}", e);
+                    backoff.failed(addr);
+                },
+            }
+        }
+    }
+}
+
+/// Connects to the nameserver, trying each address it resolves to, with
+/// `NAMESERVER_TIMEOUT` on connecting, reading and writing.
+fn connect_nameserver(nameserver: &str) -> std::io::Result<Connection> {
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No address for the nameserver");
+    for addr in nameserver.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, NAMESERVER_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(NAMESERVER_TIMEOUT))?;
+                stream.set_write_timeout(Some(NAMESERVER_TIMEOUT))?;
+                return Ok(Connection::new(stream));
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Adds the nameserver's peers to the address book, reconnecting to it
+/// first, and telling it about us again, if the connection's gone.
+fn refresh_from_nameserver(node: &Node, nameserver: &str, connection: &mut Option<Connection>) {
+    if connection.is_none() {
+        let reconnected = connect_nameserver(nameserver).and_then(|mut connection| {
+            connection.write_message(&ClientToNameserverMessage::Inform(node.port))?;
+            Ok(connection)
         });
+        match reconnected {
+            Ok(reconnected) => *connection = Some(reconnected),
+            Err(e) => return println!("Couldn't connect to nameserver {}: {}", nameserver, e),
+        }
+    }
+    let reply = match *connection {
+        Some(ref mut connection) => connection.write_message(&ClientToNameserverMessage::Query)
+            .and_then(|_| connection.read_message()),
+        None => return,
+    };
+    match reply {
+        Ok(Some(NameserverToClientMessage::Peers(peers))) => {
+            let mut addresses = node.addresses.lock().unwrap();
+            for addr in peers {
+                addresses.add(addr);
+            }
+        },
+        Ok(_) | Err(_) => {
+            println!("Lost the nameserver, will reconnect");
+            *connection = None;
+        },
     }
-    let mut connection = connection.lock().unwrap();
-    connection.write_message(&ClientMessage::QueryChain).unwrap();
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 enum ReplCommand {
     NewBlock,
+    NewTransaction(String),
+    ShowMempool,
     ShowChain,
     Exit,
     ListPeers,
     Latest,
+    Checkpoints,
+    Balance(Address),
+    Send(Address, u64, Option<TimeLock>),
+    ShowAddress,
+    Deploy(String),
+    Invoke(Address, String, Vec<u8>),
+    ShowContract(Address),
+    Notarize(String),
+    Receipt(String),
+    Store(String),
+    Fetch(Hash32Byte, String),
+    Encrypt(Vec<Address>, String),
+    Decrypt,
+    CreateToken(String, u64, bool),
+    MintToken(String, Address, u64),
+    SendToken(String, Address, u64),
+    ShowToken(String, Option<Address>),
+    RegisterName(String, String),
+    RenewName(String),
+    TransferName(String, Address),
+    Resolve(String),
+    Bans,
+    Unban(PeerKey),
+    FindNode(Address),
+    FindBlock(Hash32Byte),
     Help,
 }
 
 impl ReplCommand {
     fn variants() -> std::slice::Iter<'static, ReplCommand> {
         static VARIANTS: &'static [ReplCommand] = &[
-            ReplCommand::NewBlock, ReplCommand::ShowChain, ReplCommand::ListPeers,
-            ReplCommand::Latest, ReplCommand::Exit, ReplCommand::Help];
+            ReplCommand::NewBlock, ReplCommand::NewTransaction(String::new()), ReplCommand::ShowMempool,
+            ReplCommand::ShowChain, ReplCommand::ListPeers,
+            ReplCommand::Latest, ReplCommand::Checkpoints, ReplCommand::Balance(Address([0; 32])),
+            ReplCommand::Send(Address([0; 32]), 0, None), ReplCommand::ShowAddress,
+            ReplCommand::Deploy(String::new()), ReplCommand::Invoke(Address([0; 32]), String::new(), Vec::new()),
+            ReplCommand::ShowContract(Address([0; 32])), ReplCommand::Notarize(String::new()),
+            ReplCommand::Receipt(String::new()), ReplCommand::Store(String::new()),
+            ReplCommand::Fetch(Hash32Byte([0; 32]), String::new()), ReplCommand::Encrypt(Vec::new(), String::new()),
+            ReplCommand::Decrypt, ReplCommand::CreateToken(String::new(), 0, false),
+            ReplCommand::MintToken(String::new(), Address([0; 32]), 0),
+            ReplCommand::SendToken(String::new(), Address([0; 32]), 0), ReplCommand::ShowToken(String::new(), None),
+            ReplCommand::RegisterName(String::new(), String::new()), ReplCommand::RenewName(String::new()),
+            ReplCommand::TransferName(String::new(), Address([0; 32])), ReplCommand::Resolve(String::new()),
+            ReplCommand::Bans, ReplCommand::Unban(PeerKey::Node(Address([0; 32]))),
+            ReplCommand::FindNode(Address([0; 32])), ReplCommand::FindBlock(Hash32Byte([0; 32])),
+            ReplCommand::Exit, ReplCommand::Help];
         return VARIANTS.iter();
     }
 
     fn parse(input: String) -> Result<ReplCommand, String> {
-        match input.split_whitespace().next() {
+        let mut words = input.trim().splitn(2, char::is_whitespace);
+        match words.next().filter(|word| !word.is_empty()) {
             Some("block") => Ok(ReplCommand::NewBlock),
+            Some("tx") => match words.next().map(str::trim) {
+                Some(payload) if !payload.is_empty() => Ok(ReplCommand::NewTransaction(payload.to_string())),
+                _ => Err("Usage: tx <data>".to_string()),
+            },
+            Some("mempool") => Ok(ReplCommand::ShowMempool),
             Some("chain") => Ok(ReplCommand::ShowChain),
             Some("exit") => Ok(ReplCommand::Exit),
             Some("peers") => Ok(ReplCommand::ListPeers),
             Some("latest") => Ok(ReplCommand::Latest),
+            Some("checkpoints") => Ok(ReplCommand::Checkpoints),
+            Some("balance") => match words.next().map(str::trim) {
+                Some(address) => Address::from_hex(address).map(ReplCommand::Balance),
+                None => Err("Usage: balance <address>".to_string()),
+            },
+            Some("send") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 2 && args.len() != 3 {
+                    return Err("Usage: send <address> <amount> [<lock>]".to_string());
+                }
+                let amount = args[1].parse::<u64>().map_err(|_| format!("Invalid amount {}", args[1]))?;
+                let time_lock = match args.get(2) {
+                    Some(lock) => Some(TimeLock::parse(lock)?),
+                    None => None,
+                };
+                Address::from_hex(args[0]).map(|address| ReplCommand::Send(address, amount, time_lock))
+            },
+            Some("address") => Ok(ReplCommand::ShowAddress),
+            Some("deploy") => match words.next().map(str::trim) {
+                Some(path) if !path.is_empty() => Ok(ReplCommand::Deploy(path.to_string())),
+                _ => Err("Usage: deploy <file>".to_string()),
+            },
+            Some("invoke") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 2 && args.len() != 3 {
+                    return Err("Usage: invoke <contract> <function> [<input>]".to_string());
+                }
+                let input = match args.get(2) {
+                    Some(input) => parse_hex(input)?,
+                    None => Vec::new(),
+                };
+                Address::from_hex(args[0]).map(|contract| ReplCommand::Invoke(contract, args[1].to_string(), input))
+            },
+            Some("contract") => match words.next().map(str::trim) {
+                Some(address) => Address::from_hex(address).map(ReplCommand::ShowContract),
+                None => Err("Usage: contract <address>".to_string()),
+            },
+            Some("token-create") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                let mintable = match args.get(2) {
+                    None => false,
+                    Some(&"mintable") => true,
+                    Some(_) => return Err("Usage: token-create <name> <supply> [mintable]".to_string()),
+                };
+                if args.len() < 2 {
+                    return Err("Usage: token-create <name> <supply> [mintable]".to_string());
+                }
+                let supply = args[1].parse::<u64>().map_err(|_| format!("Invalid supply {}", args[1]))?;
+                Ok(ReplCommand::CreateToken(args[0].to_string(), supply, mintable))
+            },
+            Some(command @ "token-mint") | Some(command @ "token-send") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 3 {
+                    return Err(format!("Usage: {} <name> <address> <amount>", command));
+                }
+                let to = Address::from_hex(args[1])?;
+                let amount = args[2].parse::<u64>().map_err(|_| format!("Invalid amount {}", args[2]))?;
+                Ok(if command == "token-mint" {
+                    ReplCommand::MintToken(args[0].to_string(), to, amount)
+                } else {
+                    ReplCommand::SendToken(args[0].to_string(), to, amount)
+                })
+            },
+            Some("token") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                match args.len() {
+                    1 => Ok(ReplCommand::ShowToken(args[0].to_string(), None)),
+                    2 => Address::from_hex(args[1]).map(|address| ReplCommand::ShowToken(args[0].to_string(), Some(address))),
+                    _ => Err("Usage: token <name> [<address>]".to_string()),
+                }
+            },
+            Some("register") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 2 {
+                    return Err("Usage: register <name> <value>".to_string());
+                }
+                Ok(ReplCommand::RegisterName(args[0].to_string(), args[1].to_string()))
+            },
+            Some("renew") => match words.next().map(str::trim) {
+                Some(name) if !name.is_empty() => Ok(ReplCommand::RenewName(name.to_string())),
+                _ => Err("Usage: renew <name>".to_string()),
+            },
+            Some("transfer-name") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 2 {
+                    return Err("Usage: transfer-name <name> <address>".to_string());
+                }
+                Address::from_hex(args[1]).map(|to| ReplCommand::TransferName(args[0].to_string(), to))
+            },
+            Some("resolve") => match words.next().map(str::trim) {
+                Some(name) if !name.is_empty() => Ok(ReplCommand::Resolve(name.to_string())),
+                _ => Err("Usage: resolve <name>".to_string()),
+            },
+            Some("notarize") => match words.next().map(str::trim) {
+                Some(path) if !path.is_empty() => Ok(ReplCommand::Notarize(path.to_string())),
+                _ => Err("Usage: notarize <file>".to_string()),
+            },
+            Some("receipt") => match words.next().map(str::trim) {
+                Some(path) if !path.is_empty() => Ok(ReplCommand::Receipt(path.to_string())),
+                _ => Err("Usage: receipt <file>".to_string()),
+            },
+            Some("store") => match words.next().map(str::trim) {
+                Some(path) if !path.is_empty() => Ok(ReplCommand::Store(path.to_string())),
+                _ => Err("Usage: store <file>".to_string()),
+            },
+            Some("fetch") => {
+                let args: Vec<&str> = words.next().unwrap_or("").split_whitespace().collect();
+                if args.len() != 2 {
+                    return Err("Usage: fetch <manifest> <file>".to_string());
+                }
+                Hash32Byte::from_hex(args[0]).map(|id| ReplCommand::Fetch(id, args[1].to_string()))
+            },
+            Some("encrypt") => {
+                let mut args = words.next().unwrap_or("").trim().splitn(2, char::is_whitespace);
+                match (args.next().filter(|recipients| !recipients.is_empty()), args.next().map(str::trim)) {
+                    (Some(recipients), Some(payload)) if !payload.is_empty() => {
+                        let recipients = recipients.split(',').map(Address::from_hex).collect::<Result<Vec<_>, _>>()?;
+                        Ok(ReplCommand::Encrypt(recipients, payload.to_string()))
+                    },
+                    _ => Err("Usage: encrypt <address>[,<address>...] <data>".to_string()),
+                }
+            },
+            Some("decrypt") => Ok(ReplCommand::Decrypt),
+            Some("bans") => Ok(ReplCommand::Bans),
+            Some("unban") => match words.next().map(str::trim) {
+                Some(key) if !key.is_empty() => PeerKey::parse(key).map(ReplCommand::Unban),
+                _ => Err("Usage: unban <address or IP>".to_string()),
+            },
+            Some("findnode") => match words.next().map(str::trim) {
+                Some(id) => Address::from_hex(id).map(ReplCommand::FindNode),
+                None => Err("Usage: findnode <address>".to_string()),
+            },
+            Some("findblock") => match words.next().map(str::trim) {
+                Some(hash) => Hash32Byte::from_hex(hash).map(ReplCommand::FindBlock),
+                None => Err("Usage: findblock <hash>".to_string()),
+            },
             Some("help") => Ok(ReplCommand::Help),
             Some(_) => Err("Unrecognized input".to_string()),
             None => Err("no input".to_string()),
@@ -147,11 +869,46 @@ impl ReplCommand {
 
     fn help_string(&self) -> String {
         match self {
-            &ReplCommand::NewBlock => "block - create a new block",
+            &ReplCommand::NewBlock => "block - create a new block from the mempool",
+            &ReplCommand::NewTransaction(_) => "tx <data> - broadcast a transaction from this node carrying <data>",
+            &ReplCommand::ShowMempool => "mempool - list the transactions waiting for a block or for signatures",
             &ReplCommand::ShowChain => "chain - print the chain",
             &ReplCommand::Exit => "exit - close the client",
             &ReplCommand::ListPeers => "peers - list the connected peers",
             &ReplCommand::Latest => "latest - show some info about the latest block",
+            &ReplCommand::Checkpoints => "checkpoints - list the checkpoints and max reorg depth",
+            &ReplCommand::Balance(_) => "balance <address> - show the balance and nonce of an account",
+            &ReplCommand::Send(_, _, _) => "send <address> <amount> [<lock>] - pay <amount> from this node's address, \
+                not before <lock> (height:N or time:SECONDS) if given",
+            &ReplCommand::ShowAddress => "address - show this node's address",
+            &ReplCommand::Deploy(_) => "deploy <file> - deploy the WebAssembly contract in <file>",
+            &ReplCommand::Invoke(_, _, _) => "invoke <contract> <function> [<input>] - call <function> on a contract, \
+                passing it <input> in hex and as much fuel as the fee buys",
+            &ReplCommand::ShowContract(_) => "contract <address> - show a contract's storage",
+            &ReplCommand::CreateToken(_, _, _) => "token-create <name> <supply> [mintable] - issue a token, \
+                giving this node <supply> of it; only mintable tokens can have more made later",
+            &ReplCommand::MintToken(_, _, _) => "token-mint <name> <address> <amount> - make more of a mintable token \
+                this node issued",
+            &ReplCommand::SendToken(_, _, _) => "token-send <name> <address> <amount> - send some of a token",
+            &ReplCommand::ShowToken(_, _) => "token <name> [<address>] - show a token's supply, or an address's \
+                balance of it",
+            &ReplCommand::RegisterName(_, _) => "register <name> <value> - register a name for this node, \
+                pointing at <value>",
+            &ReplCommand::RenewName(_) => "renew <name> - keep one of this node's names from expiring",
+            &ReplCommand::TransferName(_, _) => "transfer-name <name> <address> - give one of this node's names away",
+            &ReplCommand::Resolve(_) => "resolve <name> - look up a registered name",
+            &ReplCommand::Notarize(_) => "notarize <file> - record the hash of <file> on the chain",
+            &ReplCommand::Receipt(_) => "receipt <file> - show a receipt proving <file> was notarized, for verify-receipt",
+            &ReplCommand::Store(_) => "store <file> - add <file> to the mempool in chunks, with manifests \
+                listing them, for the next blocks to take",
+            &ReplCommand::Fetch(_, _) => "fetch <manifest> <file> - reassemble a stored file from the chain into <file>",
+            &ReplCommand::Encrypt(_, _) => "encrypt <address>[,<address>...] <data> - broadcast a transaction \
+                carrying <data> encrypted so only those addresses can read it",
+            &ReplCommand::Decrypt => "decrypt - show the encrypted data on the chain this node can read",
+            &ReplCommand::Bans => "bans - list banned peers and when their bans end",
+            &ReplCommand::Unban(_) => "unban <address or IP> - lift a peer's ban",
+            &ReplCommand::FindNode(_) => "findnode <address> - find the nodes closest to <address>",
+            &ReplCommand::FindBlock(_) => "findblock <hash> - find a node that has the block with <hash>",
             &ReplCommand::Help => "help - display this list",
         }.to_string()
     }
@@ -164,8 +921,33 @@ fn main() {
     // flags
     let args: Vec<String> = std::env::args().collect();
     let mut opts = getopts::Options::new();
-    opts.reqopt("n", "nameserver", "nameserver address", "ADDR")
+    opts.optopt("n", "nameserver", "nameserver address; optional if there are seeds or known peers", "ADDR")
+        .optmulti("", "seed", "try connecting to the node at ADDR at startup; can be given more than once", "ADDR")
+        .optopt("", "outbound", &format!("keep N connections to peers we dialed, redialing as they drop, \
+            defaults to {}", DEFAULT_OUTBOUND), "N")
+        .optopt("", "addrbook", "where addresses of other nodes are kept, defaults to the chainfile's name with \
+            .peers on the end", "FILE")
         .optopt("c", "chainfile", "chainfile location", "FILE")
+        .optmulti("", "checkpoint", "reject chains whose block HEIGHT isn't HASH", "HEIGHT:HASH")
+        .optopt("", "max-reorg-depth", "never replace more than N of our blocks", "N")
+        .optmulti("", "allocate", "give ADDR a starting balance of AMOUNT (all nodes of a chain must agree)", "ADDR:AMOUNT")
+        .optopt("", "ledger", "how balances are tracked: accounts (default) or utxo", "MODEL")
+        .optopt("", "fee", "fee to attach to transactions made from the repl, defaults to 0", "AMOUNT")
+        .optmulti("", "allow-key", "only connect to nodes and wallets with this address; can be given \
+            more than once", "ADDR")
+        .optopt("", "compression", "compress messages to peers that support it, and the chainfile, \
+            with zstd (default), lz4 or none", "ALGORITHM")
+        .optopt("", "ban-time", &format!("ban misbehaving peers for SECONDS, defaults to {}", DEFAULT_BAN_SECONDS), "SECONDS")
+        .optopt("", "banfile", "where banned peers are kept, defaults to the chainfile's name with .bans on the end", "FILE")
+        .optopt("", "max-inbound", &format!("accept at most N connections from peers at once, defaults to {}",
+            DEFAULT_MAX_INBOUND), "N")
+        .optopt("", "max-message-rate", &format!("slow down a peer sending more than N messages a second, defaults to {}",
+            DEFAULT_MESSAGE_RATE), "N")
+        .optopt("", "max-byte-rate", &format!("slow down a peer sending more than BYTES a second, defaults to {}",
+            DEFAULT_BYTE_RATE), "BYTES")
+        .optopt("", "fanout", &format!("announce new blocks to N random peers, defaults to {}", DEFAULT_FANOUT), "N")
+        .optopt("", "block-budget", "fill at most BYTES of each new block with transactions", "BYTES")
+        .optopt("w", "wallet", "sign with the first key in this keystore instead of a throwaway one", "FILE")
         .optflag("h", "help", "show this message");
 
     let matches = match opts.parse(&args[1..]) {
@@ -182,81 +964,357 @@ fn main() {
         return;
     }
     let chainfile_name = matches.opt_str("c").unwrap_or("my.chain".to_string());
-    let nameserver_str = matches.opt_str("n").expect("Missing nameserver address.");
+    let nameserver_str = matches.opt_str("n");
+
+    let mut allocations = Vec::new();
+    for allocation in matches.opt_strs("allocate") {
+        match parse_allocation(&allocation) {
+            Ok(allocation) => allocations.push(allocation),
+            Err(e) => {
+                writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut ledger: Box<dyn Ledger> = match matches.opt_str("ledger").as_deref() {
+        None | Some("accounts") => match AccountLedger::new(&allocations) {
+            Ok(ledger) => Box::new(ledger),
+            Err(e) => {
+                writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        },
+        Some("utxo") => match UtxoLedger::new(&allocations) {
+            Ok(ledger) => Box::new(ledger),
+            Err(e) => {
+                writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        },
+        Some(other) => {
+            writeln!(std::io::stderr(), "Unknown ledger {}, expected accounts or utxo", other).expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+    // the starting balances are part of the genesis block, so nodes that
+    // disagree on them can't mistake each other's chains for their own
+    let genesis = Block::genesis(ledger.state_root());
+
+    let mut checkpoints = Checkpoints::new(&genesis);
+    for checkpoint in matches.opt_strs("checkpoint") {
+        match Checkpoints::parse(&checkpoint) {
+            Ok((height, hash)) => checkpoints.add(height, hash),
+            Err(e) => {
+                writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Some(depth) = matches.opt_str("max-reorg-depth") {
+        match depth.parse::<u64>() {
+            Ok(depth) => checkpoints.set_max_reorg_depth(Some(depth)),
+            Err(_) => {
+                writeln!(std::io::stderr(), "max-reorg-depth must be a non-negative integer").expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    }
+    let checkpoints = Arc::new(checkpoints);
+
+    let fee = match matches.opt_str("fee").map_or(Ok(0), |fee| fee.parse::<u64>()) {
+        Ok(fee) => fee,
+        Err(_) => {
+            writeln!(std::io::stderr(), "fee must be a non-negative integer").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+    let block_budget = match matches.opt_str("block-budget").map_or(Ok(BLOCK_CAPACITY), |budget| budget.parse::<usize>()) {
+        Ok(budget) if budget <= BLOCK_CAPACITY => budget,
+        _ => {
+            writeln!(std::io::stderr(), "block-budget must be at most {} bytes", BLOCK_CAPACITY).expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let ban_seconds = match matches.opt_str("ban-time").map_or(Ok(DEFAULT_BAN_SECONDS), |seconds| seconds.parse::<u64>()) {
+        Ok(seconds) => seconds,
+        Err(_) => {
+            writeln!(std::io::stderr(), "ban-time must be a non-negative integer").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+    let banfile = matches.opt_str("banfile").unwrap_or(format!("{}.bans", chainfile_name));
+    let reputation = match Reputation::load(Path::new(&banfile), ban_seconds) {
+        Ok(reputation) => Arc::new(Mutex::new(reputation)),
+        Err(e) => {
+            writeln!(std::io::stderr(), "Couldn't load {}: {}", banfile, e).expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let max_inbound = match matches.opt_str("max-inbound").map_or(Ok(DEFAULT_MAX_INBOUND), |max| max.parse::<usize>()) {
+        Ok(max) => max,
+        Err(_) => {
+            writeln!(std::io::stderr(), "max-inbound must be a non-negative integer").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+    let messages_per_second = match matches.opt_str("max-message-rate").map_or(Ok(DEFAULT_MESSAGE_RATE), |rate| rate.parse::<f64>()) {
+        Ok(rate) if rate > 0.0 => rate,
+        _ => {
+            writeln!(std::io::stderr(), "max-message-rate must be a positive number").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+    let bytes_per_second = match matches.opt_str("max-byte-rate").map_or(Ok(DEFAULT_BYTE_RATE), |rate| rate.parse::<f64>()) {
+        Ok(rate) if rate > 0.0 => rate,
+        _ => {
+            writeln!(std::io::stderr(), "max-byte-rate must be a positive number").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let fanout = match matches.opt_str("fanout").map_or(Ok(DEFAULT_FANOUT), |fanout| fanout.parse::<usize>()) {
+        Ok(fanout) if fanout > 0 => fanout,
+        _ => {
+            writeln!(std::io::stderr(), "fanout must be a positive integer").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let compression = match matches.opt_str("compression").map_or(Ok(Compression::Zstd), |c| Compression::parse(&c)) {
+        Ok(compression) => compression,
+        Err(e) => {
+            writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let mut allowed_keys = Vec::new();
+    for key in matches.opt_strs("allow-key") {
+        match Address::from_hex(&key) {
+            Ok(address) => allowed_keys.push(address),
+            Err(e) => {
+                writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    }
+    let allowed_keys = Arc::new(allowed_keys);
+
+    let outbound = match matches.opt_str("outbound").map_or(Ok(DEFAULT_OUTBOUND), |outbound| outbound.parse::<usize>()) {
+        Ok(outbound) => outbound,
+        Err(_) => {
+            writeln!(std::io::stderr(), "outbound must be a non-negative integer").expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
+
+    let mut seeds = Vec::new();
+    for seed in matches.opt_strs("seed") {
+        match seed.to_socket_addrs() {
+            Ok(addrs) => seeds.extend(addrs),
+            Err(e) => {
+                writeln!(std::io::stderr(), "Couldn't resolve seed {}: {}", seed, e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    }
+    let addrbook = matches.opt_str("addrbook").unwrap_or(format!("{}.peers", chainfile_name));
+    let addresses = match AddressBook::load(Path::new(&addrbook)) {
+        Ok(addresses) => Arc::new(Mutex::new(addresses)),
+        Err(e) => {
+            writeln!(std::io::stderr(), "Couldn't load {}: {}", addrbook, e).expect("Couldn't write error");
+            std::process::exit(1);
+        }
+    };
 
-    // connect to nameserver
-    let mut nameserver_stream = TcpStream::connect(nameserver_str).expect("Couldn't connet to nameserver");
-    let mut nameserver_connection = Connection::new(nameserver_stream);
+    // connect to nameserver, if there is one; without it we make do with
+    // seeds and the address book
+    let mut nameserver_connection = nameserver_str.as_ref().and_then(|nameserver| match connect_nameserver(nameserver) {
+        Ok(connection) => Some(connection),
+        Err(e) => {
+            println!("Couldn't connect to nameserver {}: {}", nameserver, e);
+            None
+        }
+    });
 
     // load your chain
     let mut chainfile = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(chainfile_name)
+            .open(&chainfile_name)
             .unwrap();
     let mut chain_serialized = Vec::new();
     chainfile.read_to_end(&mut chain_serialized).expect("Couldn't read chain file");
-    let chain: Arc<Mutex<Vec<Block>>> = Arc::new(Mutex::new(if chain_serialized.len() == 0 {
-        vec![Block::genesis()]
+    let chain: Vec<Block> = if chain_serialized.len() == 0 {
+        vec![genesis.clone()]
     } else {
-        deserialize(&chain_serialized).unwrap()
-    }));
+        match unpack_chainfile(&chain_serialized).map_err(|e| e.to_string()).and_then(|bytes| decode_chain(&bytes)) {
+            Ok(chain) => chain,
+            Err(e) => {
+                writeln!(std::io::stderr(), "Couldn't load {}: {}", chainfile_name, e).expect("Couldn't write error");
+                std::process::exit(1);
+            }
+        }
+    };
+    if chain.first() != Some(&genesis) {
+        writeln!(std::io::stderr(), "{} starts from a different genesis block; were the starting balances different?",
+            chainfile_name).expect("Couldn't write error");
+        std::process::exit(1);
+    }
+    if let Err(e) = ledger.reorg(&[genesis], &chain) {
+        writeln!(std::io::stderr(), "Invalid chainfile: {}", e).expect("Couldn't write error");
+        std::process::exit(1);
+    }
+    let chain = Arc::new(Mutex::new(chain));
+    let ledger = Arc::new(Mutex::new(ledger));
 
-    let mut peers: Arc<Mutex<Vec<Arc<Mutex<Connection>>>>> = Arc::new(Mutex::new(Vec::new()));
+    let mempool = Arc::new(Mutex::new(Mempool::new(MEMPOOL_SIZE)));
+
+    // transactions made from the repl are signed with this
+    let keypair = match matches.opt_str("w") {
+        Some(wallet_file) => {
+            let password = read_password();
+            match Wallet::load(Path::new(&wallet_file), &password).map(|wallet| wallet.keys().first().cloned()) {
+                Ok(Some(keypair)) => keypair,
+                Ok(None) => {
+                    writeln!(std::io::stderr(), "{} has no keys", wallet_file).expect("Couldn't write error");
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    writeln!(std::io::stderr(), "Couldn't load {}: {}", wallet_file, e).expect("Couldn't write error");
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => Keypair::generate().expect("Couldn't generate a key"),
+    };
+    println!("Node address: {}", keypair.address());
 
     // listen for peers
     let listener = Arc::new(TcpListener::bind(("::", 0)).expect("Unable to bind to socket"));
+    let my_addr = listener.local_addr().expect("Couldn't get listening address");
+    println!("Listening on port {}", my_addr.port());
 
-    // get peers
-    nameserver_connection.write_message(&ClientToNameserverMessage::Query);
-    let peer_addrs = match nameserver_connection.read_message() {
-        Ok(Some(NameserverToClientMessage::Peers(peers))) => peers,
-        Ok(_) => panic!("Unexpected message from the nameserver"),
-        Err(e) => panic!("Error reading from nameserver: {}", e),
+    let limits = RateLimits { messages_per_second, bytes_per_second };
+    let (network, events) = Network::start(limits).expect("Couldn't start networking");
+    let mut gossip = Gossip::new(fanout);
+    gossip.index(&chain.lock().unwrap(), &[]);
+    let gossip = Arc::new(Mutex::new(gossip));
+    let node = Node {
+        chain: chain.clone(),
+        checkpoints: checkpoints.clone(),
+        ledger: ledger.clone(),
+        mempool: mempool.clone(),
+        gossip: gossip.clone(),
+        reputation: reputation.clone(),
+        network: network.clone(),
+        addresses: addresses.clone(),
+        dht: Dht::start(network.clone(), keypair.clone(), allowed_keys.clone(), reputation.clone()),
+        keypair: keypair.clone(),
+        allowed_keys: allowed_keys.clone(),
+        compression,
+        port: my_addr.port(),
+        chain_replies: Slots::new(MAX_CHAIN_REPLIES),
+    };
+    let event_thread = {
+        let node = node.clone();
+        thread::spawn(move || handle_events(events, node))
     };
 
+    // get peers: the nameserver's, our seeds, then whoever we last saw
+    let mut peer_addrs = Vec::new();
+    if let Some(ref mut nameserver_connection) = nameserver_connection {
+        let reply = nameserver_connection.write_message(&ClientToNameserverMessage::Query)
+            .and_then(|_| nameserver_connection.read_message());
+        match reply {
+            Ok(Some(NameserverToClientMessage::Peers(peers))) => peer_addrs.extend(peers),
+            Ok(_) => println!("Unexpected message from the nameserver"),
+            Err(e) => println!("Error reading from nameserver: {}", e),
+        }
+    }
+    peer_addrs.extend(seeds);
     {
-        let mut peers = peers.lock().unwrap();
-        for addr in peer_addrs {
-            match TcpStream::connect(addr) {
-                Ok(stream) => {
-                    stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
-                    let connection = Arc::new(Mutex::new(Connection::new(stream)));
-                    {
-                        let chain = chain.clone();
-                        let connection = connection.clone();
-                        handle_peer(connection, chain);
-                    }
-                    peers.push(connection);
-                }
-                Err(e) => {
-                    println!("{} is dead: {}", addr, e);
-                }
-            }
+        // so they're redialed if we can't reach them now
+        let mut addresses = addresses.lock().unwrap();
+        for addr in &peer_addrs {
+            addresses.add(*addr);
+        }
+        peer_addrs.extend(addresses.recent(BOOTSTRAP_PEERS));
+    }
+    let mut tried = HashSet::new();
+    for addr in peer_addrs {
+        let addr = canonical(addr);
+        if !tried.insert(addr) {
+            continue;
+        }
+        if let Err(e) = dial(addr, &node) {
+            println!("{}", e);
         }
     }
 
+    // fill our routing table with the nodes closest to us
+    {
+        let node = node.clone();
+        thread::spawn(move || node.dht.lookup(Target::Node(node.keypair.address())));
+    }
+
     let listener_thread = {
         let listener = listener.clone();
-        let chain = chain.clone();
-        let peers = peers.clone();
+        let network = network.clone();
+        let keypair = keypair.clone();
+        let allowed_keys = allowed_keys.clone();
+        let reputation = reputation.clone();
+        let inbound = Slots::new(max_inbound);
         thread::spawn(move || {
             for connection in listener.incoming() {
                 match connection {
                     Ok(stream) => {
-                        stream.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
-                        let connection = Arc::new(Mutex::new(Connection::new(stream)));
-                        {
-                            let mut peers = peers.lock().unwrap();
-                            let connection = connection.clone();
-                            peers.push(connection);
-                        }
-                        {
-                            let chain = chain.clone();
-                            handle_peer(connection, chain);
+                        let addr = match stream.peer_addr() {
+                            Ok(addr) => addr,
+                            Err(_) => continue,
+                        };
+                        if !reputation.lock().unwrap().allows(addr, None) {
+                            continue;
                         }
-                        println!("new connection")},
+                        // held through the handshake too, so slow ones count
+                        let slot = match inbound.acquire() {
+                            Some(slot) => slot,
+                            None => {
+                                println!("Too many peers, turning away {}", addr);
+                                continue;
+                            },
+                        };
+                        let network = network.clone();
+                        let keypair = keypair.clone();
+                        let allowed_keys = allowed_keys.clone();
+                        let reputation = reputation.clone();
+                        // handshake off the listener thread, so a slow peer can't hold up the rest
+                        thread::spawn(move || {
+                            let connection = match Connection::handshake(stream, &keypair, false) {
+                                Ok(connection) => connection,
+                                Err(e) => {
+                                    println!("Handshake failed: {}", e);
+                                    return;
+                                },
+                            };
+                            if !allowed(&connection, &allowed_keys) {
+                                println!("{} isn't an allowed key, disconnecting", connection.peer_id().unwrap());
+                                return;
+                            }
+                            if !reputation.lock().unwrap().allows(addr, connection.peer_id()) {
+                                println!("{} is banned, disconnecting", connection.peer_id().unwrap());
+                                return;
+                            }
+                            println!("new connection from {}", connection.peer_id().unwrap());
+                            if let Err(e) = network.add(connection, Direction::Inbound(slot)) {
+                                println!("Couldn't add peer: {}", e);
+                            }
+                        });
+                    },
                     Err(e) => writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error"),
                 }
             }
@@ -264,12 +1322,25 @@ fn main() {
     };
 
     // inform nameserver
-    let my_addr = listener.local_addr().expect("Couldn't get listening address");
-    nameserver_connection.write_message(&ClientToNameserverMessage::Inform(my_addr.port()));
+    if let Some(ref mut nameserver_connection) = nameserver_connection {
+        if let Err(e) = nameserver_connection.write_message(&ClientToNameserverMessage::Inform(my_addr.port())) {
+            println!("Couldn't tell the nameserver about us: {}", e);
+        }
+    }
+
+    // stay connected
+    {
+        let node = node.clone();
+        thread::spawn(move || maintain_peers(node, outbound, nameserver_str, nameserver_connection));
+    }
 
     // launch repl
     let repl_thread = {
+        let node = node.clone();
         let chain = chain.clone();
+        let checkpoints = checkpoints.clone();
+        let ledger = ledger.clone();
+        let mempool = mempool.clone();
         thread::spawn(move || {
             loop {
                 print!("> ");
@@ -280,18 +1351,235 @@ fn main() {
                     Ok(ReplCommand::ShowChain) => {println!("{:#?}", *chain.lock().unwrap());},
                     Ok(ReplCommand::NewBlock) => {
                         let mut chain = chain.lock().unwrap();
-                        let new_block = Block::new(chain.last().unwrap(), [0; 1024]);
+                        let mut ledger = ledger.lock().unwrap();
+                        let mut mempool = mempool.lock().unwrap();
+                        // if our clock's behind the chain's, just after the median will do
+                        let timestamp = ns(&time::now_utc()).max(median_time_past(&chain) + 1);
+                        let context = BlockContext { height: chain.last().unwrap().block_num + 1, timestamp };
+                        let mut draft = ledger.draft(context);
+                        let transactions = mempool.select_for_block(block_budget, |transaction| {
+                            transaction.is_mature(context.height, context.timestamp) && draft.apply_transaction(transaction).is_ok()
+                        });
+                        let mut body = BlockBody { reward: None, transactions };
+                        let fees = body.fees().expect("Selected transactions' fees overflow");
+                        if fees > 0 {
+                            let reward = Reward { to: keypair.address(), amount: fees };
+                            match draft.apply_reward(&reward, &chain.last().unwrap().hash) {
+                                Ok(()) => body.reward = Some(reward),
+                                Err(e) => println!("Leaving out the fees: {}", e),
+                            }
+                        }
+                        let data = pack_block(&body).expect("Selected transactions don't fit in a block");
+                        let new_block = Block::new(chain.last().unwrap(), context.timestamp, draft.state_root(),
+                                                   body.transactions_root(), data);
                         let block_num = new_block.block_num;
+                        ledger.apply_block(&new_block).expect("Created an invalid block");
+                        mempool.remove_included(std::slice::from_ref(&new_block), &**ledger);
                         chain.push(new_block.clone());
 
                         {
-                            let peers = peers.lock().unwrap();
-                            for peer in peers.iter() {
-                                let mut peer = peer.lock().unwrap();
-                                peer.write_message(&ClientMessage::NewBlock(new_block.clone()));
+                            let mut gossip = gossip.lock().unwrap();
+                            gossip.see(new_block.hash);
+                            gossip.index(std::slice::from_ref(&new_block), &[]);
+                        }
+                        announce(&network, &gossip, new_block.hash, None);
+                        println!("Created block {} with {} transactions, earning {} in fees", block_num, body.transactions.len(), fees);
+                    },
+                    Ok(ReplCommand::NewTransaction(payload)) => {
+                        match submit(&keypair, fee, TransactionKind::Data(payload.into_bytes()), &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Deploy(path)) => {
+                        let mut code = Vec::new();
+                        if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_end(&mut code)) {
+                            println!("Couldn't read {}: {}", path, e);
+                            continue;
+                        }
+                        match submit(&keypair, fee, TransactionKind::Deploy(code), &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Deploying contract {} in transaction {:?}",
+                                contract_address(&transaction.from, transaction.nonce), transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Invoke(contract, function, input)) => {
+                        let fuel = fee.saturating_mul(FUEL_PER_FEE).min(MAX_FUEL);
+                        if fuel == 0 {
+                            println!("Error: Invocations need a fee (--fee) to pay for their fuel");
+                            continue;
+                        }
+                        let kind = TransactionKind::Invoke { contract, function, input, fuel };
+                        match submit(&keypair, fee, kind, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::CreateToken(name, supply, mintable)) => {
+                        match submit(&keypair, fee, TransactionKind::CreateToken { name, supply, mintable }, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::MintToken(name, to, amount)) => {
+                        match submit(&keypair, fee, TransactionKind::MintToken { name, to, amount }, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::SendToken(name, to, amount)) => {
+                        match submit(&keypair, fee, TransactionKind::TransferToken { name, to, amount }, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::ShowToken(name, address)) => {
+                        match (ledger.lock().unwrap().token(&name), address) {
+                            (Some(token), Some(address)) => println!("{} has {} {}", address, token.balance(&address), name),
+                            (Some(token), None) => println!("Issued by {}, supply {}{}", token.issuer, token.supply,
+                                if token.mintable { ", mintable" } else { "" }),
+                            (None, _) => println!("No token named {}", name),
+                        }
+                    },
+                    Ok(ReplCommand::RegisterName(name, value)) => {
+                        match submit(&keypair, fee, TransactionKind::RegisterName { name, value }, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::RenewName(name)) => {
+                        match submit(&keypair, fee, TransactionKind::RenewName(name), &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::TransferName(name, to)) => {
+                        match submit(&keypair, fee, TransactionKind::TransferName { name, to }, &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Resolve(name)) => {
+                        match ledger.lock().unwrap().resolve(&name) {
+                            Some(registration) => println!("{} -> {}, owned by {} until block {}",
+                                name, registration.value, registration.owner, registration.expires),
+                            None => println!("{} isn't registered", name),
+                        }
+                    },
+                    Ok(ReplCommand::Notarize(path)) => {
+                        let digest = match digest_file(Path::new(&path)) {
+                            Ok(digest) => digest,
+                            Err(e) => {
+                                println!("Couldn't read {}: {}", path, e);
+                                continue;
+                            },
+                        };
+                        match submit(&keypair, fee, TransactionKind::Notarize(digest), &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Notarizing {:?} in transaction {:?}", digest, transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Receipt(path)) => {
+                        let digest = match digest_file(Path::new(&path)) {
+                            Ok(digest) => digest,
+                            Err(e) => {
+                                println!("Couldn't read {}: {}", path, e);
+                                continue;
+                            },
+                        };
+                        match Receipt::find(&chain.lock().unwrap(), &digest) {
+                            Some(receipt) => println!("Notarized in block {} at {}:\n{}", receipt.height,
+                                time::at(ns_to_spec(receipt.timestamp)).rfc822(), receipt.to_base64()),
+                            None => println!("{:?} hasn't been notarized in a block yet", digest),
+                        }
+                    },
+                    Ok(ReplCommand::Store(path)) => {
+                        let mut payload = Vec::new();
+                        if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_end(&mut payload)) {
+                            println!("Couldn't read {}: {}", path, e);
+                            continue;
+                        }
+                        let (id, kinds) = match split(&payload) {
+                            Ok(split) => split,
+                            Err(e) => {
+                                println!("Error: {}", e);
+                                continue;
+                            },
+                        };
+                        match submit_all(&keypair, fee, kinds, block_budget, &ledger, &mempool, &network) {
+                            Ok(transactions) => println!("Storing {} bytes in {} transactions as manifest {:?}",
+                                payload.len(), transactions.len(), id),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Fetch(id, path)) => {
+                        let payload = reassemble(&chain.lock().unwrap(), &id);
+                        match payload {
+                            Ok(payload) => match File::create(&path).and_then(|mut file| file.write_all(&payload)) {
+                                Ok(()) => println!("Wrote {} bytes to {}", payload.len(), path),
+                                Err(e) => println!("Couldn't write {}: {}", path, e),
+                            },
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Encrypt(recipients, payload)) => {
+                        let envelope = match Envelope::seal(payload.as_bytes(), &recipients) {
+                            Ok(envelope) => envelope,
+                            Err(e) => {
+                                println!("Error: {}", e);
+                                continue;
+                            },
+                        };
+                        match submit(&keypair, fee, TransactionKind::Encrypted(envelope), &ledger, &mempool, &network) {
+                            Ok(transaction) => println!("Created transaction {:?}", transaction.hash()),
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::Bans) => {
+                        for (key, until) in reputation.lock().unwrap().bans() {
+                            println!("{} until {}", key, time::at(time::Timespec::new(until as i64, 0)).rfc822());
+                        }
+                    },
+                    Ok(ReplCommand::Unban(key)) => {
+                        let unbanned = reputation.lock().unwrap().unban(&key);
+                        match unbanned {
+                            Ok(true) => println!("Unbanned {}", key),
+                            Ok(false) => println!("{} isn't banned", key),
+                            Err(e) => println!("Unbanned {}, but couldn't save the ban list: {}", key, e),
+                        }
+                    },
+                    Ok(ReplCommand::Decrypt) => {
+                        for (height, payload) in addressed_to(&chain.lock().unwrap(), &keypair) {
+                            println!("Block {}: {}", height, String::from_utf8_lossy(&payload));
+                        }
+                    },
+                    Ok(ReplCommand::ShowContract(address)) => {
+                        match ledger.lock().unwrap().contract(&address) {
+                            Some(contract) => {
+                                println!("Created by {}, {} bytes of code", contract.creator, contract.code.len());
+                                for (key, value) in &contract.storage {
+                                    println!("{}: {}", hex(key), hex(value));
+                                }
+                            },
+                            None => println!("No contract at {}", address),
+                        }
+                    },
+                    Ok(ReplCommand::ShowMempool) => {
+                        let height = chain.lock().unwrap().last().unwrap().block_num + 1;
+                        let now = ns(&time::now_utc());
+                        let mempool = mempool.lock().unwrap();
+                        for transaction in mempool.iter() {
+                            match transaction.time_lock {
+                                Some(lock) if !transaction.is_mature(height, now) =>
+                                    println!("{:?} ({} bytes, locked until {:?})", transaction.hash(), transaction.size(), lock),
+                                _ => println!("{:?} ({} bytes)", transaction.hash(), transaction.size()),
                             }
                         }
-                        println!("Created block {}", block_num);
+                        println!("{} transactions, {} bytes", mempool.len(), mempool.size());
+                        for partial in mempool.partials() {
+                            println!("{:?} waiting for {} more signatures", partial.signing_hash(),
+                                partial.signatures_needed().unwrap_or(0));
+                        }
                     },
                     Ok(ReplCommand::Help) => {
                         for variant in ReplCommand::variants() {
@@ -299,20 +1587,81 @@ fn main() {
                         }
                     },
                     Ok(ReplCommand::ListPeers) => {
-                        let peers = peers.lock().unwrap();
-                        for peer in peers.iter() {
-                            let peer = peer.lock().unwrap();
-                            println!("{}", peer.peer_addr().unwrap());
+                        for peer in network.peers() {
+                            match peer.id() {
+                                Some(id) => println!("{} ({})", peer.addr(), id),
+                                None => println!("{}", peer.addr()),
+                            }
                         }
                     },
+                    Ok(ReplCommand::FindNode(id)) => match node.dht.lookup(Target::Node(id)) {
+                        Ok(lookup) => {
+                            if !lookup.is_done() {
+                                println!("Timed out, the closest found so far are:");
+                            }
+                            for contact in lookup.closest() {
+                                let found = if contact.id == id { " (found)" } else { "" };
+                                println!("{} ({}){}", contact.addr, contact.id, found);
+                            }
+                        },
+                        Err(e) => println!("{}", e),
+                    },
+                    Ok(ReplCommand::FindBlock(hash)) => match node.dht.lookup(Target::Value(hash)) {
+                        Ok(lookup) => match lookup.value() {
+                            Some((holder, block)) => println!("Block {} is held by {} ({})",
+                                block.block_num, holder.addr, holder.id),
+                            None if lookup.is_done() => println!("No one we could reach has that block"),
+                            None => println!("Timed out without finding that block"),
+                        },
+                        Err(e) => println!("{}", e),
+                    },
                     Ok(ReplCommand::Latest) => {
                         let chain = chain.lock().unwrap();
                         let last = chain.last().unwrap();
                         println!("Block number {} created at {}",
                             last.block_num, time::at(ns_to_spec(last.timestamp)).rfc822());
                     },
-                    Ok(ReplCommand::Exit) => {std::process::exit(0);},
-                    Ok(_) => {println!("Unhandled command");},
+                    Ok(ReplCommand::Checkpoints) => {
+                        for (height, hash) in checkpoints.iter() {
+                            println!("{}: {:?}", height, hash);
+                        }
+                        match checkpoints.max_reorg_depth() {
+                            Some(depth) => println!("Max reorg depth: {}", depth),
+                            None => println!("Max reorg depth: unlimited"),
+                        }
+                    },
+                    Ok(ReplCommand::Balance(address)) => {
+                        let ledger = ledger.lock().unwrap();
+                        println!("{}: balance {}, nonce {}", address, ledger.balance(&address), ledger.nonce(&address));
+                    },
+                    Ok(ReplCommand::Send(to, amount, time_lock)) => {
+                        let inserted = {
+                            let ledger = ledger.lock().unwrap();
+                            let mut mempool = mempool.lock().unwrap();
+                            let info = mempool.account_info(ledger.account_info(&keypair.address()));
+                            build_payment(&keypair, &info, to, amount, fee, time_lock)
+                                .and_then(|transaction| mempool.insert(transaction.clone(), &**ledger).map(|hash| (hash, transaction)))
+                        };
+                        match inserted {
+                            Ok((hash, transaction)) => {
+                                network.broadcast(&ClientMessage::NewTransaction(transaction));
+                                println!("Sent {} to {} in transaction {:?}", amount, to, hash);
+                            },
+                            Err(e) => println!("Error: {}", e),
+                        }
+                    },
+                    Ok(ReplCommand::ShowAddress) => {
+                        println!("{}", keypair.address());
+                    },
+                    Ok(ReplCommand::Exit) => {
+                        if let Err(e) = save_chain(&mut chainfile, &chain.lock().unwrap(), compression) {
+                            println!("Couldn't save the chain: {}", e);
+                        }
+                        if let Err(e) = node.addresses.lock().unwrap().save() {
+                            println!("Couldn't save the address book: {}", e);
+                        }
+                        std::process::exit(0);
+                    },
                     Err(e) => {println!("Error: {}", e);}
                 }
             }
@@ -356,4 +1705,5 @@ fn main() {
 
     repl_thread.join();
     listener_thread.join();
+    event_thread.join();
 }
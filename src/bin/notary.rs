@@ -0,0 +1,97 @@
+extern crate getopts;
+extern crate time;
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+extern crate naivechain_rs;
+use naivechain_rs::block::{Block, decode_chain};
+use naivechain_rs::compression::unpack_chainfile;
+use naivechain_rs::notary::{Receipt, digest_file};
+
+
+fn print_usage(program: &str, opts: getopts::Options) {
+    let brief = format!("Usage: {} -c FILE COMMAND\n\n\
+        Commands:\n    \
+        hash FILE            show the digest notarizing FILE records\n    \
+        receipt FILE         find the receipt proving FILE was notarized\n    \
+        verify-receipt RECEIPT [FILE]\n                             \
+        check RECEIPT against the chain, and that it's for FILE", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(msg: &str) -> ! {
+    writeln!(std::io::stderr(), "{}", msg).expect("Couldn't write error");
+    std::process::exit(1);
+}
+
+fn load_chain(matches: &getopts::Matches) -> Vec<Block> {
+    let chainfile = matches.opt_str("c").unwrap_or_else(|| fail("This command needs a chainfile (-c)"));
+    let mut serialized = Vec::new();
+    File::open(&chainfile).and_then(|mut file| file.read_to_end(&mut serialized))
+        .unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", chainfile, e)));
+    let serialized = unpack_chainfile(&serialized).unwrap_or_else(|e| fail(&format!("Couldn't decompress {}: {}", chainfile, e)));
+    decode_chain(&serialized).unwrap_or_else(|e| fail(&format!("Couldn't load {}: {}", chainfile, e)))
+}
+
+fn digest(path: &str) -> naivechain_rs::block::Hash32Byte {
+    digest_file(Path::new(path)).unwrap_or_else(|e| fail(&format!("Couldn't read {}: {}", path, e)))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut opts = getopts::Options::new();
+    opts.optopt("c", "chainfile", "chainfile of a node to check against", "FILE")
+        .optflag("h", "help", "show this message");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+            print_usage(&args[0], opts);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(&args[0], opts);
+        return;
+    }
+
+    match matches.free[0].as_str() {
+        "hash" => {
+            if matches.free.len() != 2 {
+                fail("Usage: hash FILE");
+            }
+            println!("{:?}", digest(&matches.free[1]));
+        },
+        "receipt" => {
+            if matches.free.len() != 2 {
+                fail("Usage: receipt FILE");
+            }
+            let digest = digest(&matches.free[1]);
+            match Receipt::find(&load_chain(&matches), &digest) {
+                Some(receipt) => println!("{}", receipt.to_base64()),
+                None => fail(&format!("{:?} hasn't been notarized", digest)),
+            }
+        },
+        "verify-receipt" => {
+            if matches.free.len() != 2 && matches.free.len() != 3 {
+                fail("Usage: verify-receipt RECEIPT [FILE]");
+            }
+            let receipt = Receipt::from_base64(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            if let Some(path) = matches.free.get(2) {
+                if digest(path) != receipt.digest {
+                    fail(&format!("The receipt isn't for {}", path));
+                }
+            }
+            receipt.verify(&load_chain(&matches)).unwrap_or_else(|e| fail(&e));
+            let timestamp = time::at_utc(time::Timespec::new((receipt.timestamp / 1_000_000_000) as i64,
+                (receipt.timestamp % 1_000_000_000) as i32));
+            println!("Valid: {:?} was notarized in block {} ({:?}) at {}",
+                receipt.digest, receipt.height, receipt.block_hash, timestamp.rfc822());
+        },
+        other => fail(&format!("Unknown command {}", other)),
+    }
+}
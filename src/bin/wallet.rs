@@ -0,0 +1,293 @@
+extern crate getopts;
+
+use std::io::prelude::*;
+use std::net::TcpStream;
+use std::path::Path;
+
+extern crate naivechain_rs;
+use naivechain_rs::connection::Connection;
+use naivechain_rs::hd::generate_mnemonic;
+use naivechain_rs::keys::{Address, Keypair, MultisigPolicy};
+use naivechain_rs::message::ClientMessage;
+use naivechain_rs::state::AccountInfo;
+use naivechain_rs::script::{Script, parse_hex};
+use naivechain_rs::transaction::{TimeLock, Transaction, Witness};
+use naivechain_rs::wallet::{Wallet, build_payment, build_multisig_payment, payment_kind, read_password};
+
+
+fn print_usage(program: &str, opts: getopts::Options) {
+    let brief = format!("Usage: {} -w FILE COMMAND\n\n\
+        Commands:\n    \
+        new [WORDS]          create a keystore from a new seed phrase of 12 to 24 words\n    \
+        restore [COUNT]      recreate a keystore and its first COUNT keys from a seed phrase\n    \
+        mnemonic             show the keystore's seed phrase\n    \
+        generate             add a new key to the keystore\n    \
+        address              list the keystore's addresses\n    \
+        balance              ask the node for the balance of each address\n    \
+        send ADDRESS AMOUNT  pay AMOUNT to ADDRESS through the node\n    \
+        multisig-address     show the address of the multisig account given by -m and -k\n    \
+        propose ADDRESS AMOUNT\n                             \
+        start a payment from that multisig account, signed by this wallet's keys\n    \
+        cosign TRANSACTION   add this wallet's signatures to a proposed payment\n    \
+        submit TRANSACTION   hand a partially signed payment to the node, which\n                             \
+        pools it once it has enough signatures\n    \
+        script-address SCRIPT\n                             \
+        show the address whose coins SCRIPT guards\n    \
+        script-send SCRIPT ADDRESS AMOUNT [ARG...]\n                             \
+        pay out of SCRIPT's address, with each ARG either hex data\n                             \
+        or sig:ADDRESS for a signature by one of the wallet's keys", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn fail(msg: &str) -> ! {
+    writeln!(std::io::stderr(), "{}", msg).expect("Couldn't write error");
+    std::process::exit(1);
+}
+
+fn prompt(prompt: &str) -> String {
+    print!("{}", prompt);
+    std::io::stdout().flush().unwrap();
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).expect("Couldn't read from stdin");
+    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+/// Connects to the node as the wallet's first key, so a node that only
+/// allows some keys can let the wallet in.
+fn connect(node: &Option<String>, wallet: &Wallet) -> Connection {
+    let node = match *node {
+        Some(ref node) => node,
+        None => fail("This command needs a node address (-n)"),
+    };
+    let keypair = wallet.keys().first().unwrap_or_else(|| fail("The wallet has no keys to connect with"));
+    match TcpStream::connect(node).and_then(|stream| Connection::handshake(stream, keypair, true)) {
+        Ok(connection) => connection,
+        Err(e) => fail(&format!("Couldn't connect to {}: {}", node, e)),
+    }
+}
+
+fn query_account(connection: &mut Connection, address: &Address) -> AccountInfo {
+    connection.write_message(&ClientMessage::QueryAccount(*address)).expect("Couldn't query the node");
+    loop {
+        // the node treats us like any other peer, so skip whatever else it says
+        match connection.read_message() {
+            Ok(Some(ClientMessage::Account(info))) => return info,
+            Ok(Some(_)) => continue,
+            Ok(None) => fail("The node hung up"),
+            Err(e) => fail(&format!("Error reading from the node: {}", e)),
+        }
+    }
+}
+
+fn multisig_policy(matches: &getopts::Matches) -> MultisigPolicy {
+    let threshold = matches.opt_str("m").unwrap_or_else(|| fail("Give the number of signatures needed with -m"))
+        .parse::<u8>().unwrap_or_else(|_| fail("Invalid threshold"));
+    let keys = matches.opt_strs("k").iter()
+        .map(|key| Address::from_hex(key).unwrap_or_else(|e| fail(&e)))
+        .collect();
+    MultisigPolicy::new(threshold, keys).unwrap_or_else(|e| fail(&e))
+}
+
+fn time_lock(matches: &getopts::Matches) -> Option<TimeLock> {
+    matches.opt_str("after").map(|lock| TimeLock::parse(&lock).unwrap_or_else(|e| fail(&e)))
+}
+
+fn print_partial(transaction: &Transaction) {
+    match transaction.signatures_needed() {
+        Ok(0) => println!("Fully signed, submit it to a node:"),
+        Ok(needed) => println!("Needs {} more signatures:", needed),
+        Err(e) => fail(&e),
+    }
+    println!("{}", transaction.to_base64());
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut opts = getopts::Options::new();
+    opts.reqopt("w", "wallet", "keystore location", "FILE")
+        .optopt("n", "node", "node to query and send transactions through, connecting as the wallet's \
+            first address", "ADDR")
+        .optopt("f", "from", "address to send from, defaults to the first one", "ADDRESS")
+        .optopt("", "fee", "fee to pay the block producer, defaults to 0", "AMOUNT")
+        .optopt("", "after", "keep the payment out of blocks until height:N or time:SECONDS", "LOCK")
+        .optopt("m", "threshold", "signatures a multisig account needs", "M")
+        .optmulti("k", "key", "one of a multisig account's keys, in order", "ADDRESS")
+        .optflag("h", "help", "show this message");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            writeln!(std::io::stderr(), "{}", e).expect("Couldn't write error");
+            print_usage(&args[0], opts);
+            std::process::exit(1);
+        }
+    };
+
+    if matches.opt_present("h") || matches.free.is_empty() {
+        print_usage(&args[0], opts);
+        return;
+    }
+    let wallet_file = matches.opt_str("w").unwrap();
+    let wallet_path = Path::new(&wallet_file);
+    let node = matches.opt_str("n");
+
+    let command = matches.free[0].as_str();
+    if command == "new" || command == "restore" {
+        if wallet_path.exists() {
+            fail(&format!("{} already exists", wallet_file));
+        }
+        let (mnemonic, count) = if command == "new" {
+            let words = matches.free.get(1).map_or(Ok(12), |words| words.parse::<usize>())
+                .unwrap_or_else(|_| fail("Invalid word count"));
+            (generate_mnemonic(words).unwrap_or_else(|e| fail(&e.to_string())), 1)
+        } else {
+            let count = matches.free.get(1).map_or(Ok(1), |count| count.parse::<u32>())
+                .unwrap_or_else(|_| fail("Invalid key count"));
+            (prompt("Seed phrase: "), count)
+        };
+        let wallet = Wallet::from_mnemonic(&mnemonic, count).unwrap_or_else(|e| fail(&e));
+        let password = read_password();
+        wallet.save(wallet_path, &password).expect("Couldn't write the keystore");
+        if command == "new" {
+            println!("Seed phrase, write it down: {}", mnemonic);
+        }
+        for address in wallet.addresses() {
+            println!("{}", address);
+        }
+        return;
+    }
+
+    if command == "multisig-address" {
+        println!("{}", multisig_policy(&matches).address());
+        return;
+    }
+    if command == "script-address" {
+        if matches.free.len() != 2 {
+            fail("Usage: script-address SCRIPT");
+        }
+        println!("{}", Script::parse(&matches.free[1]).unwrap_or_else(|e| fail(&e)).address());
+        return;
+    }
+
+    let password = read_password();
+    let mut wallet = match Wallet::load(wallet_path, &password) {
+        Ok(wallet) => wallet,
+        Err(e) => fail(&format!("Couldn't load {}: {}", wallet_file, e)),
+    };
+
+    match command {
+        "generate" => {
+            let address = wallet.generate_key().expect("Couldn't generate a key");
+            wallet.save(wallet_path, &password).expect("Couldn't write the keystore");
+            println!("{}", address);
+        },
+        "mnemonic" => {
+            match wallet.mnemonic() {
+                Some(mnemonic) => println!("{}", mnemonic),
+                None => fail("This keystore predates seed phrases"),
+            }
+        },
+        "address" => {
+            for address in wallet.addresses() {
+                println!("{}", address);
+            }
+        },
+        "submit" => {
+            if matches.free.len() != 2 {
+                fail("Usage: submit TRANSACTION");
+            }
+            let transaction = Transaction::from_base64(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            let mut connection = connect(&node, &wallet);
+            connection.write_message(&ClientMessage::PartialTransaction(transaction))
+                .expect("Couldn't send the transaction");
+        },
+        "balance" => {
+            let mut connection = connect(&node, &wallet);
+            for address in wallet.addresses() {
+                let info = query_account(&mut connection, &address);
+                println!("{}: {}", address, info.balance);
+            }
+        },
+        "send" => {
+            if matches.free.len() != 3 {
+                fail("Usage: send ADDRESS AMOUNT");
+            }
+            let to = Address::from_hex(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            let amount = matches.free[2].parse::<u64>().unwrap_or_else(|_| fail("Invalid amount"));
+            let fee = matches.opt_str("fee").map_or(Ok(0), |fee| fee.parse::<u64>())
+                .unwrap_or_else(|_| fail("Invalid fee"));
+            let keypair: Keypair = match matches.opt_str("f") {
+                Some(from) => {
+                    let from = Address::from_hex(&from).unwrap_or_else(|e| fail(&e));
+                    wallet.key_for(&from).cloned().unwrap_or_else(|| fail("That address isn't in the wallet"))
+                },
+                None => wallet.keys().first().cloned().unwrap_or_else(|| fail("The wallet has no keys")),
+            };
+
+            let mut connection = connect(&node, &wallet);
+            let info = query_account(&mut connection, &keypair.address());
+            let transaction = build_payment(&keypair, &info, to, amount, fee, time_lock(&matches)).unwrap_or_else(|e| fail(&e));
+            connection.write_message(&ClientMessage::NewTransaction(transaction.clone()))
+                .expect("Couldn't send the transaction");
+            println!("Sent transaction {:?}", transaction.hash());
+        },
+        "propose" => {
+            if matches.free.len() != 3 {
+                fail("Usage: propose ADDRESS AMOUNT");
+            }
+            let to = Address::from_hex(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            let amount = matches.free[2].parse::<u64>().unwrap_or_else(|_| fail("Invalid amount"));
+            let fee = matches.opt_str("fee").map_or(Ok(0), |fee| fee.parse::<u64>())
+                .unwrap_or_else(|_| fail("Invalid fee"));
+            let policy = multisig_policy(&matches);
+
+            let mut connection = connect(&node, &wallet);
+            let info = query_account(&mut connection, &policy.address());
+            let transaction = build_multisig_payment(&wallet, policy, &info, to, amount, fee, time_lock(&matches))
+                .unwrap_or_else(|e| fail(&e));
+            print_partial(&transaction);
+        },
+        "cosign" => {
+            if matches.free.len() != 2 {
+                fail("Usage: cosign TRANSACTION");
+            }
+            let mut transaction = Transaction::from_base64(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            if wallet.cosign(&mut transaction) == 0 {
+                fail("None of this wallet's keys can add a signature");
+            }
+            print_partial(&transaction);
+        },
+        "script-send" => {
+            if matches.free.len() < 4 {
+                fail("Usage: script-send SCRIPT ADDRESS AMOUNT [ARG...]");
+            }
+            let script = Script::parse(&matches.free[1]).unwrap_or_else(|e| fail(&e));
+            let to = Address::from_hex(&matches.free[2]).unwrap_or_else(|e| fail(&e));
+            let amount = matches.free[3].parse::<u64>().unwrap_or_else(|_| fail("Invalid amount"));
+            let fee = matches.opt_str("fee").map_or(Ok(0), |fee| fee.parse::<u64>())
+                .unwrap_or_else(|_| fail("Invalid fee"));
+
+            let mut connection = connect(&node, &wallet);
+            let info = query_account(&mut connection, &script.address());
+            let kind = payment_kind(&info, to, amount, fee).unwrap_or_else(|e| fail(&e));
+            let mut transaction = Transaction::new_scripted(script.clone(), info.nonce, fee, time_lock(&matches), kind);
+            let message = transaction.signing_bytes();
+            let args = matches.free[4..].iter().map(|arg| {
+                if let Some(address) = arg.strip_prefix("sig:") {
+                    let address = Address::from_hex(address).unwrap_or_else(|e| fail(&e));
+                    let key = wallet.key_for(&address).unwrap_or_else(|| fail("That address isn't in the wallet"));
+                    key.sign(&message).0.to_vec()
+                } else {
+                    parse_hex(arg).unwrap_or_else(|e| fail(&e))
+                }
+            }).collect();
+            transaction.witness = Witness::Script { script, args };
+            transaction.validate().unwrap_or_else(|e| fail(&e));
+
+            connection.write_message(&ClientMessage::NewTransaction(transaction.clone()))
+                .expect("Couldn't send the transaction");
+            println!("Sent transaction {:?}", transaction.hash());
+        },
+        other => fail(&format!("Unknown command {}", other)),
+    }
+}
@@ -11,11 +11,43 @@ use std::cmp::{PartialEq, Eq};
 
 use time;
 
+use bincode::{serialize, deserialize, Infinite};
 
-#[derive(Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+
+/// Starts a serialized chain, before its format version.
+const CHAIN_MAGIC: &[u8; 4] = b"NCHN";
+
+/// The layout of serialized blocks in a chainfile. Bump it whenever
+/// `Block` changes, so older chainfiles are refused rather than misread.
+/// Version 1 is the unversioned layout from before blocks had state and
+/// transactions roots.
+pub const CHAIN_VERSION: u32 = 2;
+
+/// How many of the latest blocks a new block's timestamp has to be later
+/// than the median of.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far ahead of our clock a block's timestamp may be, in ns.
+pub const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60 * 1_000_000_000;
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Hash32Byte(pub [u8; 32]);
 pub struct BlockData(pub [u8; 1024]);
 
+impl Hash32Byte {
+    pub fn from_hex(s: &str) -> Result<Hash32Byte, String> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format!("Expected 64 hex digits, got {:?}", s));
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| format!("Invalid hex in {:?}", s))?;
+        }
+        Ok(Hash32Byte(bytes))
+    }
+}
+
 impl PartialEq for BlockData {
     fn eq(&self, other: &BlockData) -> bool {
         if self.0.len() != other.0.len() {
@@ -70,6 +102,10 @@ impl<'de> Deserialize<'de> for BlockData {
             fn visit_bytes<E>(self, v: &[u8]) -> Result<BlockData, E>
                 where E: serde::de::Error
             {
+                // blocks come from peers, so a wrong length is an error, not a panic
+                if v.len() != 1024 {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
                 let mut data = [0u8; 1024];
                 data.copy_from_slice(v);
                 Ok(BlockData(data))
@@ -78,11 +114,7 @@ impl<'de> Deserialize<'de> for BlockData {
             fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<BlockData, E>
                 where E: serde::de::Error
             {
-                let mut data = [0u8; 1024];
-                for i in 0..1024 {
-                    data[i] = v[i];
-                }
-                Ok(BlockData(data))
+                self.visit_bytes(&v)
             }
         }
 
@@ -110,11 +142,17 @@ pub struct Block {
     pub block_num: u64,
     pub previous_hash: Hash32Byte,
     pub timestamp: u64, // ns
+    /// What `Ledger::state_root` must be after applying this block.
+    pub state_root: Hash32Byte,
+    /// Merkle root of the hashes of the transactions in `data`, so a
+    /// transaction can be shown to be in the block with just the header.
+    pub transactions_root: Hash32Byte,
     pub data: BlockData,
     pub hash: Hash32Byte
 }
 
-fn make_hash(block_num: u64, previous_hash: Hash32Byte, timestamp: u64, data: [u8; 1024]) -> Hash32Byte {
+fn make_hash(block_num: u64, previous_hash: Hash32Byte, timestamp: u64, state_root: Hash32Byte,
+             transactions_root: Hash32Byte, data: [u8; 1024]) -> Hash32Byte {
     let mut sha = Sha256::new();
 
     let mut buf = &mut [0; 8];
@@ -126,6 +164,9 @@ fn make_hash(block_num: u64, previous_hash: Hash32Byte, timestamp: u64, data: [u
     byteorder::BigEndian::write_u64(buf, timestamp);
     sha.input(buf);
 
+    sha.input(&state_root.0);
+    sha.input(&transactions_root.0);
+
     sha.input(&data);
 
     let mut output = [0; 32];
@@ -135,33 +176,47 @@ fn make_hash(block_num: u64, previous_hash: Hash32Byte, timestamp: u64, data: [u
     return Hash32Byte(output);
 }
 
-fn ns(time: &time::Tm) -> u64 {
+pub fn ns(time: &time::Tm) -> u64 {
     let spec = time.to_timespec();
     return (spec.sec * 1_000_000_000 + (spec.nsec as i64)) as u64;
 }
 
 impl Block {
-    pub fn new(past_block: &Block, data: [u8; 1024]) -> Block {
+    /// Makes the block after `past_block`. `timestamp` should be now, as
+    /// given by `ns`; it has to be picked before the block's transactions
+    /// are run, since contracts can see it.
+    pub fn new(past_block: &Block, timestamp: u64, state_root: Hash32Byte, transactions_root: Hash32Byte,
+               data: [u8; 1024]) -> Block {
         let block_num = past_block.block_num + 1;
-        let now = time::now_utc();
-        let ts = ns(&now);
 
         Block{
             block_num: block_num,
             previous_hash: past_block.hash,
-            timestamp: ts,
+            timestamp,
+            state_root,
+            transactions_root,
             data: BlockData(data),
-            hash: make_hash(block_num, past_block.hash, ts, data)
+            hash: make_hash(block_num, past_block.hash, timestamp, state_root, transactions_root, data)
         }
     }
 
-    pub fn genesis() -> Block {
+    /// Whether `hash` really is the hash of the rest of the block.
+    pub fn has_valid_hash(&self) -> bool {
+        self.hash == make_hash(self.block_num, self.previous_hash, self.timestamp, self.state_root,
+                               self.transactions_root, self.data.0)
+    }
+
+    /// The first block of a chain whose ledger starts at `state_root`, so
+    /// nodes that don't agree on the starting balances don't share a chain.
+    pub fn genesis(state_root: Hash32Byte) -> Block {
         Block {
             block_num: 0,
             previous_hash: Hash32Byte([0; 32]),
             timestamp: 0,
+            state_root,
+            transactions_root: Hash32Byte([0; 32]),
             data: BlockData([0; 1024]),
-            hash: make_hash(0, Hash32Byte([0; 32]), 0, [0; 1024])
+            hash: make_hash(0, Hash32Byte([0; 32]), 0, state_root, Hash32Byte([0; 32]), [0; 1024])
         }
     }
 }
@@ -185,23 +240,93 @@ impl Block {
 //     }
 // }
 
+/// The median timestamp of the last `MEDIAN_TIME_SPAN` of `parents`,
+/// which a block after them has to beat.
+pub fn median_time_past(parents: &[Block]) -> u64 {
+    let mut timestamps: Vec<u64> = parents.iter().rev().take(MEDIAN_TIME_SPAN).map(|block| block.timestamp).collect();
+    timestamps.sort();
+    timestamps.get(timestamps.len() / 2).cloned().unwrap_or(0)
+}
+
+/// Checks that `block`'s timestamp, which time locks and contracts trust,
+/// is later than the median of the blocks before it and not too far ahead
+/// of `now`. Blocks can't just pick the time they like, then: the past is
+/// fixed by the median and the future by everyone's clocks.
+pub fn check_timestamp(block: &Block, parents: &[Block], now: u64) -> Result<(), String> {
+    if block.timestamp <= median_time_past(parents) {
+        return Err(format!("Block {} is timestamped before the blocks it follows", block.block_num));
+    }
+    if block.timestamp > now.saturating_add(MAX_FUTURE_DRIFT) {
+        return Err(format!("Block {} is timestamped too far in the future", block.block_num));
+    }
+    Ok(())
+}
+
+/// Serializes `chain` for a chainfile, with its format version.
+pub fn encode_chain(chain: &[Block]) -> Vec<u8> {
+    let mut encoded = CHAIN_MAGIC.to_vec();
+    let mut version = [0; 4];
+    byteorder::LittleEndian::write_u32(&mut version, CHAIN_VERSION);
+    encoded.extend_from_slice(&version);
+    encoded.extend(serialize(chain, Infinite).unwrap());
+    encoded
+}
+
+/// The chain in `bytes` from a chainfile, if it's in the current format.
+pub fn decode_chain(bytes: &[u8]) -> Result<Vec<Block>, String> {
+    if bytes.len() < CHAIN_MAGIC.len() + 4 || !bytes.starts_with(CHAIN_MAGIC) {
+        return Err(format!("It's from before chainfiles had versions (version 1), but this node reads version {}; \
+            move it aside to start again from genesis", CHAIN_VERSION));
+    }
+    let version = byteorder::LittleEndian::read_u32(&bytes[CHAIN_MAGIC.len()..]);
+    if version != CHAIN_VERSION {
+        return Err(format!("It's version {}, but this node reads version {}", version, CHAIN_VERSION));
+    }
+    deserialize(&bytes[CHAIN_MAGIC.len() + 4..]).map_err(|e| format!("It's corrupt: {}", e))
+}
+
 pub fn check_chain<'a>(chain: &[Block]) -> bool {
-    // check that the chain is unbroken, and the first block is genesis
+    // check that the chain is unbroken, every block's hash is right, and
+    // the first block is a genesis block; which one is for checkpoints
     if let Some((first, rest)) = chain.split_first() {
-        if *first != Block::genesis() {
+        if *first != Block::genesis(first.state_root) {
             return false;
         }
 
         let (valid, _) = rest.iter().fold(
             (true, first), |acc, x|
                 (acc.0 &&
+                    x.has_valid_hash() &&
                     x.previous_hash == acc.1.hash &&
                     x.block_num == acc.1.block_num + 1,
                 x)
         );
 
-        return valid;
+        let now = ns(&time::now_utc());
+        valid && (1..chain.len()).all(|i| check_timestamp(&chain[i], &chain[..i], now).is_ok())
     } else {
-        return true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_data_of_the_wrong_length_is_an_error() {
+        for len in &[0, 10, 1023, 1025] {
+            let serialized = serialize(&vec![7u8; *len], Infinite).unwrap();
+            assert!(deserialize::<BlockData>(&serialized).is_err());
+        }
+        let serialized = serialize(&vec![7u8; 1024], Infinite).unwrap();
+        assert!(deserialize::<BlockData>(&serialized).unwrap() == BlockData([7; 1024]));
+    }
+
+    #[test]
+    fn chain_roundtrips_through_chainfile_encoding() {
+        let chain = vec![Block::genesis(Hash32Byte([0; 32]))];
+        assert_eq!(decode_chain(&encode_chain(&chain)).unwrap(), chain);
+        assert!(decode_chain(&serialize(&chain, Infinite).unwrap()).is_err());
     }
 }
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+use block::{Block, Hash32Byte};
+
+
+/// Known-good block hashes by height, plus an optional limit on how many of
+/// our blocks a competing chain is allowed to replace.
+pub struct Checkpoints {
+    points: BTreeMap<u64, Hash32Byte>,
+    max_reorg_depth: Option<u64>,
+}
+
+impl Checkpoints {
+    /// The hardcoded checkpoints; currently just `genesis`, the block our
+    /// chain starts from.
+    pub fn new(genesis: &Block) -> Checkpoints {
+        let mut points = BTreeMap::new();
+        points.insert(0, genesis.hash);
+        Checkpoints {
+            points,
+            max_reorg_depth: None,
+        }
+    }
+
+    pub fn add(&mut self, height: u64, hash: Hash32Byte) {
+        self.points.insert(height, hash);
+    }
+
+    pub fn set_max_reorg_depth(&mut self, depth: Option<u64>) {
+        self.max_reorg_depth = depth;
+    }
+
+    pub fn max_reorg_depth(&self) -> Option<u64> {
+        self.max_reorg_depth
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<'_, u64, Hash32Byte> {
+        self.points.iter()
+    }
+
+    /// Parses a checkpoint given on the command line as `HEIGHT:HASH`.
+    pub fn parse(s: &str) -> Result<(u64, Hash32Byte), String> {
+        let mut parts = s.splitn(2, ':');
+        let height = match parts.next().map(|h| h.parse::<u64>()) {
+            Some(Ok(h)) => h,
+            _ => return Err(format!("Invalid checkpoint height in {}", s)),
+        };
+        match parts.next().map(Hash32Byte::from_hex) {
+            Some(Ok(hash)) => Ok((height, hash)),
+            Some(Err(e)) => Err(e),
+            None => Err(format!("Checkpoint {} is missing a hash", s)),
+        }
+    }
+
+    /// Whether a block may sit at its height without contradicting a checkpoint.
+    pub fn accepts_block(&self, block: &Block) -> bool {
+        match self.points.get(&block.block_num) {
+            Some(hash) => *hash == block.hash,
+            None => true,
+        }
+    }
+
+    /// Whether `their_chain` agrees with every checkpoint it reaches and
+    /// would not roll back more than `max_reorg_depth` of `my_chain`.
+    pub fn accepts_chain(&self, their_chain: &[Block], my_chain: &[Block]) -> bool {
+        for (&height, hash) in self.points.iter() {
+            if let Some(block) = their_chain.get(height as usize) {
+                if block.hash != *hash {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(max_depth) = self.max_reorg_depth {
+            let common = their_chain.iter().zip(my_chain.iter())
+                .take_while(|&(theirs, mine)| theirs.hash == mine.hash)
+                .count();
+            if (my_chain.len() - common) as u64 > max_depth {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
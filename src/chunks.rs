@@ -0,0 +1,231 @@
+//! Storing payloads too big for one block. The payload is split into chunks
+//! which each go on the chain as a `Data` transaction, and a `Manifest`
+//! transaction lists the chunks' hashes in order, so the payload can be put
+//! back together and checked given just the manifest's id.
+
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::HashMap;
+
+use block::{Block, Hash32Byte};
+use transaction::{TransactionKind, block_transactions};
+
+
+/// Bytes per chunk, small enough that a signed `Data` transaction carrying
+/// one fits in a block.
+pub const CHUNK_SIZE: usize = 768;
+
+/// Most entries a manifest can list while still fitting in a block.
+pub const MAX_CHUNKS: usize = 24;
+
+/// Most levels of manifests listing other manifests, which caps payloads at
+/// `CHUNK_SIZE * MAX_CHUNKS^(MAX_DEPTH + 1)` bytes, about 250 MB.
+pub const MAX_DEPTH: u8 = 3;
+
+fn sha256(bytes: &[u8]) -> Hash32Byte {
+    let mut sha = Sha256::new();
+    sha.input(bytes);
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    Hash32Byte(output)
+}
+
+/// How many bytes of the payload each entry of a manifest at `depth` holds,
+/// unless it's the last.
+fn span(depth: u8) -> u64 {
+    CHUNK_SIZE as u64 * (MAX_CHUNKS as u64).pow(depth as u32)
+}
+
+/// The most a payload can be.
+pub fn max_payload() -> u64 {
+    span(MAX_DEPTH) * MAX_CHUNKS as u64
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Total size of the part of the payload this manifest covers, in
+    /// bytes.
+    pub size: u64,
+    /// 0 if the entries are chunks, otherwise one more than the depth of
+    /// the manifests they are.
+    pub depth: u8,
+    /// Hash of each chunk, or id of each manifest, in order.
+    pub entries: Vec<Hash32Byte>,
+}
+
+impl Manifest {
+    /// What the payload is fetched by.
+    pub fn id(&self) -> Hash32Byte {
+        sha256(&serialize(self, Infinite).unwrap())
+    }
+
+    /// Checks the entry count agrees with the size, given every entry but
+    /// the last is full.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.entries.is_empty() || self.entries.len() > MAX_CHUNKS {
+            return Err(format!("Manifests must list between 1 and {} entries", MAX_CHUNKS));
+        }
+        if self.depth > MAX_DEPTH {
+            return Err(format!("Manifests can only be nested {} deep", MAX_DEPTH));
+        }
+        let full = (self.entries.len() - 1) as u64 * span(self.depth);
+        if self.size <= full || self.size > full + span(self.depth) {
+            return Err(format!("{} entries can't hold {} bytes", self.entries.len(), self.size));
+        }
+        Ok(())
+    }
+}
+
+/// Splits `payload` into the transactions storing it: the chunks, then
+/// manifests listing up to `MAX_CHUNKS` of them, then manifests listing
+/// those, and so on up to a single manifest, whose id is returned too.
+pub fn split(payload: &[u8]) -> Result<(Hash32Byte, Vec<TransactionKind>), String> {
+    if payload.is_empty() || payload.len() as u64 > max_payload() {
+        return Err(format!("Payloads must be between 1 and {} bytes", max_payload()));
+    }
+    let mut transactions = Vec::new();
+    // the hash and size of each entry of the level being listed
+    let mut level = Vec::new();
+    for chunk in payload.chunks(CHUNK_SIZE) {
+        level.push((sha256(chunk), chunk.len() as u64));
+        transactions.push(TransactionKind::Data(chunk.to_vec()));
+    }
+    let mut depth = 0;
+    loop {
+        let manifests: Vec<Manifest> = level.chunks(MAX_CHUNKS).map(|entries| Manifest {
+            size: entries.iter().map(|&(_, size)| size).sum(),
+            depth,
+            entries: entries.iter().map(|&(hash, _)| hash).collect(),
+        }).collect();
+        level = manifests.iter().map(|manifest| (manifest.id(), manifest.size)).collect();
+        transactions.extend(manifests.into_iter().map(TransactionKind::Manifest));
+        if level.len() == 1 {
+            return Ok((level[0].0, transactions));
+        }
+        depth += 1;
+    }
+}
+
+/// Puts the payload with manifest `id` back together from the chunks and
+/// manifests in `chain`, checking every one against what lists it.
+pub fn reassemble(chain: &[Block], id: &Hash32Byte) -> Result<Vec<u8>, String> {
+    let mut chunks: HashMap<Hash32Byte, Vec<u8>> = HashMap::new();
+    let mut manifests: HashMap<Hash32Byte, Manifest> = HashMap::new();
+    for transaction in chain.iter().flat_map(block_transactions) {
+        match transaction.kind {
+            TransactionKind::Data(data) => {
+                chunks.insert(sha256(&data), data);
+            },
+            TransactionKind::Manifest(manifest) => {
+                manifests.insert(manifest.id(), manifest);
+            },
+            _ => {},
+        }
+    }
+
+    let manifest = manifests.get(id).ok_or(format!("No manifest {:?} on the chain", id))?;
+    let mut payload = Vec::with_capacity(manifest.size as usize);
+    collect(manifest, &manifests, &chunks, &mut payload)?;
+    if payload.len() as u64 != manifest.size {
+        return Err(format!("Chunks add up to {} bytes, but the manifest says {}", payload.len(), manifest.size));
+    }
+    Ok(payload)
+}
+
+/// Appends the part of the payload `manifest` covers to `payload`.
+fn collect(manifest: &Manifest, manifests: &HashMap<Hash32Byte, Manifest>, chunks: &HashMap<Hash32Byte, Vec<u8>>,
+           payload: &mut Vec<u8>) -> Result<(), String> {
+    for hash in &manifest.entries {
+        if manifest.depth == 0 {
+            let chunk = chunks.get(hash).ok_or(format!("Chunk {:?} isn't on the chain yet", hash))?;
+            payload.extend_from_slice(chunk);
+            continue;
+        }
+        match manifests.get(hash) {
+            Some(inner) if inner.depth + 1 == manifest.depth => collect(inner, manifests, chunks, payload)?,
+            Some(_) => return Err(format!("Manifest {:?} is listed at the wrong depth", hash)),
+            None => return Err(format!("Manifest {:?} isn't on the chain yet", hash)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+    use transaction::{BlockBody, Transaction, pack_block};
+
+    /// A chain with each of `kinds` in a block of its own.
+    fn chain_of(kinds: Vec<TransactionKind>) -> Vec<Block> {
+        let keypair = Keypair::from_seed(&[1; 32]);
+        let mut chain = vec![Block::genesis(Hash32Byte([0; 32]))];
+        for (nonce, kind) in kinds.into_iter().enumerate() {
+            let body = BlockBody { reward: None, transactions: vec![Transaction::new(&keypair, nonce as u64, 0, kind)] };
+            let data = pack_block(&body).expect("Transaction doesn't fit in a block");
+            let block = Block::new(chain.last().unwrap(), nonce as u64 + 1, Hash32Byte([0; 32]), body.transactions_root(), data);
+            chain.push(block);
+        }
+        chain
+    }
+
+    fn payload(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn small_payload_has_one_manifest() {
+        let (id, transactions) = split(&payload(100)).unwrap();
+        assert_eq!(transactions.len(), 2);
+        match transactions[1] {
+            TransactionKind::Manifest(ref manifest) => {
+                assert_eq!(manifest.id(), id);
+                assert_eq!(manifest.depth, 0);
+                assert_eq!(manifest.size, 100);
+            },
+            ref other => panic!("Expected a manifest, got {:?}", other),
+        }
+        assert_eq!(reassemble(&chain_of(transactions), &id).unwrap(), payload(100));
+    }
+
+    #[test]
+    fn large_payload_nests_manifests() {
+        // more than one manifest's worth of chunks, so one level of nesting
+        let size = CHUNK_SIZE * MAX_CHUNKS * 3 + 5;
+        let (id, transactions) = split(&payload(size)).unwrap();
+        let manifests: Vec<&Manifest> = transactions.iter().filter_map(|kind| match *kind {
+            TransactionKind::Manifest(ref manifest) => Some(manifest),
+            _ => None,
+        }).collect();
+        assert_eq!(manifests.len(), 5);
+        assert_eq!(manifests.last().unwrap().depth, 1);
+        assert_eq!(manifests.last().unwrap().id(), id);
+        assert!(manifests.iter().all(|manifest| manifest.validate().is_ok()));
+        assert_eq!(reassemble(&chain_of(transactions), &id).unwrap(), payload(size));
+    }
+
+    #[test]
+    fn manifests_fit_in_blocks() {
+        let manifest = Manifest { size: max_payload(), depth: MAX_DEPTH, entries: vec![Hash32Byte([0xff; 32]); MAX_CHUNKS] };
+        assert!(manifest.validate().is_ok());
+        chain_of(vec![TransactionKind::Manifest(manifest), TransactionKind::Data(vec![0xff; CHUNK_SIZE])]);
+    }
+
+    #[test]
+    fn missing_chunk_is_reported() {
+        let (id, mut transactions) = split(&payload(CHUNK_SIZE * 2)).unwrap();
+        transactions.remove(0);
+        assert!(reassemble(&chain_of(transactions), &id).unwrap_err().contains("isn't on the chain yet"));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        assert!(split(&[]).is_err());
+        let manifest = Manifest { size: CHUNK_SIZE as u64 + 1, depth: 0, entries: vec![Hash32Byte([0; 32])] };
+        assert!(manifest.validate().is_err());
+        let manifest = Manifest { size: 1, depth: MAX_DEPTH + 1, entries: vec![Hash32Byte([0; 32])] };
+        assert!(manifest.validate().is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Compression for `Connection` frames and chainfiles. Mostly-empty
+//! `BlockData` compresses very well, which matters most for `Chain`
+//! messages and the chainfile, which hold every block.
+
+use lz4_flex;
+use std::fmt;
+use std::io;
+use zstd;
+
+
+/// Largest a compressed chainfile may expand to, so a tiny malicious one
+/// can't make us allocate without bound.
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Starts a compressed chainfile. Chainfiles without it are plain bincode.
+const CHAINFILE_MAGIC: &[u8; 4] = b"NCZ1";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn parse(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression {}, expected zstd, lz4 or none", s)),
+        }
+    }
+
+    /// Identifies the compression at the start of each frame.
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> io::Result<Compression> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unknown compression {}", tag))),
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).expect("Couldn't compress"),
+        }
+    }
+
+    /// Decompresses `data`, failing if it would expand past `limit` bytes.
+    pub fn decompress(self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let invalid = |e: &dyn fmt::Display| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+        match self {
+            Compression::None if data.len() > limit => Err(invalid(&"Data is too big")),
+            Compression::None => Ok(data.to_vec()),
+            Compression::Lz4 => {
+                let (size, _) = lz4_flex::block::uncompressed_size(data).map_err(|e| invalid(&e))?;
+                if size > limit {
+                    return Err(invalid(&"Compressed data is too big"));
+                }
+                lz4_flex::decompress_size_prepended(data).map_err(|e| invalid(&e))
+            },
+            Compression::Zstd => zstd::bulk::decompress(data, limit),
+        }
+    }
+
+    /// What a node offers in its handshake when it prefers `self`: that,
+    /// then no compression, which every node understands.
+    pub fn offer(self) -> Vec<Compression> {
+        if self == Compression::None {
+            vec![Compression::None]
+        } else {
+            vec![self, Compression::None]
+        }
+    }
+}
+
+/// Picks what to compress frames to a peer with: our favourite that the
+/// peer also offered.
+pub fn negotiate(ours: &[Compression], theirs: &[Compression]) -> Compression {
+    ours.iter().cloned().find(|compression| theirs.contains(compression)).unwrap_or(Compression::None)
+}
+
+/// The bytes of a chainfile holding `chain`, already serialized.
+pub fn pack_chainfile(compression: Compression, chain: &[u8]) -> Vec<u8> {
+    if compression == Compression::None {
+        return chain.to_vec();
+    }
+    let mut packed = CHAINFILE_MAGIC.to_vec();
+    packed.push(compression.tag());
+    packed.extend(compression.compress(chain));
+    packed
+}
+
+/// The serialized chain in a chainfile, compressed or not.
+pub fn unpack_chainfile(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    if bytes.len() > CHAINFILE_MAGIC.len() && bytes.starts_with(CHAINFILE_MAGIC) {
+        Compression::from_tag(bytes[CHAINFILE_MAGIC.len()])?.decompress(&bytes[CHAINFILE_MAGIC.len() + 1..], MAX_DECOMPRESSED_SIZE)
+    } else {
+        Ok(bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Compression; 3] = [Compression::None, Compression::Lz4, Compression::Zstd];
+
+    fn data() -> Vec<u8> {
+        (0..10_000u32).map(|i| (i % 17) as u8).collect()
+    }
+
+    #[test]
+    fn compression_roundtrips() {
+        for &compression in &ALL {
+            let compressed = compression.compress(&data());
+            assert_eq!(compression.decompress(&compressed, data().len()).unwrap(), data());
+            assert!(compression.decompress(&compressed, data().len() - 1).is_err());
+            assert_eq!(Compression::from_tag(compression.tag()).unwrap(), compression);
+        }
+        assert!(Compression::Lz4.compress(&data()).len() < data().len());
+        assert!(Compression::from_tag(3).is_err());
+    }
+
+    #[test]
+    fn garbage_doesnt_decompress() {
+        let garbage = vec![0xff; 64];
+        assert!(Compression::Lz4.decompress(&garbage, MAX_DECOMPRESSED_SIZE).is_err());
+        assert!(Compression::Zstd.decompress(&garbage, MAX_DECOMPRESSED_SIZE).is_err());
+    }
+
+    #[test]
+    fn lz4_claiming_too_much_is_refused() {
+        let mut compressed = Compression::Lz4.compress(&data());
+        compressed[..4].copy_from_slice(&(MAX_DECOMPRESSED_SIZE as u32 + 1).to_le_bytes());
+        assert!(Compression::Lz4.decompress(&compressed, MAX_DECOMPRESSED_SIZE).is_err());
+    }
+
+    #[test]
+    fn negotiation_picks_our_favourite_they_offer() {
+        assert_eq!(negotiate(&Compression::Zstd.offer(), &Compression::Zstd.offer()), Compression::Zstd);
+        assert_eq!(negotiate(&Compression::Zstd.offer(), &Compression::Lz4.offer()), Compression::None);
+        assert_eq!(negotiate(&[Compression::Lz4, Compression::Zstd], &[Compression::Zstd, Compression::Lz4]),
+            Compression::Lz4);
+        assert_eq!(negotiate(&Compression::None.offer(), &[]), Compression::None);
+    }
+
+    #[test]
+    fn chainfiles_unpack_compressed_or_not() {
+        for &compression in &ALL {
+            assert_eq!(unpack_chainfile(&pack_chainfile(compression, &data())).unwrap(), data());
+        }
+        assert_eq!(pack_chainfile(Compression::None, &data()), data());
+    }
+}
@@ -1,61 +1,311 @@
 use std::net::{TcpStream, SocketAddr};
 use std::io;
 use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
 use byteorder::{ByteOrder, NetworkEndian};
 use bincode::{serialize, deserialize, Infinite};
 use serde::ser::Serialize;
 use serde::de::DeserializeOwned;
+use snow;
 
+use compression::Compression;
+use keys::{verify, Address, Keypair, Signature};
+use message::MAX_MESSAGE_SIZE;
 
+
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Longest message Noise will encrypt, including its tag.
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_SIZE: usize = 16;
+
+/// How much is read from a socket at a time.
+const READ_SIZE: usize = 64 * 1024;
+
+/// Largest frame we'll buffer. Compression and encryption only add a
+/// little to a message, so this is plenty for the largest one allowed, and
+/// small enough that decoding a frame never holds up the network thread
+/// for long.
+const MAX_FRAME_SIZE: u64 = 2 * MAX_MESSAGE_SIZE as u64;
+
+/// How long the other end gets to finish the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Turns messages into frames: a size, then a byte saying how the rest is
+/// compressed, so the two ends can compress differently, or not at all.
+/// On a secured connection, the rest is encrypted in pieces no bigger than
+/// a Noise message.
+pub struct FrameWriter {
+    compression: Compression,
+    transport: Option<Arc<snow::StatelessTransportState>>,
+    nonce: u64,
+}
+
+impl FrameWriter {
+    /// Compresses frames written from now on, once the handshake has shown
+    /// the peer can read them.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// The bytes to send for a message that's already serialized.
+    pub fn frame(&mut self, serialized: &[u8]) -> io::Result<Vec<u8>> {
+        let mut frame = vec![self.compression.tag()];
+        frame.extend(self.compression.compress(serialized));
+        if let Some(ref transport) = self.transport {
+            let mut encrypted = Vec::new();
+            let mut buf = vec![0; NOISE_MAX_MESSAGE];
+            for piece in frame.chunks(NOISE_MAX_MESSAGE - NOISE_TAG_SIZE) {
+                let len = transport.write_message(self.nonce, piece, &mut buf).map_err(noise_error)?;
+                self.nonce += 1;
+                encrypted.extend_from_slice(&buf[..len]);
+            }
+            frame = encrypted;
+        }
+
+        // size first, big endian
+        let mut bytes = vec![0; 8];
+        NetworkEndian::write_u64(&mut bytes, frame.len() as u64);
+        bytes.extend(frame);
+        Ok(bytes)
+    }
+}
+
+/// Collects bytes as they arrive and takes messages out of them once a
+/// whole frame is in, so it never has to wait on the other end.
+pub struct FrameReader {
+    buffer: Vec<u8>,
+    transport: Option<Arc<snow::StatelessTransportState>>,
+    nonce: u64,
+}
+
+impl FrameReader {
+    /// Reads what `source` has ready onto the end of the buffer. Reading
+    /// nothing means the other end hung up.
+    pub fn read_from<R: Read>(&mut self, source: &mut R) -> io::Result<usize> {
+        let start = self.buffer.len();
+        self.buffer.resize(start + READ_SIZE, 0);
+        let result = source.read(&mut self.buffer[start..]);
+        self.buffer.truncate(start + *result.as_ref().unwrap_or(&0));
+        result
+    }
+
+    /// How many bytes have arrived but not been taken out as messages.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// The next message, if all of it has arrived.
+    pub fn next_message<M>(&mut self) -> io::Result<Option<M>> where M: DeserializeOwned {
+        if self.buffer.len() < 8 {
+            return Ok(None);
+        }
+        let size = NetworkEndian::read_u64(&self.buffer[..8]);
+        if size > MAX_FRAME_SIZE {
+            return Err(invalid_data("Frame is too big"));
+        }
+        if ((self.buffer.len() - 8) as u64) < size {
+            return Ok(None);
+        }
+        let mut frame: Vec<u8> = self.buffer.drain(..8 + size as usize).skip(8).collect();
+
+        if let Some(ref transport) = self.transport {
+            let mut decrypted = Vec::with_capacity(frame.len());
+            let mut buf = vec![0; NOISE_MAX_MESSAGE];
+            for piece in frame.chunks(NOISE_MAX_MESSAGE) {
+                let len = transport.read_message(self.nonce, piece, &mut buf).map_err(noise_error)?;
+                self.nonce += 1;
+                decrypted.extend_from_slice(&buf[..len]);
+            }
+            frame = decrypted;
+        }
+
+        let (tag, compressed) = frame.split_first().ok_or(invalid_data("Empty message"))?;
+        let serialized = Compression::from_tag(*tag)?.decompress(compressed, MAX_MESSAGE_SIZE)?;
+        deserialize(&serialized).map(Some).map_err(|e| invalid_data(&e.to_string()))
+    }
+}
+
+/// Sends and receives messages over a blocking stream, one at a time.
+///
+/// Connections between nodes are secured with a Noise XX handshake, after
+/// which each frame is encrypted. Each side's static Noise key is the
+/// exchange key of its node address, so a finished handshake proves who's
+/// at the other end.
 pub struct Connection {
-    next_message_size: Option<u64>,
     stream: TcpStream,
+    reader: FrameReader,
+    writer: FrameWriter,
+    peer_id: Option<Address>,
+}
+
+/// A handshake payload: our address, and a signature with its key over the
+/// handshake so far. The Noise static key alone can't tell an address from
+/// the one with its sign bit flipped, since both map to the same exchange
+/// key, but only the real one checks the signature.
+fn identity(keypair: &Keypair, transcript: &[u8]) -> Vec<u8> {
+    let mut payload = keypair.address().0.to_vec();
+    payload.extend_from_slice(&keypair.sign(transcript).0);
+    payload
+}
+
+/// The address in a handshake payload made by `identity` over `transcript`.
+fn check_identity(payload: &[u8], transcript: &[u8]) -> io::Result<Address> {
+    if payload.len() != 32 + 64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Peer didn't send its address"));
+    }
+    let mut peer_id = Address([0; 32]);
+    peer_id.0.copy_from_slice(&payload[..32]);
+    let mut signature = Signature([0; 64]);
+    signature.0.copy_from_slice(&payload[32..]);
+    if !verify(transcript, &peer_id, &signature) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Peer didn't sign for its address"));
+    }
+    Ok(peer_id)
+}
+
+/// Handshake messages are sent with a two byte length.
+fn write_handshake(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    let mut size_buf = [0; 2];
+    NetworkEndian::write_u16(&mut size_buf, message.len() as u16);
+    stream.write_all(&size_buf)?;
+    stream.write_all(message)
+}
+
+fn read_handshake(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut size_buf = [0; 2];
+    stream.read_exact(&mut size_buf)?;
+    let mut message = vec![0; NetworkEndian::read_u16(&size_buf) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
 }
 
 impl Connection {
+    /// An unencrypted connection, as used with the nameserver.
     pub fn new(stream: TcpStream) -> Connection {
         Connection{
-            next_message_size: None,
             stream: stream,
+            reader: FrameReader { buffer: Vec::new(), transport: None, nonce: 0 },
+            writer: FrameWriter { compression: Compression::None, transport: None, nonce: 0 },
+            peer_id: None,
+        }
+    }
+
+    /// Runs the Noise XX handshake over `stream` as `keypair`. The side that
+    /// connected is the initiator. Each side sends its address in its
+    /// handshake payload, which only counts if the static key it used
+    /// matches that address and it's signed for, as in `identity`. A read
+    /// timeout already set on `stream` holds if it's shorter than ours.
+    pub fn handshake(mut stream: TcpStream, keypair: &Keypair, initiator: bool) -> io::Result<Connection> {
+        let timeout = stream.read_timeout()?;
+        stream.set_read_timeout(Some(timeout.map_or(HANDSHAKE_TIMEOUT, |timeout| timeout.min(HANDSHAKE_TIMEOUT))))?;
+
+        let secret = keypair.exchange_secret();
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&secret);
+        let mut noise = if initiator { builder.build_initiator() } else { builder.build_responder() }
+            .map_err(noise_error)?;
+        let mut buf = vec![0; NOISE_MAX_MESSAGE];
+        let mut payload = vec![0; NOISE_MAX_MESSAGE];
+
+        // -> e; <- e, ee, s, es; -> s, se
+        let peer_id = if initiator {
+            let len = noise.write_message(&[], &mut buf).map_err(noise_error)?;
+            write_handshake(&mut stream, &buf[..len])?;
+            let transcript = noise.get_handshake_hash().to_vec();
+            let len = noise.read_message(&read_handshake(&mut stream)?, &mut payload).map_err(noise_error)?;
+            let peer_id = check_identity(&payload[..len], &transcript)?;
+            let ours = identity(keypair, noise.get_handshake_hash());
+            let len = noise.write_message(&ours, &mut buf).map_err(noise_error)?;
+            write_handshake(&mut stream, &buf[..len])?;
+            peer_id
+        } else {
+            noise.read_message(&read_handshake(&mut stream)?, &mut payload).map_err(noise_error)?;
+            let ours = identity(keypair, noise.get_handshake_hash());
+            let len = noise.write_message(&ours, &mut buf).map_err(noise_error)?;
+            write_handshake(&mut stream, &buf[..len])?;
+            let transcript = noise.get_handshake_hash().to_vec();
+            let len = noise.read_message(&read_handshake(&mut stream)?, &mut payload).map_err(noise_error)?;
+            check_identity(&payload[..len], &transcript)?
+        };
+
+        if noise.get_remote_static() != Some(&peer_id.exchange_key()[..]) {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Peer's key doesn't match its address"));
         }
+
+        stream.set_read_timeout(timeout)?;
+        let transport = Arc::new(noise.into_stateless_transport_mode().map_err(noise_error)?);
+        let mut connection = Connection::new(stream);
+        connection.reader.transport = Some(transport.clone());
+        connection.writer.transport = Some(transport);
+        connection.peer_id = Some(peer_id);
+        Ok(connection)
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         self.stream.peer_addr()
     }
 
-    pub fn write_message<M>(&mut self, msg: &M) -> io::Result<()> where M: Serialize  {
-        let serialized = serialize(msg, Infinite).unwrap();
+    /// The node address of the peer, if the connection is secured.
+    pub fn peer_id(&self) -> Option<Address> {
+        self.peer_id
+    }
 
-        // first write size big endian
-        let mut size_buf = [0; 8];
-        NetworkEndian::write_u64(&mut size_buf, serialized.len() as u64);
-        self.stream.write(&size_buf)?;
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.writer.set_compression(compression);
+    }
 
-        // now write the bytes
-        self.stream.write(&serialized)?;
+    /// Splits the connection, for reading and writing it separately.
+    pub fn into_parts(self) -> (TcpStream, FrameReader, FrameWriter) {
+        (self.stream, self.reader, self.writer)
+    }
 
-        Ok(())
+    pub fn write_message<M>(&mut self, msg: &M) -> io::Result<()> where M: Serialize  {
+        let frame = self.writer.frame(&serialize(msg, Infinite).unwrap())?;
+        self.stream.write_all(&frame)
     }
 
     pub fn read_message<M>(&mut self) -> io::Result<Option<M>> where M: DeserializeOwned {
-        // read the size of the payload
-        let mut size_buf = [0; 8];
-        let bytes_read = self.stream.read(&mut size_buf)?;
-
-        if bytes_read == 0 {
-            Ok(None)
-        } else if bytes_read != 8 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid message length"));
-        } else {
-            let size = NetworkEndian::read_u64(&size_buf);
+        loop {
+            if let Some(message) = self.reader.next_message()? {
+                return Ok(Some(message));
+            }
+            if self.reader.read_from(&mut self.stream)? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
 
-            let mut msg_buf = Vec::with_capacity(size as usize);
-            msg_buf.resize(size as usize, 0);
-            self.stream.read_exact(&mut msg_buf)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let message: M = deserialize(&msg_buf).unwrap();
-            return Ok(Some(message));
-        }
+    #[test]
+    fn identity_checks_out() {
+        let keypair = Keypair::generate().unwrap();
+        let payload = identity(&keypair, b"transcript");
+        assert_eq!(check_identity(&payload, b"transcript").unwrap(), keypair.address());
+        assert!(check_identity(&payload, b"another transcript").is_err());
+        assert!(check_identity(&payload[..32], b"transcript").is_err());
+    }
+
+    #[test]
+    fn flipped_sign_is_rejected() {
+        let keypair = Keypair::generate().unwrap();
+        let mut payload = identity(&keypair, b"transcript");
+        payload[31] ^= 0x80;
+        let mut flipped = Address([0; 32]);
+        flipped.0.copy_from_slice(&payload[..32]);
+        assert_eq!(flipped.exchange_key(), keypair.address().exchange_key());
+        assert!(check_identity(&payload, b"transcript").is_err());
     }
 }
@@ -0,0 +1,345 @@
+//! WebAssembly contracts. A contract is a module deployed by a transaction,
+//! with its own key-value storage that only its exported functions can
+//! change. Execution is made deterministic by running in an interpreter with
+//! floating point disabled, metering every instruction with fuel, and only
+//! letting the module see the block it runs in and who called it.
+
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::BTreeMap;
+use wasmi;
+use wasmi::{Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use block::{Block, Hash32Byte};
+use keys::Address;
+
+
+/// Most fuel a single invocation may be given.
+pub const MAX_FUEL: u64 = 10_000_000;
+
+/// How much fuel a unit of fee buys.
+pub const FUEL_PER_FEE: u64 = 1_000;
+
+/// The least fee an invocation given `fuel` can pay. It's charged whether
+/// the fuel gets used or not, so running contracts is never free.
+pub fn fuel_fee(fuel: u64) -> u64 {
+    fuel.div_ceil(FUEL_PER_FEE)
+}
+
+pub const MAX_KEY_SIZE: usize = 64;
+pub const MAX_VALUE_SIZE: usize = 1024;
+
+/// Most memory a contract may use while running.
+const MAX_MEMORY: usize = 1024 * 1024;
+
+/// Extra fuel charged for storage access, on top of a unit per byte.
+const STORAGE_READ_FUEL: u64 = 100;
+const STORAGE_WRITE_FUEL: u64 = 1_000;
+
+/// The block a contract is running in, which is all it gets to know about
+/// the outside world.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockContext {
+    pub height: u64,
+    /// In ns, like `Block::timestamp`.
+    pub timestamp: u64,
+}
+
+impl BlockContext {
+    pub fn of(block: &Block) -> BlockContext {
+        BlockContext { height: block.block_num, timestamp: block.timestamp }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Contract {
+    pub creator: Address,
+    pub code: Vec<u8>,
+    pub storage: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// The address of the contract deployed by `creator`'s transaction with
+/// `nonce`.
+pub fn contract_address(creator: &Address, nonce: u64) -> Address {
+    let mut sha = Sha256::new();
+    sha.input(b"contract");
+    sha.input(&serialize(&(creator, nonce), Infinite).unwrap());
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    Address(output)
+}
+
+fn engine() -> Engine {
+    let mut config = Config::default();
+    config.consume_fuel(true).floats(false);
+    Engine::new(&config)
+}
+
+/// What the host functions can reach while a contract runs.
+struct Host {
+    storage: BTreeMap<Vec<u8>, Vec<u8>>,
+    caller: Address,
+    input: Vec<u8>,
+    context: BlockContext,
+    limits: StoreLimits,
+}
+
+fn memory(caller: &Caller<Host>) -> Result<Memory, wasmi::Error> {
+    caller.get_export("memory").and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("Contract doesn't export its memory"))
+}
+
+fn read_bytes(caller: &Caller<Host>, ptr: i32, len: i32, max: usize) -> Result<Vec<u8>, wasmi::Error> {
+    if len < 0 || len as usize > max {
+        return Err(wasmi::Error::new("Too many bytes"));
+    }
+    let mut bytes = vec![0; len as usize];
+    memory(caller)?.read(caller, ptr as u32 as usize, &mut bytes).map_err(wasmi::Error::from)?;
+    Ok(bytes)
+}
+
+fn write_bytes(caller: &mut Caller<Host>, ptr: i32, bytes: &[u8]) -> Result<(), wasmi::Error> {
+    memory(caller)?.write(caller, ptr as u32 as usize, bytes).map_err(wasmi::Error::from)
+}
+
+fn charge(caller: &mut Caller<Host>, fuel: u64) -> Result<(), wasmi::Error> {
+    let remaining = caller.get_fuel()?;
+    if fuel > remaining {
+        return Err(wasmi::Error::from(wasmi::core::TrapCode::OutOfFuel));
+    }
+    caller.set_fuel(remaining - fuel)?;
+    Ok(())
+}
+
+fn link(linker: &mut Linker<Host>) -> Result<(), wasmi::Error> {
+    // storage_read(key, key_len, value, value_capacity) -> value_len or -1
+    linker.func_wrap("env", "storage_read", |mut caller: Caller<Host>, key: i32, key_len: i32, value: i32, capacity: i32| {
+        let key = read_bytes(&caller, key, key_len, MAX_KEY_SIZE)?;
+        let found = caller.data().storage.get(&key).cloned();
+        match found {
+            Some(found) => {
+                charge(&mut caller, STORAGE_READ_FUEL + found.len() as u64)?;
+                let copied = found.len().min(capacity.max(0) as usize);
+                write_bytes(&mut caller, value, &found[..copied])?;
+                Ok(found.len() as i32)
+            },
+            None => {
+                charge(&mut caller, STORAGE_READ_FUEL)?;
+                Ok(-1)
+            },
+        }
+    })?;
+    // storage_write(key, key_len, value, value_len)
+    linker.func_wrap("env", "storage_write", |mut caller: Caller<Host>, key: i32, key_len: i32, value: i32, value_len: i32| {
+        let key = read_bytes(&caller, key, key_len, MAX_KEY_SIZE)?;
+        let value = read_bytes(&caller, value, value_len, MAX_VALUE_SIZE)?;
+        charge(&mut caller, STORAGE_WRITE_FUEL + (key.len() + value.len()) as u64)?;
+        caller.data_mut().storage.insert(key, value);
+        Ok(())
+    })?;
+    // storage_remove(key, key_len)
+    linker.func_wrap("env", "storage_remove", |mut caller: Caller<Host>, key: i32, key_len: i32| {
+        let key = read_bytes(&caller, key, key_len, MAX_KEY_SIZE)?;
+        charge(&mut caller, STORAGE_WRITE_FUEL)?;
+        caller.data_mut().storage.remove(&key);
+        Ok(())
+    })?;
+    linker.func_wrap("env", "input_len", |caller: Caller<Host>| caller.data().input.len() as i32)?;
+    // input_read(buffer), copying the whole input
+    linker.func_wrap("env", "input_read", |mut caller: Caller<Host>, buffer: i32| {
+        let input = caller.data().input.clone();
+        charge(&mut caller, input.len() as u64)?;
+        write_bytes(&mut caller, buffer, &input)
+    })?;
+    // caller(buffer), copying the caller's 32 byte address
+    linker.func_wrap("env", "caller", |mut caller: Caller<Host>, buffer: i32| {
+        let address = caller.data().caller;
+        write_bytes(&mut caller, buffer, &address.0)
+    })?;
+    linker.func_wrap("env", "block_height", |caller: Caller<Host>| caller.data().context.height as i64)?;
+    linker.func_wrap("env", "block_timestamp", |caller: Caller<Host>| caller.data().context.timestamp as i64)?;
+    Ok(())
+}
+
+/// Every deployed contract, by address.
+#[derive(Clone, Default)]
+pub struct Contracts {
+    contracts: BTreeMap<Address, Contract>,
+}
+
+impl Contracts {
+    pub fn get(&self, address: &Address) -> Option<&Contract> {
+        self.contracts.get(address)
+    }
+
+    /// Puts a contract back the way it was, for undoing blocks.
+    pub fn restore(&mut self, address: Address, contract: Option<Contract>) {
+        match contract {
+            Some(contract) => self.contracts.insert(address, contract),
+            None => self.contracts.remove(&address),
+        };
+    }
+
+    /// Checks `code` is a module we can run and stores it at `address`.
+    pub fn deploy(&mut self, address: Address, creator: Address, code: Vec<u8>) -> Result<(), String> {
+        if self.contracts.contains_key(&address) {
+            return Err(format!("There's already a contract at {}", address));
+        }
+        let module = Module::new(&engine(), &code).map_err(|e| format!("Invalid contract: {}", e))?;
+        if module.get_export("memory").and_then(|export| export.memory().cloned()).is_none() {
+            return Err("Contract doesn't export its memory".to_string());
+        }
+        self.contracts.insert(address, Contract { creator, code, storage: BTreeMap::new() });
+        Ok(())
+    }
+
+    /// Calls `function` on a contract with at most `fuel`, returning how
+    /// much was used. The contract's storage only changes if the call
+    /// succeeds.
+    pub fn invoke(&mut self, address: &Address, caller: Address, function: &str, input: &[u8], fuel: u64,
+                  context: BlockContext) -> Result<u64, String> {
+        let contract = self.contracts.get_mut(address).ok_or(format!("No contract at {}", address))?;
+
+        let engine = engine();
+        let module = Module::new(&engine, &contract.code).map_err(|e| e.to_string())?;
+        let host = Host {
+            storage: contract.storage.clone(),
+            caller,
+            input: input.to_vec(),
+            context,
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+        };
+        let mut store = Store::new(&engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(fuel.min(MAX_FUEL)).map_err(|e| e.to_string())?;
+
+        let mut linker = Linker::new(&engine);
+        link(&mut linker).map_err(|e| e.to_string())?;
+        let run = |store: &mut Store<Host>| -> Result<(), wasmi::Error> {
+            let instance = linker.instantiate(&mut *store, &module)?.start(&mut *store)?;
+            instance.get_typed_func::<(), ()>(&*store, function)?.call(&mut *store, ())
+        };
+        run(&mut store).map_err(|e| format!("Contract call failed: {}", e))?;
+
+        let used = fuel.min(MAX_FUEL) - store.get_fuel().map_err(|e| e.to_string())?;
+        contract.storage = store.into_data().storage;
+        Ok(used)
+    }
+
+    /// A hash committing to every contract's code and storage.
+    pub fn state_root(&self) -> Hash32Byte {
+        let mut sha = Sha256::new();
+        sha.input(&serialize(&self.contracts, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+    use state::{AccountLedger, Ledger};
+    use transaction::{BlockBody, Transaction, TransactionKind, pack_block};
+
+    /// (module
+    ///   (import "env" "storage_write" (func (param i32 i32 i32 i32)))
+    ///   (memory (export "memory") 1)
+    ///   (data (i32.const 0) "kv")
+    ///   (func (export "set") (call 0 (i32.const 0) (i32.const 1) (i32.const 1) (i32.const 1)))
+    ///   (func (export "fail") unreachable))
+    const CODE: &[u8] = &[
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        // types
+        0x01, 0x0b, 0x02, 0x60, 0x04, 0x7f, 0x7f, 0x7f, 0x7f, 0x00, 0x60, 0x00, 0x00,
+        // imports
+        0x02, 0x15, 0x01, 0x03, b'e', b'n', b'v', 0x0d, b's', b't', b'o', b'r', b'a', b'g', b'e', b'_',
+        b'w', b'r', b'i', b't', b'e', 0x00, 0x00,
+        // functions
+        0x03, 0x03, 0x02, 0x01, 0x01,
+        // memory
+        0x05, 0x03, 0x01, 0x00, 0x01,
+        // exports
+        0x07, 0x17, 0x03, 0x06, b'm', b'e', b'm', b'o', b'r', b'y', 0x02, 0x00, 0x03, b's', b'e', b't', 0x00, 0x01,
+        0x04, b'f', b'a', b'i', b'l', 0x00, 0x02,
+        // code
+        0x0a, 0x12, 0x02, 0x0c, 0x00, 0x41, 0x00, 0x41, 0x01, 0x41, 0x01, 0x41, 0x01, 0x10, 0x00, 0x0b,
+        0x03, 0x00, 0x00, 0x0b,
+        // data
+        0x0b, 0x08, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x02, b'k', b'v',
+    ];
+
+    fn stored(contracts: &Contracts, address: &Address) -> Option<Vec<u8>> {
+        contracts.get(address).unwrap().storage.get(&b"k"[..]).cloned()
+    }
+
+    #[test]
+    fn only_runnable_modules_with_memory_deploy() {
+        let mut contracts = Contracts::default();
+        assert!(contracts.deploy(Address([1; 32]), Address([0; 32]), b"not wasm".to_vec()).is_err());
+        // the smallest module there is, which has no memory
+        assert!(contracts.deploy(Address([1; 32]), Address([0; 32]), CODE[..8].to_vec()).is_err());
+        contracts.deploy(Address([1; 32]), Address([0; 32]), CODE.to_vec()).unwrap();
+        assert!(contracts.deploy(Address([1; 32]), Address([0; 32]), CODE.to_vec()).is_err());
+    }
+
+    #[test]
+    fn storage_only_changes_when_a_call_succeeds() {
+        let address = Address([1; 32]);
+        let mut contracts = Contracts::default();
+        contracts.deploy(address, Address([0; 32]), CODE.to_vec()).unwrap();
+        let root = contracts.state_root();
+
+        assert!(contracts.invoke(&address, Address([0; 32]), "fail", &[], MAX_FUEL, BlockContext::default()).is_err());
+        assert!(contracts.invoke(&address, Address([0; 32]), "set", &[], STORAGE_WRITE_FUEL, BlockContext::default()).is_err());
+        assert!(contracts.invoke(&address, Address([0; 32]), "missing", &[], MAX_FUEL, BlockContext::default()).is_err());
+        assert_eq!(stored(&contracts, &address), None);
+        assert_eq!(contracts.state_root(), root);
+
+        let used = contracts.invoke(&address, Address([0; 32]), "set", &[], MAX_FUEL, BlockContext::default()).unwrap();
+        assert!(used > STORAGE_WRITE_FUEL && used < MAX_FUEL);
+        assert_eq!(stored(&contracts, &address), Some(b"v".to_vec()));
+        assert_ne!(contracts.state_root(), root);
+    }
+
+    #[test]
+    fn undoing_a_block_undeploys_and_unwrites() {
+        let keypair = Keypair::generate().unwrap();
+        let mut ledger = AccountLedger::new(&[(keypair.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+        let address = contract_address(&keypair.address(), 0);
+        let fuel = 10 * FUEL_PER_FEE;
+        let invoke = |nonce, function: &str| Transaction::new(&keypair, nonce, fuel_fee(fuel), TransactionKind::Invoke {
+            contract: address, function: function.to_string(), input: Vec::new(), fuel,
+        });
+        let block_with = |previous: &Block, ledger: &dyn Ledger, transactions: Vec<Transaction>| {
+            let context = BlockContext { height: previous.block_num + 1, timestamp: previous.timestamp + 1 };
+            let mut draft = ledger.draft(context);
+            for transaction in &transactions {
+                draft.apply_transaction(transaction).unwrap();
+            }
+            let body = BlockBody { reward: None, transactions };
+            Block::new(previous, context.timestamp, draft.state_root(), body.transactions_root(), pack_block(&body).unwrap())
+        };
+
+        let deploy = Transaction::new(&keypair, 0, 0, TransactionKind::Deploy(CODE.to_vec()));
+        let first = block_with(&genesis, &ledger, vec![deploy]);
+        ledger.apply_block(&first).unwrap();
+        // a failed call still pays for its fuel
+        let second = block_with(&first, &ledger, vec![invoke(1, "fail"), invoke(2, "set")]);
+        ledger.apply_block(&second).unwrap();
+        assert_eq!(ledger.contract(&address).unwrap().storage.get(&b"k"[..]), Some(&b"v".to_vec()));
+        assert_eq!(ledger.balance(&keypair.address()), 1000 - 2 * fuel_fee(fuel));
+
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), first.state_root);
+        assert!(ledger.contract(&address).unwrap().storage.is_empty());
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), genesis.state_root);
+        assert_eq!(ledger.contract(&address), None);
+    }
+}
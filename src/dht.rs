@@ -0,0 +1,352 @@
+//! Running Kademlia lookups over the network. Queries go to peers we're
+//! already connected to if they're among the contacts being asked, and
+//! otherwise over a connection of their own, opened by a small pool of
+//! workers and closed once the answer's in, so looking things up never
+//! leaves us with peers we didn't set out to keep.
+
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use block::Block;
+use connection::Connection;
+use kademlia::{Contact, Lookup, RoutingTable, Target, K};
+use keys::{Address, Keypair};
+use message::ClientMessage;
+use network::Network;
+use reputation::Reputation;
+
+
+/// How long to wait on a lookup before settling for what it's found so far.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a contact we're not connected to gets to accept a connection
+/// and answer.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many contacts we can be connecting to for lookups at once.
+pub const QUERY_WORKERS: usize = 8;
+
+/// What a contact said to a query.
+enum Answer {
+    Nodes(Vec<Contact>),
+    Value(Box<Block>),
+}
+
+/// A query for a contact we aren't connected to.
+struct Query {
+    contact: Contact,
+    target: Target,
+}
+
+struct Shared {
+    routing: Mutex<RoutingTable>,
+    /// Lookups under way, with who to hand each to once it's over.
+    lookups: Mutex<HashMap<Target, (Lookup, Sender<Lookup>)>>,
+    network: Network,
+    keypair: Keypair,
+    allowed_keys: Arc<Vec<Address>>,
+    reputation: Arc<Mutex<Reputation>>,
+    queries: Mutex<Sender<Query>>,
+}
+
+/// A handle on our routing table and the lookups under way.
+#[derive(Clone)]
+pub struct Dht {
+    shared: Arc<Shared>,
+}
+
+impl Dht {
+    /// Starts the query workers. Only contacts with `allowed_keys`, if
+    /// there are any, and that `reputation` allows, are connected to.
+    pub fn start(network: Network, keypair: Keypair, allowed_keys: Arc<Vec<Address>>,
+                 reputation: Arc<Mutex<Reputation>>) -> Dht {
+        let (queries, query_receiver) = channel();
+        let dht = Dht {
+            shared: Arc::new(Shared {
+                routing: Mutex::new(RoutingTable::new(keypair.address())),
+                lookups: Mutex::new(HashMap::new()),
+                network,
+                keypair,
+                allowed_keys,
+                reputation,
+                queries: Mutex::new(queries),
+            }),
+        };
+        let query_receiver = Arc::new(Mutex::new(query_receiver));
+        for _ in 0..QUERY_WORKERS {
+            let dht = dht.clone();
+            let query_receiver = query_receiver.clone();
+            thread::spawn(move || dht.run_queries(&query_receiver));
+        }
+        dht
+    }
+
+    pub fn routing(&self) -> MutexGuard<'_, RoutingTable> {
+        self.shared.routing.lock().unwrap()
+    }
+
+    /// Looks up `target` and waits for the lookup to finish, or for
+    /// `LOOKUP_TIMEOUT`, whichever's first.
+    pub fn lookup(&self, target: Target) -> Result<Lookup, String> {
+        let contacts = self.routing().closest(&target, K);
+        let (done, result) = channel();
+        {
+            let mut lookups = self.shared.lookups.lock().unwrap();
+            if lookups.contains_key(&target) {
+                return Err("Already looking that up".to_string());
+            }
+            lookups.insert(target, (Lookup::new(target, self.shared.keypair.address(), contacts), done));
+        }
+        self.advance(target);
+        match result.recv_timeout(LOOKUP_TIMEOUT) {
+            Ok(lookup) => Ok(lookup),
+            Err(_) => {
+                let lookup = self.shared.lookups.lock().unwrap().remove(&target);
+                match lookup {
+                    Some((lookup, _)) => Ok(lookup),
+                    // it finished just as we gave up
+                    None => result.recv().map_err(|e| e.to_string()),
+                }
+            },
+        }
+    }
+
+    /// Takes a peer's answer of the contacts it knows closest to `target`.
+    /// False if no lookup was waiting on it; answers to lookups that have
+    /// been given up on are dropped.
+    pub fn answered(&self, from: &Address, target: Target, contacts: Vec<Contact>) -> bool {
+        self.answer(from, target, |lookup| lookup.answered(from, contacts))
+    }
+
+    /// Takes a peer's answer of the block a lookup is after, which the
+    /// caller has checked. False if no lookup was waiting on it.
+    pub fn found(&self, from: &Address, block: Block) -> bool {
+        self.answer(from, Target::Value(block.hash), |lookup| lookup.found(from, block))
+    }
+
+    /// Gives up on `id` in every lookup waiting on it, say because it
+    /// disconnected.
+    pub fn failed(&self, id: &Address) {
+        let targets: Vec<Target> = self.shared.lookups.lock().unwrap().values_mut()
+            .filter(|&&mut (ref lookup, _)| lookup.is_waiting_on(id))
+            .map(|&mut (ref mut lookup, _)| {
+                lookup.failed(id);
+                lookup.target()
+            })
+            .collect();
+        for target in targets {
+            self.advance(target);
+        }
+    }
+
+    fn answer<F>(&self, from: &Address, target: Target, answer: F) -> bool where F: FnOnce(&mut Lookup) {
+        {
+            let mut lookups = self.shared.lookups.lock().unwrap();
+            match lookups.get_mut(&target) {
+                Some(&mut (ref mut lookup, _)) if lookup.is_waiting_on(from) => answer(lookup),
+                Some(_) => return false,
+                None => return true,
+            }
+        }
+        self.advance(target);
+        true
+    }
+
+    /// Sends the next queries of the lookup for `target`, or hands the
+    /// lookup over if it's done.
+    fn advance(&self, target: Target) {
+        let next = {
+            let mut lookups = self.shared.lookups.lock().unwrap();
+            match lookups.get_mut(&target) {
+                Some(&mut (ref mut lookup, _)) if !lookup.is_done() => lookup.next_queries(),
+                Some(_) => {
+                    let (lookup, done) = lookups.remove(&target).unwrap();
+                    let _ = done.send(lookup);
+                    return;
+                },
+                None => return,
+            }
+        };
+        for contact in next {
+            match self.shared.network.peers().into_iter().find(|peer| peer.id() == Some(contact.id)) {
+                Some(peer) => peer.send(&query_message(target)),
+                // the workers only stop with the node
+                None => { let _ = self.shared.queries.lock().unwrap().send(Query { contact, target }); },
+            }
+        }
+    }
+
+    /// Gives up on `id` in the lookup for `target` alone.
+    fn failed_in(&self, target: Target, id: &Address) {
+        let failed = match self.shared.lookups.lock().unwrap().get_mut(&target) {
+            Some(&mut (ref mut lookup, _)) if lookup.is_waiting_on(id) => {
+                lookup.failed(id);
+                true
+            },
+            _ => false,
+        };
+        if failed {
+            self.advance(target);
+        }
+    }
+
+    fn run_queries(&self, queries: &Mutex<Receiver<Query>>) {
+        loop {
+            let query = match queries.lock().unwrap().recv() {
+                Ok(query) => query,
+                Err(_) => return,
+            };
+            let Query { contact, target } = query;
+            match self.query(contact, target) {
+                Ok(answer) => {
+                    self.routing().insert(contact);
+                    match answer {
+                        Answer::Nodes(contacts) => self.answered(&contact.id, target, contacts),
+                        Answer::Value(block) => self.found(&contact.id, *block),
+                    };
+                },
+                Err(e) => {
+                    println!("Lookup query to {} failed: {}", contact.addr, e);
+                    self.routing().remove(&contact.id);
+                    self.failed_in(target, &contact.id);
+                },
+            }
+        }
+    }
+
+    /// Connects to `contact` just long enough to ask it about `target`.
+    fn query(&self, contact: Contact, target: Target) -> Result<Answer, String> {
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let stream = TcpStream::connect_timeout(&contact.addr, QUERY_TIMEOUT).map_err(|e| e.to_string())?;
+        stream.set_read_timeout(Some(QUERY_TIMEOUT)).map_err(|e| e.to_string())?;
+        let mut connection = Connection::handshake(stream, &self.shared.keypair, true).map_err(|e| e.to_string())?;
+        if connection.peer_id() != Some(contact.id) {
+            return Err("it isn't who it was said to be".to_string());
+        }
+        if !self.shared.allowed_keys.is_empty() && !self.shared.allowed_keys.contains(&contact.id) {
+            return Err("it isn't an allowed key".to_string());
+        }
+        if !self.shared.reputation.lock().unwrap().allows(contact.addr, Some(contact.id)) {
+            return Err("it's banned".to_string());
+        }
+        connection.write_message(&query_message(target)).map_err(|e| e.to_string())?;
+        // it treats us as any other peer, so there's its greeting to skip
+        while Instant::now() < deadline {
+            match connection.read_message::<ClientMessage>().map_err(|e| e.to_string())? {
+                Some(ClientMessage::Nodes(answered, contacts)) if answered == target => return Ok(Answer::Nodes(contacts)),
+                Some(ClientMessage::Value(block)) if Target::Value(block.hash) == target => {
+                    if !block.has_valid_hash() {
+                        return Err("it sent a block with a bad hash".to_string());
+                    }
+                    return Ok(Answer::Value(Box::new(block)));
+                },
+                Some(_) => {},
+                None => return Err("it hung up".to_string()),
+            }
+        }
+        Err("it didn't answer in time".to_string())
+    }
+}
+
+fn query_message(target: Target) -> ClientMessage {
+    match target {
+        Target::Node(id) => ClientMessage::FindNode(id),
+        Target::Value(hash) => ClientMessage::FindValue(hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::Hash32Byte;
+    use network::{Direction, Event, RateLimits};
+    use std::net::TcpListener;
+
+    const LIMITS: RateLimits = RateLimits { messages_per_second: 100.0, bytes_per_second: 1e6 };
+
+    fn dht() -> (Dht, Network, Receiver<Event>) {
+        let (network, events) = Network::start(LIMITS).unwrap();
+        let dht = Dht::start(network.clone(), Keypair::generate().unwrap(), Arc::new(Vec::new()),
+            Arc::new(Mutex::new(Reputation::new(60))));
+        (dht, network, events)
+    }
+
+    /// A DHT whose only contact is a peer it's connected to, with the
+    /// peer's end of the connection.
+    fn with_peer() -> (Dht, Network, Receiver<Event>, Contact, Connection) {
+        let (dht, network, events) = dht();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let keypair = Keypair::generate().unwrap();
+        let id = keypair.address();
+        let theirs = thread::spawn(move || Connection::handshake(listener.accept().unwrap().0, &keypair, false).unwrap());
+        let ours = Connection::handshake(TcpStream::connect(addr).unwrap(), &dht.shared.keypair, true).unwrap();
+        network.add(ours, Direction::Outbound).unwrap();
+        let contact = Contact { id, addr };
+        dht.routing().insert(contact);
+        (dht, network, events, contact, theirs.join().unwrap())
+    }
+
+    fn looking_up(dht: &Dht, target: Target) -> thread::JoinHandle<Result<Lookup, String>> {
+        let dht = dht.clone();
+        thread::spawn(move || dht.lookup(target))
+    }
+
+    #[test]
+    fn answers_only_count_from_who_was_asked() {
+        let (dht, _network, _events, contact, mut theirs) = with_peer();
+        let target = Target::Node(Address([7; 32]));
+        let lookup = looking_up(&dht, target);
+        match theirs.read_message::<ClientMessage>().unwrap() {
+            Some(ClientMessage::FindNode(id)) => assert_eq!(Target::Node(id), target),
+            other => panic!("Expected FindNode, got {:?}", other),
+        }
+        assert!(!dht.answered(&Address([9; 32]), target, Vec::new()));
+        assert!(dht.answered(&contact.id, target, Vec::new()));
+        let lookup = lookup.join().unwrap().unwrap();
+        assert_eq!(lookup.closest(), &[contact]);
+        // the lookup's over, so a late answer is dropped
+        assert!(dht.answered(&contact.id, target, Vec::new()));
+    }
+
+    #[test]
+    fn values_end_the_lookup() {
+        let (dht, _network, _events, contact, mut theirs) = with_peer();
+        let block = Block::genesis(Hash32Byte([3; 32]));
+        let lookup = looking_up(&dht, Target::Value(block.hash));
+        match theirs.read_message::<ClientMessage>().unwrap() {
+            Some(ClientMessage::FindValue(hash)) => assert_eq!(hash, block.hash),
+            other => panic!("Expected FindValue, got {:?}", other),
+        }
+        assert!(dht.found(&contact.id, block.clone()));
+        assert_eq!(lookup.join().unwrap().unwrap().value(), Some(&(contact, block)));
+    }
+
+    #[test]
+    fn contacts_that_go_away_are_given_up_on() {
+        let (dht, _network, _events, contact, mut theirs) = with_peer();
+        let target = Target::Node(Address([7; 32]));
+        let lookup = looking_up(&dht, target);
+        theirs.read_message::<ClientMessage>().unwrap();
+        dht.failed(&contact.id);
+        assert!(lookup.join().unwrap().unwrap().closest().is_empty());
+    }
+
+    #[test]
+    fn contacts_that_dont_answer_time_out() {
+        let (dht, _network, _events) = dht();
+        // it takes the connection but never says anything
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let contact = Contact { id: Address([1; 32]), addr: listener.local_addr().unwrap() };
+        dht.routing().insert(contact);
+        let start = Instant::now();
+        let lookup = dht.lookup(Target::Node(Address([7; 32]))).unwrap();
+        assert!(start.elapsed() >= QUERY_TIMEOUT && start.elapsed() < LOOKUP_TIMEOUT);
+        assert!(lookup.closest().is_empty());
+        assert_eq!(dht.routing().get(&contact.id), None);
+    }
+}
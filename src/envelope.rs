@@ -0,0 +1,189 @@
+//! Encrypted payloads. An envelope's contents are encrypted with
+//! ChaCha20-Poly1305 under a random key, and that key is wrapped for each
+//! recipient using X25519 with a throwaway key, so only the recipients can
+//! read what's stored on the chain. Recipients are plain addresses, using
+//! `Address::exchange_key`.
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::curve25519::{curve25519, curve25519_base};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rand::{OsRng, Rng};
+
+use block::Block;
+use keys::{Address, Keypair};
+use transaction::{TransactionKind, block_transactions};
+
+
+/// Most recipients an envelope can have.
+pub const MAX_RECIPIENTS: usize = 8;
+
+/// A key ChaCha20-Poly1305 is only used with once, so the nonce can be
+/// fixed.
+const NONCE: [u8; 8] = [0; 8];
+
+/// The key wrapping the content key for one recipient.
+fn wrapping_key(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> [u8; 32] {
+    let mut sha = Sha256::new();
+    sha.input(b"envelope");
+    sha.input(shared);
+    sha.input(ephemeral);
+    sha.input(recipient);
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    output
+}
+
+fn seal_with(key: &[u8; 32], plaintext: &[u8]) -> (Vec<u8>, [u8; 16]) {
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = [0; 16];
+    ChaCha20Poly1305::new(key, &NONCE, &[]).encrypt(plaintext, &mut ciphertext, &mut tag);
+    (ciphertext, tag)
+}
+
+fn open_with(key: &[u8; 32], ciphertext: &[u8], tag: &[u8; 16]) -> Option<Vec<u8>> {
+    let mut plaintext = vec![0; ciphertext.len()];
+    if ChaCha20Poly1305::new(key, &NONCE, &[]).decrypt(ciphertext, &mut plaintext, tag) {
+        Some(plaintext)
+    } else {
+        None
+    }
+}
+
+/// The content key, encrypted for one recipient. Which recipient isn't
+/// recorded; they find theirs by trying each one.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub key: [u8; 32],
+    pub tag: [u8; 16],
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Public half of the throwaway X25519 key.
+    pub ephemeral: [u8; 32],
+    pub recipients: Vec<WrappedKey>,
+    pub ciphertext: Vec<u8>,
+    pub tag: [u8; 16],
+}
+
+impl Envelope {
+    /// Encrypts `payload` so any of `recipients` can read it. They have to
+    /// be single-key addresses: multisig and script accounts have no key to
+    /// encrypt to.
+    pub fn seal(payload: &[u8], recipients: &[Address]) -> Result<Envelope, String> {
+        if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+            return Err(format!("Envelopes need between 1 and {} recipients", MAX_RECIPIENTS));
+        }
+        if let Some(recipient) = recipients.iter().find(|recipient| !recipient.is_key()) {
+            return Err(format!("{} is a multisig or script address, which can't be encrypted to", recipient));
+        }
+        let mut rng = OsRng::new().map_err(|e| e.to_string())?;
+        let mut content_key = [0; 32];
+        rng.fill_bytes(&mut content_key);
+        let mut ephemeral_secret = [0; 32];
+        rng.fill_bytes(&mut ephemeral_secret);
+        let ephemeral = curve25519_base(&ephemeral_secret);
+
+        let recipients = recipients.iter().map(|recipient| {
+            let public = recipient.exchange_key();
+            let shared = curve25519(&ephemeral_secret, &public);
+            let (wrapped, tag) = seal_with(&wrapping_key(&shared, &ephemeral, &public), &content_key);
+            let mut key = [0; 32];
+            key.copy_from_slice(&wrapped);
+            WrappedKey { key, tag }
+        }).collect();
+        let (ciphertext, tag) = seal_with(&content_key, payload);
+        Ok(Envelope { ephemeral, recipients, ciphertext, tag })
+    }
+
+    /// Decrypts the payload, if it was sealed for `keypair`.
+    pub fn open(&self, keypair: &Keypair) -> Option<Vec<u8>> {
+        let public = keypair.address().exchange_key();
+        let shared = curve25519(&keypair.exchange_secret(), &self.ephemeral);
+        if shared == [0; 32] {
+            // a malicious ephemeral key of low order
+            return None;
+        }
+        let wrapping_key = wrapping_key(&shared, &self.ephemeral, &public);
+        self.recipients.iter()
+            .filter_map(|wrapped| open_with(&wrapping_key, &wrapped.key, &wrapped.tag))
+            .next()
+            .and_then(|content_key| {
+                let mut key = [0; 32];
+                key.copy_from_slice(&content_key);
+                open_with(&key, &self.ciphertext, &self.tag)
+            })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.recipients.is_empty() || self.recipients.len() > MAX_RECIPIENTS {
+            return Err(format!("Envelopes need between 1 and {} recipients", MAX_RECIPIENTS));
+        }
+        Ok(())
+    }
+}
+
+/// Every payload in `chain` that `keypair` can decrypt, with the number of
+/// the block it's in.
+pub fn addressed_to(chain: &[Block], keypair: &Keypair) -> Vec<(u64, Vec<u8>)> {
+    chain.iter().flat_map(|block| {
+        block_transactions(block).into_iter().filter_map(move |transaction| match transaction.kind {
+            TransactionKind::Encrypted(envelope) => envelope.open(keypair).map(|payload| (block.block_num, payload)),
+            _ => None,
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::MultisigPolicy;
+    use script::{Op, Script};
+
+    #[test]
+    fn recipients_can_open() {
+        let alice = Keypair::from_seed(&[1; 32]);
+        let bob = Keypair::from_seed(&[2; 32]);
+        let envelope = Envelope::seal(b"for your eyes only", &[alice.address(), bob.address()]).unwrap();
+        assert!(envelope.validate().is_ok());
+        assert_eq!(envelope.open(&alice).unwrap(), b"for your eyes only");
+        assert_eq!(envelope.open(&bob).unwrap(), b"for your eyes only");
+    }
+
+    #[test]
+    fn others_cannot_open() {
+        let alice = Keypair::from_seed(&[1; 32]);
+        let eve = Keypair::from_seed(&[3; 32]);
+        let envelope = Envelope::seal(b"secret", &[alice.address()]).unwrap();
+        assert_eq!(envelope.open(&eve), None);
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let alice = Keypair::from_seed(&[1; 32]);
+        let mut envelope = Envelope::seal(b"secret", &[alice.address()]).unwrap();
+        envelope.ciphertext[0] ^= 1;
+        assert_eq!(envelope.open(&alice), None);
+    }
+
+    #[test]
+    fn rejects_keyless_recipients() {
+        let keys: Vec<Address> = (1..4).map(|i| Keypair::from_seed(&[i; 32]).address()).collect();
+        for threshold in 1..4 {
+            let multisig = MultisigPolicy::new(threshold, keys.clone()).unwrap().address();
+            assert!(Envelope::seal(b"secret", &[keys[0], multisig]).is_err());
+        }
+        let script = Script(vec![Op::Push(vec![1])]).address();
+        assert!(Envelope::seal(b"secret", &[script]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_recipient_counts() {
+        assert!(Envelope::seal(b"secret", &[]).is_err());
+        let address = Keypair::from_seed(&[1; 32]).address();
+        assert!(Envelope::seal(b"secret", &vec![address; MAX_RECIPIENTS + 1]).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! Relaying new blocks. A node that accepts a new block announces its hash
+//! to a few random peers with `Inv`, and peers that haven't seen it yet ask
+//! for it with `GetData`. Remembering which hashes have been seen stops
+//! blocks echoing back and forth. New transactions are sent on to every
+//! peer, once each.
+
+use rand;
+use rand::Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use block::{Block, Hash32Byte};
+
+
+/// How many peers a block is announced to, unless the node says otherwise.
+pub const DEFAULT_FANOUT: usize = 8;
+
+/// Most hashes an `Inv` or `GetData` is taken to carry; any after that
+/// are ignored.
+pub const MAX_INVENTORY: usize = 500;
+
+/// How long to wait for a block we've asked a peer for before asking
+/// whoever announces it next.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many hashes each cache remembers.
+const CACHE_CAPACITY: usize = 4096;
+
+/// A set of hashes that forgets the oldest once it's full.
+struct HashCache {
+    order: VecDeque<Hash32Byte>,
+    hashes: HashSet<Hash32Byte>,
+}
+
+impl HashCache {
+    fn new() -> HashCache {
+        HashCache { order: VecDeque::new(), hashes: HashSet::new() }
+    }
+
+    /// Adds `hash`, returning false if it was already there.
+    fn insert(&mut self, hash: Hash32Byte) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > CACHE_CAPACITY {
+            let oldest = self.order.pop_front().unwrap();
+            self.hashes.remove(&oldest);
+        }
+        true
+    }
+
+    fn contains(&self, hash: &Hash32Byte) -> bool {
+        self.hashes.contains(hash)
+    }
+}
+
+pub struct Gossip {
+    /// Blocks we've received or made.
+    seen: HashCache,
+    /// Transactions we've relayed.
+    relayed: HashCache,
+    /// Blocks we've asked a peer for, and when. Each is only asked for
+    /// once until the request times out, and at most `CACHE_CAPACITY`
+    /// are outstanding.
+    requested: HashMap<Hash32Byte, Instant>,
+    /// The height of each block in our chain, so finding one by hash
+    /// doesn't mean searching the chain.
+    heights: HashMap<Hash32Byte, u64>,
+    fanout: usize,
+}
+
+impl Gossip {
+    pub fn new(fanout: usize) -> Gossip {
+        Gossip {
+            seen: HashCache::new(),
+            relayed: HashCache::new(),
+            requested: HashMap::new(),
+            heights: HashMap::new(),
+            fanout,
+        }
+    }
+
+    /// Records a block arriving or being made, returning false if it's
+    /// been seen before.
+    pub fn see(&mut self, hash: Hash32Byte) -> bool {
+        self.requested.remove(&hash);
+        self.seen.insert(hash)
+    }
+
+    /// Records relaying a transaction, returning false if it's been
+    /// relayed before.
+    pub fn relay_transaction(&mut self, hash: Hash32Byte) -> bool {
+        self.relayed.insert(hash)
+    }
+
+    /// Which of the announced `hashes` to ask for at `now`, noting that
+    /// they have been. Blocks asked for more than `REQUEST_TIMEOUT` ago
+    /// that still haven't arrived are asked for again.
+    pub fn wanted(&mut self, hashes: &[Hash32Byte], now: Instant) -> Vec<Hash32Byte> {
+        self.requested.retain(|_, &mut asked| now.duration_since(asked) < REQUEST_TIMEOUT);
+        let Gossip { ref seen, ref mut requested, .. } = *self;
+        // announcing it again doesn't put off asking someone else
+        hashes.iter().take(MAX_INVENTORY).cloned()
+            .filter(|hash| {
+                if seen.contains(hash) || requested.contains_key(hash) || requested.len() >= CACHE_CAPACITY {
+                    return false;
+                }
+                requested.insert(*hash, now);
+                true
+            })
+            .collect()
+    }
+
+    /// Notes that `added` have joined our chain and `removed` have left it.
+    pub fn index(&mut self, added: &[Block], removed: &[Block]) {
+        for block in removed {
+            self.heights.remove(&block.hash);
+        }
+        for block in added {
+            self.heights.insert(block.hash, block.block_num);
+        }
+    }
+
+    /// The height of the block with `hash` in our chain, if it's there.
+    pub fn height(&self, hash: &Hash32Byte) -> Option<u64> {
+        self.heights.get(hash).cloned()
+    }
+
+    /// Picks who to announce a block to from `peers`, which shouldn't
+    /// include whoever it came from.
+    pub fn relay_targets<T>(&self, mut peers: Vec<T>) -> Vec<T> {
+        rand::thread_rng().shuffle(&mut peers);
+        peers.truncate(self.fanout);
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> Hash32Byte {
+        Hash32Byte([byte; 32])
+    }
+
+    #[test]
+    fn transactions_are_only_relayed_once() {
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        assert!(gossip.relay_transaction(hash(1)));
+        assert!(!gossip.relay_transaction(hash(1)));
+        // blocks and transactions are remembered apart
+        assert!(gossip.see(hash(1)));
+    }
+
+    #[test]
+    fn blocks_are_only_seen_once() {
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        assert!(gossip.see(hash(1)));
+        assert!(!gossip.see(hash(1)));
+        assert!(gossip.see(hash(2)));
+    }
+
+    #[test]
+    fn old_hashes_are_forgotten() {
+        let mut cache = HashCache::new();
+        for i in 0..CACHE_CAPACITY as u32 + 1 {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            assert!(cache.insert(Hash32Byte(bytes)));
+        }
+        assert!(!cache.contains(&Hash32Byte([0; 32])));
+        assert_eq!(cache.hashes.len(), CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn blocks_are_asked_for_once_until_the_request_times_out() {
+        let start = Instant::now();
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        gossip.see(hash(1));
+        assert_eq!(gossip.wanted(&[hash(1), hash(2), hash(3)], start), vec![hash(2), hash(3)]);
+        assert!(gossip.wanted(&[hash(2), hash(3)], start + Duration::from_secs(1)).is_empty());
+        // one arrives, the other doesn't
+        gossip.see(hash(2));
+        assert_eq!(gossip.wanted(&[hash(2), hash(3)], start + REQUEST_TIMEOUT), vec![hash(3)]);
+    }
+
+    #[test]
+    fn only_so_many_hashes_are_taken_from_an_inv() {
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        let hashes: Vec<Hash32Byte> = (0..MAX_INVENTORY as u32 + 10).map(|i| {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            Hash32Byte(bytes)
+        }).collect();
+        assert_eq!(gossip.wanted(&hashes, Instant::now()).len(), MAX_INVENTORY);
+    }
+
+    #[test]
+    fn only_so_many_blocks_are_asked_for_at_once() {
+        let start = Instant::now();
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        let hashes: Vec<Hash32Byte> = (0..CACHE_CAPACITY as u32 + 1).map(|i| {
+            let mut bytes = [0; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            Hash32Byte(bytes)
+        }).collect();
+        let asked: usize = hashes.chunks(MAX_INVENTORY).map(|inv| gossip.wanted(inv, start).len()).sum();
+        assert_eq!(asked, CACHE_CAPACITY);
+        assert_eq!(gossip.wanted(&hashes[CACHE_CAPACITY..], start + REQUEST_TIMEOUT).len(), 1);
+    }
+
+    #[test]
+    fn heights_follow_the_chain() {
+        let genesis = Block::genesis(Hash32Byte([0; 32]));
+        let first = Block::new(&genesis, 1, hash(0), hash(0), [0; 1024]);
+        let other = Block::new(&genesis, 2, hash(0), hash(0), [0; 1024]);
+        let mut gossip = Gossip::new(DEFAULT_FANOUT);
+        gossip.index(&[genesis.clone(), first.clone()], &[]);
+        assert_eq!(gossip.height(&first.hash), Some(1));
+        gossip.index(std::slice::from_ref(&other), std::slice::from_ref(&first));
+        assert_eq!(gossip.height(&first.hash), None);
+        assert_eq!(gossip.height(&other.hash), Some(1));
+        assert_eq!(gossip.height(&genesis.hash), Some(0));
+    }
+
+    #[test]
+    fn relays_go_to_fanout_peers() {
+        let gossip = Gossip::new(3);
+        let mut targets = gossip.relay_targets((0..10).collect());
+        assert_eq!(targets.len(), 3);
+        targets.sort();
+        targets.dedup();
+        assert_eq!(targets.len(), 3);
+        assert_eq!(gossip.relay_targets(vec![1, 2]).len(), 2);
+    }
+}
@@ -0,0 +1,156 @@
+//! Seed phrases (BIP39) and deterministic key derivation for ed25519
+//! (SLIP-0010), so a whole wallet can be restored from a list of words.
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::{Sha256, Sha512};
+use rand::{OsRng, Rng};
+use std::io;
+
+use keys::Keypair;
+
+
+const WORDLIST: &str = include_str!("english.txt");
+
+/// Index of the first hardened child. ed25519 only supports hardened
+/// derivation, so every index is offset by this.
+pub const HARDENED: u32 = 0x8000_0000;
+
+fn words() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+/// Makes a new random seed phrase of 12, 15, 18, 21 or 24 words.
+pub fn generate_mnemonic(word_count: usize) -> io::Result<String> {
+    if !word_count.is_multiple_of(3) || !(12..=24).contains(&word_count) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seed phrases have 12, 15, 18, 21 or 24 words"));
+    }
+    let mut entropy = vec![0u8; word_count / 3 * 4];
+    OsRng::new()?.fill_bytes(&mut entropy);
+    Ok(entropy_to_mnemonic(&entropy))
+}
+
+/// Encodes 16 to 32 bytes of entropy as a seed phrase.
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> String {
+    assert!(entropy.len().is_multiple_of(4) && entropy.len() >= 16 && entropy.len() <= 32);
+
+    let mut checksum = [0u8; 32];
+    let mut sha = Sha256::new();
+    sha.input(entropy);
+    sha.result(&mut checksum);
+
+    let mut bits: Vec<bool> = Vec::new();
+    for byte in entropy.iter().chain(checksum.iter().take(1)) {
+        for i in (0..8).rev() {
+            bits.push(byte & (1 << i) != 0);
+        }
+    }
+    // one checksum bit per 32 bits of entropy
+    bits.truncate(entropy.len() * 8 + entropy.len() / 4);
+
+    let words = words();
+    bits.chunks(11)
+        .map(|chunk| words[chunk.iter().fold(0, |index, &bit| (index << 1) | bit as usize)])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Decodes a seed phrase back into its entropy, checking the checksum.
+pub fn mnemonic_to_entropy(mnemonic: &str) -> Result<Vec<u8>, String> {
+    let words = words();
+    let mut bits: Vec<bool> = Vec::new();
+    let mut word_count = 0usize;
+    for word in mnemonic.split_whitespace() {
+        let index = match words.binary_search(&word) {
+            Ok(index) => index,
+            Err(_) => return Err(format!("{} isn't in the word list", word)),
+        };
+        for i in (0..11).rev() {
+            bits.push(index & (1 << i) != 0);
+        }
+        word_count += 1;
+    }
+    if !word_count.is_multiple_of(3) || !(12..=24).contains(&word_count) {
+        return Err("Seed phrases have 12, 15, 18, 21 or 24 words".to_string());
+    }
+
+    let entropy: Vec<u8> = bits[..word_count / 3 * 32].chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |byte, &bit| (byte << 1) | bit as u8))
+        .collect();
+    if entropy_to_mnemonic(&entropy) != mnemonic.split_whitespace().collect::<Vec<_>>().join(" ") {
+        return Err("Seed phrase checksum doesn't match".to_string());
+    }
+    Ok(entropy)
+}
+
+/// Stretches a seed phrase and optional passphrase into a 64 byte seed.
+pub fn mnemonic_to_seed(mnemonic: &str, passphrase: &str) -> [u8; 64] {
+    let mnemonic = mnemonic.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut mac = Hmac::new(Sha512::new(), mnemonic.as_bytes());
+    let salt = format!("mnemonic{}", passphrase);
+    let mut seed = [0u8; 64];
+    pbkdf2(&mut mac, salt.as_bytes(), 2048, &mut seed);
+    seed
+}
+
+fn hmac_sha512(key: &[u8], data: &[&[u8]]) -> ExtendedKey {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    for part in data {
+        mac.input(part);
+    }
+    let mut output = [0u8; 64];
+    mac.raw_result(&mut output);
+
+    let mut extended = ExtendedKey { key: [0; 32], chain_code: [0; 32] };
+    extended.key.copy_from_slice(&output[..32]);
+    extended.chain_code.copy_from_slice(&output[32..]);
+    extended
+}
+
+/// A private key plus the chain code needed to derive its children.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        hmac_sha512(b"ed25519 seed", &[seed])
+    }
+
+    /// Derives child `index`, which is always hardened.
+    pub fn child(&self, index: u32) -> ExtendedKey {
+        let mut index_bytes = [0u8; 4];
+        let index = index | HARDENED;
+        for (i, byte) in index_bytes.iter_mut().enumerate() {
+            *byte = (index >> (24 - 8 * i)) as u8;
+        }
+        hmac_sha512(&self.chain_code, &[&[0], &self.key, &index_bytes])
+    }
+
+    /// Follows a path like `m/44'/0'/3'`. Every step is hardened, with or
+    /// without the `'`.
+    pub fn derive_path(&self, path: &str) -> Result<ExtendedKey, String> {
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(format!("Path {} doesn't start at m", path));
+        }
+        let mut key = self.clone();
+        for part in parts {
+            let index = part.trim_end_matches('\'').trim_end_matches('H').parse::<u32>()
+                .map_err(|_| format!("Invalid step {} in path {}", part, path))?;
+            if index >= HARDENED {
+                return Err(format!("Step {} in path {} is too big", part, path));
+            }
+            key = key.child(index);
+        }
+        Ok(key)
+    }
+
+    pub fn keypair(&self) -> Keypair {
+        Keypair::from_seed(&self.key)
+    }
+}
@@ -0,0 +1,313 @@
+//! Kademlia-style peer discovery. Node ids are node addresses, and the
+//! distance between two ids, or an id and a block hash, is their XOR. Each
+//! node keeps contacts in buckets by how many leading bits they share with
+//! its own id, so it knows many nodes near it and a few far away, and a
+//! lookup asks ever closer nodes until no one closer turns up.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+
+use block::{Block, Hash32Byte};
+use keys::Address;
+
+
+/// Most contacts kept in a bucket, and returned by a lookup or a
+/// `FindNode`.
+pub const K: usize = 20;
+
+/// How many queries a lookup has out at once.
+pub const ALPHA: usize = 3;
+
+/// A node and where it listens.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Contact {
+    pub id: Address,
+    pub addr: SocketAddr,
+}
+
+/// What a lookup is after: the nodes closest to an id, or a block.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Target {
+    Node(Address),
+    Value(Hash32Byte),
+}
+
+impl Target {
+    fn key(&self) -> &[u8; 32] {
+        match *self {
+            Target::Node(ref id) => &id.0,
+            Target::Value(ref hash) => &hash.0,
+        }
+    }
+}
+
+pub fn distance(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let mut distance = [0; 32];
+    for i in 0..32 {
+        distance[i] = a[i] ^ b[i];
+    }
+    distance
+}
+
+pub struct RoutingTable {
+    own: Address,
+    /// Bucket `i` holds the contacts whose ids share exactly `i` leading
+    /// bits with ours, least recently heard from first.
+    buckets: Vec<VecDeque<Contact>>,
+}
+
+impl RoutingTable {
+    pub fn new(own: Address) -> RoutingTable {
+        RoutingTable { own, buckets: (0..256).map(|_| VecDeque::new()).collect() }
+    }
+
+    /// Which bucket `id` belongs in, or `None` if it's ours.
+    fn bucket(&self, id: &Address) -> Option<usize> {
+        let distance = distance(&self.own.0, &id.0);
+        distance.iter().position(|&byte| byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+    }
+
+    /// Notes that we've just heard from `contact`. New contacts are only
+    /// added if their bucket has room: nodes that have stayed up a while
+    /// are the likeliest to stay up longer, so they're kept over new ones.
+    /// Returns whether the contact is in the table.
+    pub fn insert(&mut self, contact: Contact) -> bool {
+        let bucket = match self.bucket(&contact.id) {
+            Some(bucket) => &mut self.buckets[bucket],
+            None => return false,
+        };
+        if let Some(i) = bucket.iter().position(|known| known.id == contact.id) {
+            bucket.remove(i);
+        } else if bucket.len() >= K {
+            return false;
+        }
+        bucket.push_back(contact);
+        true
+    }
+
+    /// Forgets `id`, say because it couldn't be reached.
+    pub fn remove(&mut self, id: &Address) {
+        if let Some(bucket) = self.bucket(id) {
+            self.buckets[bucket].retain(|contact| contact.id != *id);
+        }
+    }
+
+    /// The contact for `id`, if we have one.
+    pub fn get(&self, id: &Address) -> Option<Contact> {
+        self.bucket(id).and_then(|bucket| self.buckets[bucket].iter().find(|contact| contact.id == *id).cloned())
+    }
+
+    /// Up to `count` contacts, closest to `target` first.
+    pub fn closest(&self, target: &Target, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self.buckets.iter().flat_map(|bucket| bucket.iter().cloned()).collect();
+        contacts.sort_by_key(|contact| distance(&contact.id.0, target.key()));
+        contacts.truncate(count);
+        contacts
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// One iterative lookup. It starts from the closest contacts we know and
+/// queries `ALPHA` of the closest it hasn't asked yet at a time, adding
+/// whoever they know of, until everyone in the `K` closest has answered
+/// or failed, or someone has the block it's after.
+pub struct Lookup {
+    target: Target,
+    own: Address,
+    /// The closest contacts heard of so far, closest first.
+    shortlist: Vec<Contact>,
+    queried: HashMap<Address, Contact>,
+    /// Queried contacts that haven't answered yet.
+    pending: HashSet<Address>,
+    found: Option<(Contact, Block)>,
+}
+
+impl Lookup {
+    pub fn new(target: Target, own: Address, contacts: Vec<Contact>) -> Lookup {
+        let mut lookup = Lookup {
+            target,
+            own,
+            shortlist: Vec::new(),
+            queried: HashMap::new(),
+            pending: HashSet::new(),
+            found: None,
+        };
+        lookup.add(contacts);
+        lookup
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    fn add(&mut self, contacts: Vec<Contact>) {
+        for contact in contacts {
+            if contact.id != self.own && !self.shortlist.iter().any(|known| known.id == contact.id) {
+                self.shortlist.push(contact);
+            }
+        }
+        let target = self.target;
+        self.shortlist.sort_by_key(|contact| distance(&contact.id.0, target.key()));
+        self.shortlist.truncate(K);
+    }
+
+    /// The contacts to query next, noting that they have been.
+    pub fn next_queries(&mut self) -> Vec<Contact> {
+        if self.found.is_some() {
+            return Vec::new();
+        }
+        let room = ALPHA.saturating_sub(self.pending.len());
+        let next: Vec<Contact> = self.shortlist.iter()
+            .filter(|contact| !self.queried.contains_key(&contact.id))
+            .take(room).cloned().collect();
+        for contact in &next {
+            self.queried.insert(contact.id, *contact);
+            self.pending.insert(contact.id);
+        }
+        next
+    }
+
+    /// Whether we're waiting on an answer from `id`.
+    pub fn is_waiting_on(&self, id: &Address) -> bool {
+        self.pending.contains(id)
+    }
+
+    /// Takes `from`'s answer of the contacts it knows closest to the target.
+    pub fn answered(&mut self, from: &Address, contacts: Vec<Contact>) {
+        if self.pending.remove(from) {
+            self.add(contacts.into_iter().take(K).collect());
+        }
+    }
+
+    /// Takes `from`'s answer of the block we're after.
+    pub fn found(&mut self, from: &Address, block: Block) {
+        if self.pending.remove(from) {
+            self.found = Some((self.queried[from], block));
+        }
+    }
+
+    /// Gives up on `id`, which couldn't be reached.
+    pub fn failed(&mut self, id: &Address) {
+        self.pending.remove(id);
+        self.shortlist.retain(|contact| contact.id != *id);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.found.is_some()
+            || (self.pending.is_empty() && self.shortlist.iter().all(|contact| self.queried.contains_key(&contact.id)))
+    }
+
+    /// The closest contacts found, closest first.
+    pub fn closest(&self) -> &[Contact] {
+        &self.shortlist
+    }
+
+    /// Who had the block we were after, and the block.
+    pub fn value(&self) -> Option<&(Contact, Block)> {
+        self.found.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An id that's all zeroes but for `byte`.
+    fn id(byte: u8) -> Address {
+        let mut id = [0; 32];
+        id[0] = byte;
+        Address(id)
+    }
+
+    fn contact(byte: u8) -> Contact {
+        Contact { id: id(byte), addr: SocketAddr::from(([127, 0, 0, 1], 9000 + byte as u16)) }
+    }
+
+    #[test]
+    fn buckets_go_by_shared_leading_bits() {
+        let table = RoutingTable::new(id(0));
+        assert_eq!(table.bucket(&id(0)), None);
+        assert_eq!(table.bucket(&id(0x80)), Some(0));
+        assert_eq!(table.bucket(&id(0x01)), Some(7));
+        let mut last = [0; 32];
+        last[31] = 1;
+        assert_eq!(table.bucket(&Address(last)), Some(255));
+    }
+
+    #[test]
+    fn full_buckets_keep_old_contacts() {
+        let mut table = RoutingTable::new(id(0));
+        assert!(!table.insert(contact(0)));
+        // everything from 0x80 up shares no leading bits with us
+        for byte in 0x80..0x80 + K as u8 {
+            assert!(table.insert(contact(byte)));
+        }
+        assert!(!table.insert(contact(0xff)));
+        assert_eq!(table.len(), K);
+        // hearing from a known contact moves it to the back
+        assert!(table.insert(contact(0x80)));
+        assert_eq!(table.buckets[0].back().unwrap().id, id(0x80));
+        table.remove(&id(0x80));
+        assert!(table.get(&id(0x80)).is_none());
+        assert!(table.insert(contact(0xff)));
+    }
+
+    #[test]
+    fn closest_sorts_by_distance() {
+        let mut table = RoutingTable::new(id(0));
+        for &byte in &[0x01, 0x40, 0x0f, 0x81] {
+            table.insert(contact(byte));
+        }
+        let closest: Vec<Address> = table.closest(&Target::Node(id(0x0e)), 3).iter().map(|c| c.id).collect();
+        assert_eq!(closest, vec![id(0x0f), id(0x01), id(0x40)]);
+    }
+
+    #[test]
+    fn lookup_asks_closer_contacts_until_everyone_answers() {
+        let target = Target::Node(id(0x01));
+        let mut lookup = Lookup::new(target, id(0), vec![contact(0x40), contact(0x20), contact(0x10), contact(0x08)]);
+        let first: Vec<Address> = lookup.next_queries().iter().map(|c| c.id).collect();
+        assert_eq!(first, vec![id(0x08), id(0x10), id(0x20)]);
+        // no more than ALPHA out at once
+        assert!(lookup.next_queries().is_empty());
+
+        // answers from those we didn't ask don't count
+        lookup.answered(&id(0x40), vec![contact(0x02)]);
+        assert_eq!(lookup.closest().len(), 4);
+
+        lookup.answered(&id(0x08), vec![contact(0x02), contact(0)]);
+        assert_eq!(lookup.closest()[0].id, id(0x02));
+        assert!(lookup.closest().iter().all(|c| c.id != id(0)));
+        assert_eq!(lookup.next_queries(), vec![contact(0x02)]);
+
+        lookup.failed(&id(0x10));
+        assert!(lookup.closest().iter().all(|c| c.id != id(0x10)));
+        assert_eq!(lookup.next_queries(), vec![contact(0x40)]);
+        assert!(!lookup.is_done());
+
+        for byte in &[0x02, 0x20, 0x40] {
+            lookup.answered(&id(*byte), Vec::new());
+        }
+        assert!(lookup.is_done());
+        assert!(lookup.value().is_none());
+    }
+
+    #[test]
+    fn lookup_stops_once_the_value_is_found() {
+        let block = Block::genesis(Hash32Byte([0; 32]));
+        let mut lookup = Lookup::new(Target::Value(block.hash), id(0), vec![contact(0x01), contact(0x02)]);
+        assert_eq!(lookup.next_queries().len(), 2);
+        lookup.found(&id(0x02), block.clone());
+        assert!(lookup.is_done());
+        assert!(lookup.next_queries().is_empty());
+        assert_eq!(lookup.value().unwrap().0, contact(0x02));
+    }
+}
@@ -0,0 +1,215 @@
+use serde;
+use serde::ser::{Serialize, Serializer};
+use serde::de::{Deserialize, Deserializer};
+
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::curve25519::{Fe, GeP3};
+use crypto::ed25519;
+use crypto::sha2::{Sha256, Sha512};
+use rand::{OsRng, Rng};
+use std::fmt;
+use std::io;
+
+use block::Hash32Byte;
+
+
+/// An account on the chain. For a single key this is just the ed25519
+/// public key; for a multisig account it's the hash of its
+/// `MultisigPolicy`, and for a script the hash of the `Script`.
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct Address(pub [u8; 32]);
+
+impl Address {
+    pub fn from_hex(s: &str) -> Result<Address, String> {
+        Hash32Byte::from_hex(s).map(|hash| Address(hash.0))
+    }
+
+    /// The address of an account with no key of its own, from a hash of
+    /// what controls it. The hash is hashed again until it isn't a valid
+    /// ed25519 key, which takes two tries on average, so that keyless
+    /// addresses can always be told apart from single-key ones.
+    pub fn keyless(mut hash: [u8; 32]) -> Address {
+        while GeP3::from_bytes_negate_vartime(&hash).is_some() {
+            let mut sha = Sha256::new();
+            sha.input(&hash);
+            sha.result(&mut hash);
+        }
+        Address(hash)
+    }
+
+    /// Whether this is a single-key address, rather than a keyless one.
+    pub fn is_key(&self) -> bool {
+        GeP3::from_bytes_negate_vartime(&self.0).is_some()
+    }
+
+    /// The X25519 public key for key exchange with this address's owner,
+    /// found by mapping its ed25519 key from Edwards to Montgomery form:
+    /// u = (1 + y) / (1 - y).
+    pub fn exchange_key(&self) -> [u8; 32] {
+        let one = Fe([1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let y = Fe::from_bytes(&self.0);
+        ((one + y) * (one - y).invert()).to_bytes()
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&Hash32Byte(self.0), f)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&Hash32Byte(self.0), f)
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct Signature(pub [u8; 64]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}...", self.0[0..10].iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(""))
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+       where D: Deserializer<'de>
+    {
+        struct SignatureVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for SignatureVisitor {
+            type Value = Signature;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("64 bytes")
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<Signature, E>
+                where E: serde::de::Error
+            {
+                if v.len() != 64 {
+                    return Err(E::invalid_length(v.len(), &self));
+                }
+                let mut signature = [0u8; 64];
+                signature.copy_from_slice(v);
+                Ok(Signature(signature))
+            }
+
+            fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Signature, E>
+                where E: serde::de::Error
+            {
+                self.visit_bytes(&v)
+            }
+        }
+
+        deserializer.deserialize_bytes(SignatureVisitor)
+    }
+}
+
+#[derive(Clone)]
+pub struct Keypair {
+    secret: [u8; 64],
+    public: [u8; 32],
+}
+
+impl Keypair {
+    pub fn generate() -> io::Result<Keypair> {
+        let mut seed = [0u8; 32];
+        OsRng::new()?.fill_bytes(&mut seed);
+        Ok(Keypair::from_seed(&seed))
+    }
+
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let (secret, public) = ed25519::keypair(seed);
+        Keypair { secret, public }
+    }
+
+    /// The 32 byte seed this keypair was made from.
+    pub fn seed(&self) -> [u8; 32] {
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice(&self.secret[..32]);
+        seed
+    }
+
+    pub fn address(&self) -> Address {
+        Address(self.public)
+    }
+
+    /// The X25519 secret matching `Address::exchange_key`, which is the same
+    /// scalar ed25519 signs with.
+    pub fn exchange_secret(&self) -> [u8; 32] {
+        let mut sha = Sha512::new();
+        sha.input(&self.seed());
+        let mut hash = [0; 64];
+        sha.result(&mut hash);
+
+        let mut secret = [0; 32];
+        secret.copy_from_slice(&hash[..32]);
+        secret
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature(ed25519::signature(message, &self.secret))
+    }
+}
+
+pub fn verify(message: &[u8], public_key: &Address, signature: &Signature) -> bool {
+    ed25519::verify(message, &public_key.0, &signature.0)
+}
+
+/// Most keys a multisig account can have.
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// An M-of-N account: spending from it takes valid signatures from
+/// `threshold` of `keys`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    pub keys: Vec<Address>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, keys: Vec<Address>) -> Result<MultisigPolicy, String> {
+        let policy = MultisigPolicy { threshold, keys };
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.keys.len() > MAX_MULTISIG_KEYS {
+            return Err(format!("Multisig accounts can have at most {} keys", MAX_MULTISIG_KEYS));
+        }
+        if self.threshold == 0 || self.threshold as usize > self.keys.len() {
+            return Err(format!("Can't require {} of {} signatures", self.threshold, self.keys.len()));
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            if self.keys[..i].contains(key) {
+                return Err(format!("Key {} is in the policy twice", key));
+            }
+        }
+        Ok(())
+    }
+
+    /// The account's address. Hashing a tag in with the policy keeps it
+    /// from colliding with a script's.
+    pub fn address(&self) -> Address {
+        let mut sha = Sha256::new();
+        sha.input(b"multisig");
+        sha.input(&serialize(self, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Address::keyless(output)
+    }
+}
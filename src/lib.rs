@@ -8,7 +8,38 @@ extern crate bincode;
 extern crate base64;
 extern crate time;
 extern crate getopts;
+extern crate rand;
+extern crate wasmi;
+extern crate lz4_flex;
+extern crate zstd;
+extern crate snow;
+extern crate mio;
 
 pub mod connection;
+pub mod network;
+pub mod gossip;
+pub mod reputation;
+pub mod ratelimit;
+pub mod addrbook;
+pub mod atomic;
+pub mod kademlia;
+pub mod dht;
+pub mod backoff;
+pub mod compression;
 pub mod message;
 pub mod block;
+pub mod keys;
+pub mod checkpoint;
+pub mod transaction;
+pub mod mempool;
+pub mod state;
+pub mod utxo;
+pub mod script;
+pub mod contract;
+pub mod token;
+pub mod names;
+pub mod notary;
+pub mod chunks;
+pub mod envelope;
+pub mod hd;
+pub mod wallet;
@@ -0,0 +1,440 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+
+use block::{Block, Hash32Byte};
+use keys::Address;
+use state::{AccountInfo, Ledger};
+use transaction::{OutPoint, Transaction, TransactionKind, Witness, block_transactions};
+
+
+/// Most partially signed transactions kept while waiting for co-signers.
+/// Past that the oldest make way for new ones.
+const MAX_PARTIALS: usize = 256;
+
+/// Most partially signed transactions started by any one IP, so no one
+/// can push out everyone else's.
+const MAX_PARTIALS_PER_ORIGIN: usize = 16;
+
+/// Compares what two transactions pay per byte.
+fn cmp_fee_rate(a: &Transaction, b: &Transaction) -> Ordering {
+    (a.fee as u128 * b.size() as u128).cmp(&(b.fee as u128 * a.size() as u128))
+}
+
+/// What a sender's pooled transactions have laid claim to.
+#[derive(Default)]
+struct Claimed {
+    cost: u64,
+    inputs: HashSet<OutPoint>,
+}
+
+/// Checks the sender of `transaction` can pay for it out of what the ledger
+/// says it has, `info`, less what its other pooled transactions have
+/// `claimed`, and adds it to the claims.
+fn claim(transaction: &Transaction, info: &AccountInfo, claimed: &mut Claimed) -> Result<(), String> {
+    if transaction.nonce < info.nonce {
+        return Err(format!("Nonce {} from {} is stale, the next is {}", transaction.nonce, transaction.from, info.nonce));
+    }
+    match info.unspent {
+        Some(ref unspent) => {
+            let inputs = match transaction.kind {
+                TransactionKind::Spend { ref inputs, .. } => inputs,
+                _ => return Err("The UTXO ledger only takes spends".to_string()),
+            };
+            for input in inputs {
+                if !unspent.iter().any(|&(outpoint, _)| outpoint == *input) {
+                    return Err(format!("{:?} is spent or doesn't exist", input));
+                }
+                if claimed.inputs.contains(input) {
+                    return Err(format!("{:?} is already spent by a pending transaction", input));
+                }
+            }
+            claimed.inputs.extend(inputs.iter().cloned());
+        },
+        None => {
+            let amount = match transaction.kind {
+                TransactionKind::Transfer { amount, .. } => amount,
+                _ => 0,
+            };
+            let cost = amount.checked_add(transaction.fee)
+                .and_then(|cost| cost.checked_add(claimed.cost))
+                .ok_or("Amount plus fee overflows")?;
+            if cost > info.balance {
+                return Err(format!("{} can't pay {} with what's pending, it only has {}",
+                    transaction.from, cost, info.balance));
+            }
+            claimed.cost = cost;
+        },
+    }
+    Ok(())
+}
+
+/// Transactions waiting to be included in a block. When it fills up, the
+/// transactions paying the least per byte make way for better ones.
+pub struct Mempool {
+    transactions: HashMap<Hash32Byte, Transaction>,
+    order: VecDeque<Hash32Byte>,
+    size: usize,
+    max_size: usize,
+    /// Multisig transactions still collecting signatures, by signing hash,
+    /// with where the first signatures came from.
+    partials: HashMap<Hash32Byte, (Transaction, IpAddr)>,
+    /// Signing hashes of the partials, oldest first.
+    partial_order: VecDeque<Hash32Byte>,
+}
+
+impl Mempool {
+    /// Creates a mempool holding at most `max_size` bytes of transactions.
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool {
+            transactions: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            max_size,
+            partials: HashMap::new(),
+            partial_order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// Total size in bytes of the pooled transactions.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, hash: &Hash32Byte) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.order.iter().map(move |hash| &self.transactions[hash])
+    }
+
+    /// Validates and adds a transaction, returning its hash. Its sender
+    /// must be able to pay for it on `ledger` along with the rest of its
+    /// pooled transactions. If the pool is full, transactions with a lower
+    /// fee rate are evicted to make room.
+    pub fn insert(&mut self, transaction: Transaction, ledger: &dyn Ledger) -> Result<Hash32Byte, String> {
+        transaction.validate()?;
+
+        let hash = transaction.hash();
+        if self.contains(&hash) {
+            return Err(format!("Transaction {:?} is already in the mempool", hash));
+        }
+        let size = transaction.size();
+        if size > self.max_size {
+            return Err("Transaction is bigger than the mempool".to_string());
+        }
+
+        let info = ledger.account_info(&transaction.from);
+        let mut claimed = Claimed::default();
+        for pending in self.iter().filter(|pending| pending.from == transaction.from) {
+            // what's pooled has been checked since the last block
+            let _ = claim(pending, &info, &mut claimed);
+        }
+        claim(&transaction, &info, &mut claimed)?;
+
+        if self.size + size > self.max_size {
+            let mut cheapest: Vec<&Transaction> = self.transactions.values().collect();
+            cheapest.sort_by(|a, b| cmp_fee_rate(a, b));
+
+            let mut evict = Vec::new();
+            let mut freed = 0;
+            for victim in cheapest {
+                if self.size - freed + size <= self.max_size {
+                    break;
+                }
+                if cmp_fee_rate(victim, &transaction) != Ordering::Less {
+                    return Err("Mempool is full of transactions paying at least as much".to_string());
+                }
+                freed += victim.size();
+                evict.push(victim.hash());
+            }
+            for victim in evict {
+                self.remove(&victim);
+            }
+        }
+
+        self.size += size;
+        self.order.push_back(hash);
+        self.transactions.insert(hash, transaction);
+        Ok(hash)
+    }
+
+    pub fn remove(&mut self, hash: &Hash32Byte) -> Option<Transaction> {
+        let removed = self.transactions.remove(hash);
+        if let Some(ref transaction) = removed {
+            self.size -= transaction.size();
+            self.order.retain(|h| h != hash);
+        }
+        removed
+    }
+
+    pub fn partials(&self) -> impl Iterator<Item = &Transaction> {
+        self.partials.values().map(|&(ref transaction, _)| transaction)
+    }
+
+    /// Merges a partially signed multisig transaction with the signatures
+    /// already collected for it. Once it has enough, it moves into the pool
+    /// proper. Returns the merged transaction, or an error if it brought no
+    /// new signatures. New partials are charged to `origin`.
+    pub fn add_partial(&mut self, transaction: Transaction, origin: IpAddr, ledger: &dyn Ledger) -> Result<Transaction, String> {
+        let signing_hash = transaction.signing_hash();
+        let (mut merged, origin) = match self.partials.get(&signing_hash) {
+            Some(&(ref existing, started_by)) => (existing.clone(), started_by),
+            None if self.partials.values().filter(|&&(_, started_by)| started_by == origin).count() >= MAX_PARTIALS_PER_ORIGIN => {
+                return Err(format!("Too many transactions from {} waiting for signatures", origin));
+            },
+            None => {
+                let mut empty = transaction.clone();
+                if let Witness::Multisig { ref mut signatures, .. } = empty.witness {
+                    signatures.clear();
+                }
+                (empty, origin)
+            },
+        };
+        if merged.merge_signatures(&transaction)? == 0 {
+            return Err("No new signatures".to_string());
+        }
+
+        if merged.signatures_needed()? == 0 {
+            self.remove_partial(&signing_hash);
+            self.insert(merged.clone(), ledger)?;
+        } else if self.partials.insert(signing_hash, (merged.clone(), origin)).is_none() {
+            self.partial_order.push_back(signing_hash);
+            if self.partial_order.len() > MAX_PARTIALS {
+                let oldest = self.partial_order.pop_front().unwrap();
+                self.partials.remove(&oldest);
+            }
+        }
+        Ok(merged)
+    }
+
+    fn remove_partial(&mut self, signing_hash: &Hash32Byte) {
+        if self.partials.remove(signing_hash).is_some() {
+            self.partial_order.retain(|hash| hash != signing_hash);
+        }
+    }
+
+    /// The nonce for the next transaction from `address`, counting the ones
+    /// still waiting in the pool. `confirmed` is the account's nonce on chain.
+    pub fn next_nonce(&self, address: &Address, confirmed: u64) -> u64 {
+        self.iter()
+            .filter(|transaction| transaction.from == *address)
+            .map(|transaction| transaction.nonce + 1)
+            .fold(confirmed, |a, b| a.max(b))
+    }
+
+    /// Adjusts what the ledger knows about an account for its transactions
+    /// that are still pending: bumps the nonce past them and hides the
+    /// outputs they spend.
+    pub fn account_info(&self, confirmed: AccountInfo) -> AccountInfo {
+        let mut info = confirmed;
+        info.nonce = self.next_nonce(&info.address, info.nonce);
+        let address = info.address;
+        if let Some(ref mut unspent) = info.unspent {
+            for transaction in self.iter().filter(|transaction| transaction.from == address) {
+                if let TransactionKind::Spend { ref inputs, .. } = transaction.kind {
+                    unspent.retain(|&(outpoint, _)| !inputs.contains(&outpoint));
+                }
+            }
+            info.balance = unspent.iter().map(|&(_, output)| output.amount).sum();
+        }
+        info
+    }
+
+    /// The best paying transactions that fit together in `budget` bytes and
+    /// that `accept` agrees to, in the order `accept` was asked about them.
+    /// They stay in the pool until the block is accepted.
+    pub fn select_for_block<F>(&self, budget: usize, mut accept: F) -> Vec<Transaction>
+        where F: FnMut(&Transaction) -> bool
+    {
+        let mut candidates: Vec<&Transaction> = self.iter().collect();
+        // stable, so equal fee rates keep arrival order
+        candidates.sort_by(|a, b| cmp_fee_rate(b, a));
+
+        let mut remaining = budget;
+        let mut selected = Vec::new();
+        // a transaction may only become acceptable after another one (the
+        // previous nonce, say), so keep going while we make progress
+        loop {
+            let mut progress = false;
+            candidates.retain(|transaction| {
+                let size = transaction.size();
+                if size <= remaining && accept(transaction) {
+                    remaining -= size;
+                    selected.push((*transaction).clone());
+                    progress = true;
+                    false
+                } else {
+                    true
+                }
+            });
+            if !progress {
+                return selected;
+            }
+        }
+    }
+
+    /// Drops transactions that have made it into accepted blocks, and any
+    /// their senders can no longer pay for on `ledger`, which has applied
+    /// them.
+    pub fn remove_included(&mut self, blocks: &[Block], ledger: &dyn Ledger) {
+        // a multisig transaction may have been included with a different
+        // set of signatures, so match on what was signed
+        let included: HashSet<Hash32Byte> = blocks.iter()
+            .flat_map(block_transactions)
+            .map(|transaction| transaction.signing_hash())
+            .collect();
+        let stale: Vec<Hash32Byte> = self.iter()
+            .filter(|transaction| included.contains(&transaction.signing_hash()))
+            .map(Transaction::hash)
+            .collect();
+        for hash in stale {
+            self.remove(&hash);
+        }
+        self.partials.retain(|signing_hash, _| !included.contains(signing_hash));
+        self.partial_order.retain(|signing_hash| !included.contains(signing_hash));
+        self.prune(ledger);
+    }
+
+    /// Drops the transactions whose senders can't pay for them on `ledger`
+    /// along with the ones before them in the pool.
+    fn prune(&mut self, ledger: &dyn Ledger) {
+        let mut senders: HashMap<Address, (AccountInfo, Claimed)> = HashMap::new();
+        let unpaid: Vec<Hash32Byte> = self.iter()
+            .filter(|transaction| {
+                let &mut (ref info, ref mut claimed) = senders.entry(transaction.from)
+                    .or_insert_with(|| (ledger.account_info(&transaction.from), Claimed::default()));
+                claim(transaction, info, claimed).is_err()
+            })
+            .map(Transaction::hash)
+            .collect();
+        for hash in unpaid {
+            self.remove(&hash);
+        }
+    }
+
+    /// Updates the pool after our chain switched from `old_chain` to
+    /// `new_chain`: transactions from abandoned blocks go back into the pool
+    /// unless the new blocks include them too. `ledger` is at the tip of
+    /// `new_chain`.
+    pub fn reorg(&mut self, old_chain: &[Block], new_chain: &[Block], ledger: &dyn Ledger) {
+        let common = old_chain.iter().zip(new_chain.iter())
+            .take_while(|&(old, new)| old.hash == new.hash)
+            .count();
+
+        for block in &old_chain[common..] {
+            for transaction in block_transactions(block) {
+                // may fail if the pool filled up in the meantime; those are dropped
+                let _ = self.insert(transaction, ledger);
+            }
+        }
+        self.remove_included(&new_chain[common..], ledger);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use contract::BlockContext;
+    use keys::{Keypair, MultisigPolicy};
+    use state::AccountLedger;
+    use transaction::{BlockBody, pack_block};
+
+    fn transfer(keypair: &Keypair, nonce: u64, fee: u64, amount: u64) -> Transaction {
+        Transaction::new(keypair, nonce, fee, TransactionKind::Transfer { to: Address([7; 32]), amount })
+    }
+
+    fn block_with(previous: &Block, ledger: &dyn Ledger, transactions: Vec<Transaction>) -> Block {
+        let context = BlockContext { height: previous.block_num + 1, timestamp: previous.timestamp + 1 };
+        let mut draft = ledger.draft(context);
+        for transaction in &transactions {
+            draft.apply_transaction(transaction).unwrap();
+        }
+        let body = BlockBody { reward: None, transactions };
+        Block::new(previous, context.timestamp, draft.state_root(), body.transactions_root(), pack_block(&body).unwrap())
+    }
+
+    /// Transaction `nonce` from a 2-of-2 multisig, signed by one key.
+    fn partial(keypair: &Keypair, nonce: u64) -> Transaction {
+        let policy = MultisigPolicy::new(2, vec![keypair.address(), Address([7; 32])]).unwrap();
+        let mut transaction = Transaction::new_multisig(policy, nonce, 0, None, TransactionKind::Data(vec![1]));
+        transaction.cosign(keypair).unwrap();
+        transaction
+    }
+
+    #[test]
+    fn partials_are_limited_per_origin() {
+        let keypair = Keypair::generate().unwrap();
+        let ledger = AccountLedger::new(&[]).unwrap();
+        let mut mempool = Mempool::new(1 << 20);
+        let (greedy, other) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        for nonce in 0..MAX_PARTIALS_PER_ORIGIN as u64 {
+            mempool.add_partial(partial(&keypair, nonce), greedy, &ledger).unwrap();
+        }
+        assert!(mempool.add_partial(partial(&keypair, 100), greedy, &ledger).is_err());
+        mempool.add_partial(partial(&keypair, 100), other, &ledger).unwrap();
+    }
+
+    #[test]
+    fn the_oldest_partials_make_way() {
+        let keypair = Keypair::generate().unwrap();
+        let ledger = AccountLedger::new(&[]).unwrap();
+        let mut mempool = Mempool::new(1 << 20);
+        let first = partial(&keypair, 0);
+        for nonce in 0..MAX_PARTIALS as u64 + 1 {
+            let origin = IpAddr::from([10, 0, (nonce >> 8) as u8, nonce as u8]);
+            mempool.add_partial(partial(&keypair, nonce), origin, &ledger).unwrap();
+        }
+        assert_eq!(mempool.partials().count(), MAX_PARTIALS);
+        assert!(mempool.partials().all(|transaction| transaction.signing_hash() != first.signing_hash()));
+    }
+
+    #[test]
+    fn senders_must_afford_everything_pending() {
+        let keypair = Keypair::generate().unwrap();
+        let ledger = AccountLedger::new(&[(keypair.address(), 100)]).unwrap();
+        let mut mempool = Mempool::new(1 << 20);
+        mempool.insert(transfer(&keypair, 0, 10, 50), &ledger).unwrap();
+        assert!(mempool.insert(transfer(&keypair, 1, 10, 31), &ledger).is_err());
+        mempool.insert(transfer(&keypair, 1, 10, 30), &ledger).unwrap();
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn unfunded_transactions_dont_evict_anything() {
+        let rich = Keypair::generate().unwrap();
+        let poor = Keypair::generate().unwrap();
+        let ledger = AccountLedger::new(&[(rich.address(), 100)]).unwrap();
+        let cheap = transfer(&rich, 0, 1, 1);
+        let mut mempool = Mempool::new(cheap.size());
+        mempool.insert(cheap, &ledger).unwrap();
+        assert!(mempool.insert(transfer(&poor, 0, 50, 1), &ledger).is_err());
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn accepted_blocks_prune_what_can_no_longer_be_paid_for() {
+        let keypair = Keypair::generate().unwrap();
+        let mut ledger = AccountLedger::new(&[(keypair.address(), 100)]).unwrap();
+        let mut mempool = Mempool::new(1 << 20);
+        mempool.insert(transfer(&keypair, 0, 10, 50), &ledger).unwrap();
+        mempool.insert(transfer(&keypair, 1, 10, 30), &ledger).unwrap();
+
+        // someone else's block spends most of the balance with the same nonce
+        let block = block_with(&Block::genesis(ledger.state_root()), &ledger, vec![transfer(&keypair, 0, 0, 90)]);
+        ledger.apply_block(&block).unwrap();
+        mempool.remove_included(&[block], &ledger);
+        assert!(mempool.is_empty());
+        assert!(mempool.insert(transfer(&keypair, 0, 0, 1), &ledger).is_err());
+        mempool.insert(transfer(&keypair, 1, 0, 10), &ledger).unwrap();
+    }
+}
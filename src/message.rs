@@ -1,12 +1,95 @@
 use std::net::SocketAddr;
 
-use block::{Block};
+use block::{Block, Hash32Byte};
+use compression::Compression;
+use transaction::Transaction;
+use keys::Address;
+use kademlia::{Contact, Target};
+use state::AccountInfo;
+
+/// Most blocks a `Chain` message carries. Longer chains are sent a page
+/// at a time.
+pub const CHAIN_PAGE: usize = 512;
+
+/// Largest a serialized message between nodes may be, which leaves room
+/// for a full page of the chain.
+pub const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// A reply we're waiting on after asking a peer for something.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Reply {
+    /// The page of the chain starting at this height.
+    Chain(u64),
+    Account(Address),
+    Addr,
+    /// The contacts closest to the target, or the block if it's after one.
+    Lookup(Target),
+    Block(Hash32Byte),
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ClientMessage {
+    /// Sent first on every connection, listing the compression we'd like
+    /// frames sent to us with, favourite first, and the port we listen on,
+    /// so the peer can pass our address on.
+    Hello { compression: Vec<Compression>, port: u16 },
     NewBlock(Block),
-    QueryChain,
+    /// Announces blocks by hash, so peers only fetch the ones they lack.
+    Inv(Vec<Hash32Byte>),
+    /// Asks for the blocks with these hashes, which come back as `NewBlock`s.
+    GetData(Vec<Hash32Byte>),
+    /// Asks for a page of the peer's chain, starting at this height.
+    QueryChain(u64),
+    /// A page of the chain, as asked for with `QueryChain`. Only a full
+    /// page has more after it.
     Chain(Vec<Block>),
+    NewTransaction(Transaction),
+    QueryAccount(Address),
+    Account(AccountInfo),
+    /// A multisig transaction that may still need co-signers. Nodes collect
+    /// the signatures and pool it once there are enough.
+    PartialTransaction(Transaction),
+    /// Asks for addresses of other nodes.
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// Asks for the contacts the peer knows closest to a node id.
+    FindNode(Address),
+    /// Asks for the block with this hash, or failing that the contacts the
+    /// peer knows closest to it.
+    FindValue(Hash32Byte),
+    /// The contacts closest to what a `FindNode` or `FindValue` was after.
+    Nodes(Target, Vec<Contact>),
+    /// The block a `FindValue` was after.
+    Value(Block),
+}
+
+impl ClientMessage {
+    /// The replies asking this of a peer brings back.
+    pub fn replies(&self) -> Vec<Reply> {
+        match *self {
+            ClientMessage::QueryChain(start) => vec![Reply::Chain(start)],
+            ClientMessage::QueryAccount(address) => vec![Reply::Account(address)],
+            ClientMessage::GetAddr => vec![Reply::Addr],
+            ClientMessage::FindNode(id) => vec![Reply::Lookup(Target::Node(id))],
+            ClientMessage::FindValue(hash) => vec![Reply::Lookup(Target::Value(hash))],
+            ClientMessage::GetData(ref hashes) => hashes.iter().map(|&hash| Reply::Block(hash)).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Whether this is the `reply` we've been waiting on.
+    pub fn answers(&self, reply: &Reply) -> bool {
+        match (self, *reply) {
+            (&ClientMessage::Chain(ref page), Reply::Chain(start)) =>
+                page.first().is_none_or(|block| block.block_num == start),
+            (&ClientMessage::Account(ref info), Reply::Account(address)) => info.address == address,
+            (&ClientMessage::Addr(_), Reply::Addr) => true,
+            (&ClientMessage::Nodes(target, _), Reply::Lookup(wanted)) => target == wanted,
+            (&ClientMessage::Value(ref block), Reply::Lookup(wanted)) => Target::Value(block.hash) == wanted,
+            (&ClientMessage::NewBlock(ref block), Reply::Block(hash)) => block.hash == hash,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -21,3 +104,38 @@ pub enum NameserverToClientMessage {
     Peers(Vec<SocketAddr>),
     Ping,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bincode::serialized_size;
+    use keys::Keypair;
+    use transaction::TransactionKind;
+
+    #[test]
+    fn only_what_was_asked_for_answers() {
+        let block = Block::genesis(Hash32Byte([0; 32]));
+        let replies = ClientMessage::GetData(vec![block.hash]).replies();
+        assert_eq!(replies, vec![Reply::Block(block.hash)]);
+        assert!(ClientMessage::NewBlock(block.clone()).answers(&replies[0]));
+        assert!(!ClientMessage::Value(block.clone()).answers(&replies[0]));
+        assert!(!ClientMessage::Chain(vec![block.clone()]).answers(&replies[0]));
+
+        let lookup = ClientMessage::FindValue(block.hash).replies()[0];
+        assert!(ClientMessage::Value(block.clone()).answers(&lookup));
+        assert!(ClientMessage::Nodes(Target::Value(block.hash), Vec::new()).answers(&lookup));
+        assert!(!ClientMessage::Nodes(Target::Node(Address([0; 32])), Vec::new()).answers(&lookup));
+
+        let page = ClientMessage::QueryChain(1).replies()[0];
+        assert!(!ClientMessage::Chain(vec![block]).answers(&page));
+        assert!(ClientMessage::Chain(Vec::new()).answers(&page));
+        assert!(ClientMessage::NewTransaction(Transaction::new(&Keypair::generate().unwrap(), 0, 0,
+            TransactionKind::Data(Vec::new()))).replies().is_empty());
+    }
+
+    #[test]
+    fn a_full_page_of_the_chain_fits_in_a_message() {
+        let page = ClientMessage::Chain(vec![Block::genesis(Hash32Byte([0; 32])); CHAIN_PAGE]);
+        assert!(serialized_size(&page) as usize <= MAX_MESSAGE_SIZE);
+    }
+}
@@ -0,0 +1,116 @@
+//! The on-chain name registry. Accounts register human-readable names
+//! pointing at a value of their choosing, like a socket address, and must
+//! renew them before they expire or anyone can register them again.
+
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::BTreeMap;
+
+use block::Hash32Byte;
+use keys::Address;
+
+
+/// Blocks a registration or renewal lasts for.
+pub const NAME_LIFETIME: u64 = 1000;
+
+pub const MAX_NAME_LENGTH: usize = 64;
+pub const MAX_VALUE_LENGTH: usize = 128;
+
+/// Names are lowercase ASCII letters, digits, `-` and `.`, so two names that
+/// look the same are the same.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Names must be between 1 and {} characters", MAX_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.') {
+        return Err(format!("Invalid name {:?}", name));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub owner: Address,
+    pub value: String,
+    /// The first block the name is no longer registered in.
+    pub expires: u64,
+}
+
+/// Every registered name, including expired ones until they're registered
+/// again.
+#[derive(Clone, Default)]
+pub struct Names {
+    names: BTreeMap<String, Registration>,
+}
+
+impl Names {
+    /// A hash committing to every registration.
+    pub fn state_root(&self) -> Hash32Byte {
+        let mut sha = Sha256::new();
+        sha.input(&serialize(&self.names, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Registration> {
+        self.names.get(name)
+    }
+
+    /// The registration of `name` as of block `height`, if it hasn't
+    /// expired.
+    pub fn resolve(&self, name: &str, height: u64) -> Option<&Registration> {
+        self.names.get(name).filter(|registration| registration.expires > height)
+    }
+
+    /// Puts a name back the way it was, for undoing blocks.
+    pub fn restore(&mut self, name: String, registration: Option<Registration>) {
+        match registration {
+            Some(registration) => self.names.insert(name, registration),
+            None => self.names.remove(&name),
+        };
+    }
+
+    /// Registers `name` for `owner` in block `height`, unless someone
+    /// already holds it.
+    pub fn register(&mut self, name: &str, owner: Address, value: String, height: u64) -> Result<(), String> {
+        validate_name(name)?;
+        if value.len() > MAX_VALUE_LENGTH {
+            return Err(format!("Name values can be at most {} bytes", MAX_VALUE_LENGTH));
+        }
+        if let Some(registration) = self.resolve(name, height) {
+            return Err(format!("{} is registered to {} until block {}", name, registration.owner, registration.expires));
+        }
+        self.names.insert(name.to_string(), Registration { owner, value, expires: height + NAME_LIFETIME });
+        Ok(())
+    }
+
+    /// Extends `owner`'s registration of `name` to `NAME_LIFETIME` blocks
+    /// after `height`.
+    pub fn renew(&mut self, name: &str, owner: &Address, height: u64) -> Result<(), String> {
+        let registration = self.owned(name, owner, height)?;
+        registration.expires = height + NAME_LIFETIME;
+        Ok(())
+    }
+
+    /// Hands `name` over to `to`, keeping its value and expiry.
+    pub fn transfer(&mut self, name: &str, owner: &Address, to: Address, height: u64) -> Result<(), String> {
+        let registration = self.owned(name, owner, height)?;
+        registration.owner = to;
+        Ok(())
+    }
+
+    fn owned(&mut self, name: &str, owner: &Address, height: u64) -> Result<&mut Registration, String> {
+        match self.names.get_mut(name) {
+            Some(registration) if registration.expires > height => {
+                if registration.owner != *owner {
+                    return Err(format!("{} belongs to {}", name, registration.owner));
+                }
+                Ok(registration)
+            },
+            _ => Err(format!("{} isn't registered", name)),
+        }
+    }
+}
@@ -0,0 +1,555 @@
+//! The node's networking. One thread waits on every peer's socket at once
+//! and does all the reading and writing. Everything else talks to peers by
+//! queueing messages on them, which never waits on the network, and hears
+//! from them through `Event`s.
+
+use bincode::{serialize, Infinite};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::TcpStream;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use compression::Compression;
+use connection::{Connection, FrameReader, FrameWriter};
+use keys::Address;
+use message::{ClientMessage, Reply, MAX_MESSAGE_SIZE};
+use ratelimit::{Slot, TokenBucket};
+
+
+const WAKER: Token = Token(0);
+
+/// Most bytes that can wait to go to a peer. A peer that falls further
+/// behind than this is disconnected rather than buffered for without
+/// bound.
+pub const MAX_QUEUED_BYTES: usize = 16 * MAX_MESSAGE_SIZE;
+
+/// How much of the time a peer can be held back by its rate limits, and
+/// for how many seconds at a stretch, before it counts as flooding rather
+/// than just busy.
+const THROTTLED_SHARE: f64 = 0.5;
+const MAX_THROTTLED_SECONDS: f64 = 30.0;
+
+/// How long a reply we've asked a peer for is waited on. After that, the
+/// peer's held to its rate limits again.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How fast each peer may send to us. A peer over either limit isn't read
+/// from, or has anything it's sent decoded, until it's back under, so it
+/// has to slow down. Bytes count as they're read; those that turn out to be
+/// replies to what we've asked for are given back, and the peer isn't held
+/// up for its bytes while any of those are on their way.
+#[derive(Clone, Copy)]
+pub struct RateLimits {
+    pub messages_per_second: f64,
+    pub bytes_per_second: f64,
+}
+
+/// Which side opened a connection. Inbound connections hold a slot for as
+/// long as they last.
+pub enum Direction {
+    Outbound,
+    Inbound(Slot),
+}
+
+pub enum Event {
+    Connected(Arc<Peer>),
+    Message(Arc<Peer>, Box<ClientMessage>),
+    /// The peer sent something that isn't a message, so it's being dropped.
+    Malformed(Arc<Peer>, io::Error),
+    /// The peer keeps going over its rate limits.
+    RateLimited(Arc<Peer>),
+    Disconnected(Arc<Peer>),
+}
+
+/// What other threads ask of the network thread.
+enum Command {
+    Add(Arc<Peer>, std::net::TcpStream, FrameReader, Option<Slot>),
+    Flush(Token),
+    Close(Token),
+}
+
+struct Shared {
+    commands: Mutex<Sender<Command>>,
+    waker: Waker,
+    peers: Mutex<HashMap<Token, Arc<Peer>>>,
+    next_token: AtomicUsize,
+}
+
+impl Shared {
+    fn command(&self, command: Command) {
+        // the network thread only stops with the node
+        let _ = self.commands.lock().unwrap().send(command);
+        let _ = self.waker.wake();
+    }
+}
+
+/// A frame waiting to be written, with the slots held until it has been.
+struct Frame {
+    bytes: Vec<u8>,
+    _slots: Vec<Slot>,
+}
+
+/// Frames waiting to be written to a peer.
+struct Outbound {
+    frames: VecDeque<Frame>,
+    bytes: usize,
+}
+
+/// A message serialized once, to be sent to any number of peers.
+pub struct Serialized {
+    bytes: Vec<u8>,
+    replies: Vec<Reply>,
+}
+
+impl Serialized {
+    pub fn new(message: &ClientMessage) -> Serialized {
+        Serialized { bytes: serialize(message, Infinite).unwrap(), replies: message.replies() }
+    }
+}
+
+/// A connected peer. Messages sent to it are framed straight away, in the
+/// sending thread, and queued for the network thread to write.
+pub struct Peer {
+    token: Token,
+    addr: SocketAddr,
+    id: Option<Address>,
+    /// Whether we opened the connection.
+    dialed: bool,
+    writer: Mutex<FrameWriter>,
+    outbound: Mutex<Outbound>,
+    /// Replies we've asked the peer for and not had yet, oldest first, with
+    /// when we asked.
+    awaited: Mutex<VecDeque<(Reply, Instant)>>,
+    shared: Arc<Shared>,
+}
+
+impl Peer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The peer's node address, if the connection is secured.
+    pub fn id(&self) -> Option<Address> {
+        self.id
+    }
+
+    /// Whether we opened the connection.
+    pub fn is_outbound(&self) -> bool {
+        self.dialed
+    }
+
+    pub fn set_compression(&self, compression: Compression) {
+        self.writer.lock().unwrap().set_compression(compression);
+    }
+
+    pub fn send(&self, message: &ClientMessage) {
+        self.send_holding(message, Vec::new());
+    }
+
+    /// Sends `message`, holding on to `slots` until it's been written, so
+    /// they count how many costly replies are still on their way.
+    pub fn send_holding(&self, message: &ClientMessage, slots: Vec<Slot>) {
+        self.send_serialized(&Serialized::new(message), slots);
+    }
+
+    /// Sends a message that's already been serialized, holding on to
+    /// `slots` until it's been written.
+    pub fn send_serialized(&self, serialized: &Serialized, slots: Vec<Slot>) {
+        if !serialized.replies.is_empty() {
+            let now = Instant::now();
+            self.awaited(now).extend(serialized.replies.iter().map(|&reply| (reply, now)));
+        }
+        {
+            // framing encrypts, so frames must be queued in the order they're made
+            let mut writer = self.writer.lock().unwrap();
+            let frame = match writer.frame(&serialized.bytes) {
+                Ok(frame) => frame,
+                Err(_) => return self.disconnect(),
+            };
+            let mut outbound = self.outbound.lock().unwrap();
+            if outbound.bytes > MAX_QUEUED_BYTES {
+                drop(outbound);
+                return self.disconnect();
+            }
+            outbound.bytes += frame.len();
+            outbound.frames.push_back(Frame { bytes: frame, _slots: slots });
+        }
+        self.shared.command(Command::Flush(self.token));
+    }
+
+    pub fn disconnect(&self) {
+        self.shared.command(Command::Close(self.token));
+    }
+
+    /// The replies we're still waiting on at `now`, forgetting any that
+    /// have taken too long.
+    fn awaited(&self, now: Instant) -> MutexGuard<'_, VecDeque<(Reply, Instant)>> {
+        let mut awaited = self.awaited.lock().unwrap();
+        while awaited.front().is_some_and(|&(_, asked)| now.saturating_duration_since(asked) >= REPLY_TIMEOUT) {
+            awaited.pop_front();
+        }
+        awaited
+    }
+
+    /// Whether we're waiting on replies from the peer at `now`.
+    fn awaiting_replies(&self, now: Instant) -> bool {
+        !self.awaited(now).is_empty()
+    }
+
+    /// Whether `message` is a reply we asked for, crossing it off if so.
+    fn solicited(&self, message: &ClientMessage, now: Instant) -> bool {
+        let mut awaited = self.awaited(now);
+        match awaited.iter().position(|&(ref reply, _)| message.answers(reply)) {
+            Some(index) => {
+                awaited.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// A handle on the network thread, for adding peers and sending to them.
+#[derive(Clone)]
+pub struct Network {
+    shared: Arc<Shared>,
+}
+
+impl Network {
+    /// Starts the network thread. Everything peers say comes out of the
+    /// returned receiver, in the order it arrived from each peer.
+    pub fn start(limits: RateLimits) -> io::Result<(Network, Receiver<Event>)> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let (commands, command_receiver) = channel();
+        let (events, event_receiver) = channel();
+        let shared = Arc::new(Shared {
+            commands: Mutex::new(commands),
+            waker,
+            peers: Mutex::new(HashMap::new()),
+            next_token: AtomicUsize::new(1),
+        });
+        let mut event_loop = EventLoop {
+            poll,
+            sockets: HashMap::new(),
+            commands: command_receiver,
+            events,
+            shared: shared.clone(),
+            limits,
+        };
+        thread::spawn(move || event_loop.run());
+        Ok((Network { shared }, event_receiver))
+    }
+
+    /// Hands a connection over to the network thread. It can be sent to
+    /// straight away, though nothing is written until it's registered.
+    pub fn add(&self, connection: Connection, direction: Direction) -> io::Result<Arc<Peer>> {
+        let addr = connection.peer_addr()?;
+        let id = connection.peer_id();
+        let (dialed, slot) = match direction {
+            Direction::Outbound => (true, None),
+            Direction::Inbound(slot) => (false, Some(slot)),
+        };
+        let (stream, reader, writer) = connection.into_parts();
+        stream.set_nonblocking(true)?;
+        let peer = Arc::new(Peer {
+            token: Token(self.shared.next_token.fetch_add(1, Ordering::Relaxed)),
+            addr,
+            id,
+            dialed,
+            writer: Mutex::new(writer),
+            outbound: Mutex::new(Outbound { frames: VecDeque::new(), bytes: 0 }),
+            awaited: Mutex::new(VecDeque::new()),
+            shared: self.shared.clone(),
+        });
+        self.shared.peers.lock().unwrap().insert(peer.token, peer.clone());
+        self.shared.command(Command::Add(peer.clone(), stream, reader, slot));
+        Ok(peer)
+    }
+
+    pub fn peers(&self) -> Vec<Arc<Peer>> {
+        self.shared.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Sends `message` to every peer, serializing it just once.
+    pub fn broadcast(&self, message: &ClientMessage) {
+        let serialized = Serialized::new(message);
+        for peer in self.peers() {
+            peer.send_serialized(&serialized, Vec::new());
+        }
+    }
+
+    /// Sends `message` to every peer but the one at `except`.
+    pub fn broadcast_except(&self, message: &ClientMessage, except: SocketAddr) {
+        let serialized = Serialized::new(message);
+        for peer in self.peers().into_iter().filter(|peer| peer.addr() != except) {
+            peer.send_serialized(&serialized, Vec::new());
+        }
+    }
+}
+
+/// A peer's socket, owned by the network thread.
+struct Socket {
+    stream: TcpStream,
+    reader: FrameReader,
+    peer: Arc<Peer>,
+    /// How much of the first queued frame has been written.
+    written: usize,
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    /// How long the peer's been held back by its rate limits, in seconds.
+    throttled: TokenBucket,
+    /// When to start reading from the peer again, if it's over its limits.
+    paused: Option<Instant>,
+    _slot: Option<Slot>,
+}
+
+impl Socket {
+    /// Reads everything the peer has sent so far, passing on each whole
+    /// message, unless the peer's over its rate limits. False once the peer
+    /// should be dropped.
+    fn read(&mut self, events: &Sender<Event>, now: Instant) -> bool {
+        if self.paused.is_some_and(|until| until > now) {
+            return true;
+        }
+        self.paused = None;
+        loop {
+            // before anything's done with what the peer's sent, so a peer
+            // over its limits costs us nothing but the buffer it's filling
+            let bytes_wait = if self.peer.awaiting_replies(now) { None } else { self.bytes.wait(now) };
+            if let Some(wait) = self.messages.wait(now).max(bytes_wait) {
+                self.paused = Some(now + wait);
+                if !self.throttled.take(wait.as_secs_f64(), now) {
+                    let _ = events.send(Event::RateLimited(self.peer.clone()));
+                }
+                return true;
+            }
+            let buffered = self.reader.buffered();
+            // nothing a peer sends may take the event loop down with it
+            let next = panic::catch_unwind(AssertUnwindSafe(|| self.reader.next_message()))
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::InvalidData, "Message couldn't be decoded")));
+            match next {
+                Ok(Some(message)) => {
+                    if self.peer.solicited(&message, now) {
+                        self.bytes.refund((buffered - self.reader.buffered()) as f64, now);
+                    } else {
+                        self.messages.spend(1.0, now);
+                    }
+                    let _ = events.send(Event::Message(self.peer.clone(), Box::new(message)));
+                    continue;
+                },
+                Ok(None) => {},
+                Err(e) => {
+                    let _ = events.send(Event::Malformed(self.peer.clone(), e));
+                    return false;
+                },
+            }
+            match self.reader.read_from(&mut self.stream) {
+                Ok(0) => return false,
+                Ok(read) => self.bytes.spend(read as f64, now),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Writes queued frames until they're all gone or the socket is full.
+    /// False once the peer should be dropped.
+    fn flush(&mut self) -> bool {
+        let mut outbound = self.peer.outbound.lock().unwrap();
+        while let Some(frame) = outbound.frames.pop_front() {
+            match self.stream.write(&frame.bytes[self.written..]) {
+                Ok(0) => return false,
+                Ok(n) if self.written + n < frame.bytes.len() => {
+                    self.written += n;
+                    outbound.frames.push_front(frame);
+                },
+                Ok(_) => {
+                    self.written = 0;
+                    outbound.bytes -= frame.bytes.len();
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // we'll hear when there's room again
+                    outbound.frames.push_front(frame);
+                    return true;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => outbound.frames.push_front(frame),
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+}
+
+struct EventLoop {
+    poll: Poll,
+    sockets: HashMap<Token, Socket>,
+    commands: Receiver<Command>,
+    events: Sender<Event>,
+    shared: Arc<Shared>,
+    limits: RateLimits,
+}
+
+impl EventLoop {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            // wake in time to go back to reading from paused peers
+            let now = Instant::now();
+            let timeout = self.sockets.values()
+                .filter_map(|socket| socket.paused)
+                .min()
+                .map(|until| until.saturating_duration_since(now));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("Couldn't wait for peers: {}", e);
+            }
+            let now = Instant::now();
+            for event in events.iter() {
+                let token = event.token();
+                let keep = match self.sockets.get_mut(&token) {
+                    Some(socket) => (!event.is_readable() || socket.read(&self.events, now))
+                        && (!event.is_writable() || socket.flush()),
+                    None => true,
+                };
+                if !keep {
+                    self.close(token);
+                }
+            }
+            // readiness doesn't come again for what's already waiting, so
+            // paused peers are read from once they're due
+            let due: Vec<Token> = self.sockets.iter()
+                .filter(|&(_, socket)| socket.paused.is_some_and(|until| until <= now))
+                .map(|(&token, _)| token)
+                .collect();
+            for token in due {
+                let events = &self.events;
+                let keep = self.sockets.get_mut(&token).is_none_or(|socket| socket.read(events, now));
+                if !keep {
+                    self.close(token);
+                }
+            }
+            while let Ok(command) = self.commands.try_recv() {
+                match command {
+                    Command::Add(peer, stream, reader, slot) => self.register(peer, stream, reader, slot),
+                    Command::Flush(token) => {
+                        let keep = self.sockets.get_mut(&token).is_none_or(|socket| socket.flush());
+                        if !keep {
+                            self.close(token);
+                        }
+                    },
+                    Command::Close(token) => self.close(token),
+                }
+            }
+        }
+    }
+
+    fn register(&mut self, peer: Arc<Peer>, stream: std::net::TcpStream, reader: FrameReader, slot: Option<Slot>) {
+        let token = peer.token;
+        let mut stream = TcpStream::from_std(stream);
+        // both at once: readiness only comes when it changes, so there's
+        // nothing to spin on while the peer has nothing to say
+        if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+            println!("Couldn't watch {}: {}", peer.addr, e);
+            self.shared.peers.lock().unwrap().remove(&token);
+            return;
+        }
+        let _ = self.events.send(Event::Connected(peer.clone()));
+        let now = Instant::now();
+        self.sockets.insert(token, Socket {
+            stream,
+            reader,
+            peer,
+            written: 0,
+            messages: TokenBucket::new(self.limits.messages_per_second, now),
+            bytes: TokenBucket::new(self.limits.bytes_per_second, now),
+            throttled: TokenBucket::with_capacity(THROTTLED_SHARE, MAX_THROTTLED_SECONDS, now),
+            paused: None,
+            _slot: slot,
+        });
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut socket) = self.sockets.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut socket.stream);
+            self.shared.peers.lock().unwrap().remove(&token);
+            let _ = self.events.send(Event::Disconnected(socket.peer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use block::{Block, Hash32Byte};
+    use std::net::TcpListener;
+
+    const LIMITS: RateLimits = RateLimits { messages_per_second: 100.0, bytes_per_second: 1e6 };
+
+    /// A peer on a network of its own, and the other end of its connection.
+    fn connected() -> (Network, Receiver<Event>, Arc<Peer>, Connection) {
+        let (network, events) = Network::start(LIMITS).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let theirs = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (ours, _) = listener.accept().unwrap();
+        let peer = network.add(Connection::new(ours), Direction::Outbound).unwrap();
+        (network, events, peer, Connection::new(theirs))
+    }
+
+    #[test]
+    fn messages_go_both_ways() {
+        let (_network, events, peer, mut theirs) = connected();
+        match events.recv().unwrap() {
+            Event::Connected(connected) => assert_eq!(connected.addr(), peer.addr()),
+            _ => panic!("Expected the peer to connect"),
+        }
+        peer.send(&ClientMessage::GetAddr);
+        match theirs.read_message::<ClientMessage>().unwrap() {
+            Some(ClientMessage::GetAddr) => {},
+            other => panic!("Expected GetAddr, got {:?}", other),
+        }
+        theirs.write_message(&ClientMessage::Addr(Vec::new())).unwrap();
+        match events.recv().unwrap() {
+            Event::Message(_, message) => assert!(matches!(*message, ClientMessage::Addr(_))),
+            _ => panic!("Expected a message"),
+        }
+        drop(theirs);
+        assert!(matches!(events.recv().unwrap(), Event::Disconnected(_)));
+    }
+
+    #[test]
+    fn only_replies_asked_for_are_solicited() {
+        let (_network, _events, peer, _theirs) = connected();
+        let now = Instant::now();
+        let block = Block::genesis(Hash32Byte([0; 32]));
+        assert!(!peer.awaiting_replies(now));
+        peer.send(&ClientMessage::GetData(vec![block.hash]));
+        assert!(peer.awaiting_replies(now));
+        assert!(!peer.solicited(&ClientMessage::Addr(Vec::new()), now));
+        assert!(!peer.solicited(&ClientMessage::Value(block.clone()), now));
+        assert!(peer.solicited(&ClientMessage::NewBlock(block.clone()), now));
+        assert!(!peer.solicited(&ClientMessage::NewBlock(block), now));
+        assert!(!peer.awaiting_replies(now));
+    }
+
+    #[test]
+    fn awaited_replies_expire() {
+        let (_network, _events, peer, _theirs) = connected();
+        peer.send(&ClientMessage::GetAddr);
+        let later = Instant::now() + REPLY_TIMEOUT;
+        assert!(!peer.awaiting_replies(later));
+        assert!(!peer.solicited(&ClientMessage::Addr(Vec::new()), later));
+    }
+}
@@ -0,0 +1,261 @@
+//! Notarization: proving a document existed by the time some block was
+//! made. The document's SHA-256 digest goes on the chain in a `Notarize`
+//! transaction, and a receipt ties that transaction to its block with a
+//! merkle branch, so it can be checked against a copy of the chain without
+//! trusting whoever handed out the receipt.
+
+use base64;
+use bincode::{serialize, deserialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use block::{Block, Hash32Byte, check_chain};
+use transaction::{Transaction, TransactionKind, block_transactions};
+
+
+/// The SHA-256 digest of a file's contents.
+pub fn digest_file(path: &Path) -> io::Result<Hash32Byte> {
+    let mut file = File::open(path)?;
+    let mut sha = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        sha.input(&buf[..read]);
+    }
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    Ok(Hash32Byte(output))
+}
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner
+// node can never pass for a leaf.
+fn hash_leaf(leaf: &Hash32Byte) -> Hash32Byte {
+    let mut sha = Sha256::new();
+    sha.input(&[0]);
+    sha.input(&leaf.0);
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    Hash32Byte(output)
+}
+
+fn hash_pair(left: &Hash32Byte, right: &Hash32Byte) -> Hash32Byte {
+    let mut sha = Sha256::new();
+    sha.input(&[1]);
+    sha.input(&left.0);
+    sha.input(&right.0);
+
+    let mut output = [0; 32];
+    sha.result(&mut output);
+    Hash32Byte(output)
+}
+
+/// Hashes one level of a merkle tree into the next. If there's an odd
+/// number, the last hash moves up as it is: pairing it with itself would
+/// give the same root as a list with the last hash repeated.
+fn merkle_level(hashes: &[Hash32Byte]) -> Vec<Hash32Byte> {
+    hashes.chunks(2)
+        .map(|pair| if pair.len() == 2 { hash_pair(&pair[0], &pair[1]) } else { pair[0] })
+        .collect()
+}
+
+/// The root of the merkle tree over `hashes`, or all zeroes if there are
+/// none.
+pub fn merkle_root(hashes: &[Hash32Byte]) -> Hash32Byte {
+    let mut level: Vec<Hash32Byte> = hashes.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = merkle_level(&level);
+    }
+    level.first().cloned().unwrap_or(Hash32Byte([0; 32]))
+}
+
+/// The siblings on the path from `hashes[index]` up to the root. Levels
+/// where it has no sibling are skipped.
+pub fn merkle_branch(hashes: &[Hash32Byte], mut index: usize) -> Vec<Hash32Byte> {
+    let mut level: Vec<Hash32Byte> = hashes.iter().map(hash_leaf).collect();
+    let mut branch = Vec::new();
+    while level.len() > 1 {
+        if let Some(sibling) = level.get(index ^ 1) {
+            branch.push(*sibling);
+        }
+        level = merkle_level(&level);
+        index /= 2;
+    }
+    branch
+}
+
+/// Works out the root from the leaf at `index` of `count` and its
+/// `branch`, or `None` if the branch doesn't fit a tree that size.
+pub fn branch_root(leaf: Hash32Byte, mut index: usize, mut count: usize, branch: &[Hash32Byte]) -> Option<Hash32Byte> {
+    if index >= count {
+        return None;
+    }
+    let mut hash = hash_leaf(&leaf);
+    let mut siblings = branch.iter();
+    while count > 1 {
+        if index ^ 1 < count {
+            let sibling = siblings.next()?;
+            hash = if index.is_multiple_of(2) { hash_pair(&hash, sibling) } else { hash_pair(sibling, &hash) };
+        }
+        index /= 2;
+        count = count.div_ceil(2);
+    }
+    match siblings.next() {
+        Some(_) => None,
+        None => Some(hash),
+    }
+}
+
+/// Proof that `digest` was notarized in block `height`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub digest: Hash32Byte,
+    pub block_hash: Hash32Byte,
+    pub height: u64,
+    /// The block's timestamp, in ns.
+    pub timestamp: u64,
+    /// The transaction carrying the digest.
+    pub transaction: Transaction,
+    /// Its position among the block's transactions.
+    pub index: u32,
+    /// How many transactions the block has, which fixes the shape of the
+    /// tree the branch climbs.
+    pub count: u32,
+    /// Merkle branch from the transaction's hash to the root of the block's
+    /// transaction hashes, which the block's header commits to.
+    pub branch: Vec<Hash32Byte>,
+}
+
+impl Receipt {
+    /// Looks for the earliest block in `chain` notarizing `digest`.
+    pub fn find(chain: &[Block], digest: &Hash32Byte) -> Option<Receipt> {
+        chain.iter().filter_map(|block| {
+            let transactions = block_transactions(block);
+            let index = transactions.iter().position(|transaction| transaction.kind == TransactionKind::Notarize(*digest))?;
+            let hashes: Vec<Hash32Byte> = transactions.iter().map(Transaction::hash).collect();
+            Some(Receipt {
+                digest: *digest,
+                block_hash: block.hash,
+                height: block.block_num,
+                timestamp: block.timestamp,
+                transaction: transactions[index].clone(),
+                index: index as u32,
+                count: hashes.len() as u32,
+                branch: merkle_branch(&hashes, index),
+            })
+        }).next()
+    }
+
+    /// Checks the receipt against `chain`, which must be a valid chain
+    /// containing the receipt's block.
+    pub fn verify(&self, chain: &[Block]) -> Result<(), String> {
+        if !check_chain(chain) {
+            return Err("The chain is invalid".to_string());
+        }
+        let block = match chain.get(self.height as usize) {
+            Some(block) if block.hash == self.block_hash => block,
+            Some(_) => return Err(format!("Block {} isn't the one in the receipt", self.height)),
+            None => return Err(format!("The chain doesn't have block {} yet", self.height)),
+        };
+        if block.timestamp != self.timestamp {
+            return Err("The block's timestamp doesn't match the receipt".to_string());
+        }
+        if self.transaction.kind != TransactionKind::Notarize(self.digest) {
+            return Err("The receipt's transaction doesn't notarize its digest".to_string());
+        }
+
+        match branch_root(self.transaction.hash(), self.index as usize, self.count as usize, &self.branch) {
+            Some(root) if root == block.transactions_root => Ok(()),
+            _ => Err("The inclusion proof doesn't match the block".to_string()),
+        }
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&serialize(self, Infinite).unwrap())
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Receipt, String> {
+        let bytes = base64::decode(encoded.trim()).map_err(|e| e.to_string())?;
+        deserialize(&bytes).map_err(|_| "Not a receipt".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+    use transaction::{BlockBody, pack_block};
+
+    fn leaves(count: u8) -> Vec<Hash32Byte> {
+        (0..count).map(|i| Hash32Byte([i; 32])).collect()
+    }
+
+    #[test]
+    fn every_leaf_climbs_to_the_root() {
+        for &count in &[1, 2, 3, 5] {
+            let hashes = leaves(count);
+            let root = merkle_root(&hashes);
+            for (index, leaf) in hashes.iter().enumerate() {
+                let branch = merkle_branch(&hashes, index);
+                assert_eq!(branch_root(*leaf, index, hashes.len(), &branch), Some(root));
+                // the branch only fits the leaf it was made for
+                assert_ne!(branch_root(Hash32Byte([9; 32]), index, hashes.len(), &branch), Some(root));
+                assert_eq!(branch_root(*leaf, hashes.len(), hashes.len(), &branch), None);
+            }
+        }
+        assert_eq!(merkle_branch(&leaves(1), 0), Vec::new());
+        assert_eq!(merkle_branch(&leaves(5), 4).len(), 1);
+    }
+
+    #[test]
+    fn a_repeated_last_leaf_changes_the_root() {
+        let mut hashes = leaves(3);
+        let root = merkle_root(&hashes);
+        hashes.push(hashes[2]);
+        assert_ne!(merkle_root(&hashes), root);
+    }
+
+    #[test]
+    fn receipts_check_out_only_against_the_block_they_came_from() {
+        let keypair = Keypair::generate().unwrap();
+        let digest = Hash32Byte([7; 32]);
+        let transactions: Vec<Transaction> = [Hash32Byte([1; 32]), Hash32Byte([2; 32]), digest].iter()
+            .enumerate()
+            .map(|(nonce, digest)| Transaction::new(&keypair, nonce as u64, 0, TransactionKind::Notarize(*digest)))
+            .collect();
+        let block_with = |genesis: &Block, transactions: Vec<Transaction>| {
+            let body = BlockBody { reward: None, transactions };
+            Block::new(genesis, 1, Hash32Byte([0; 32]), body.transactions_root(), pack_block(&body).unwrap())
+        };
+        let genesis = Block::genesis(Hash32Byte([0; 32]));
+        let chain = vec![genesis.clone(), block_with(&genesis, transactions.clone())];
+
+        let receipt = Receipt::find(&chain, &digest).unwrap();
+        assert_eq!(receipt.index, 2);
+        assert_eq!(receipt.count, 3);
+        receipt.verify(&chain).unwrap();
+        assert_eq!(Receipt::from_base64(&receipt.to_base64()), Ok(receipt.clone()));
+
+        let mut tampered = receipt.clone();
+        tampered.branch[0].0[0] ^= 1;
+        assert!(tampered.verify(&chain).is_err());
+        let mut tampered = receipt.clone();
+        tampered.index = 1;
+        assert!(tampered.verify(&chain).is_err());
+
+        // a chain whose block commits to other transactions
+        let fork = vec![genesis.clone(), block_with(&genesis, transactions[..2].to_vec())];
+        assert!(receipt.verify(&fork).is_err());
+        let mut forged = receipt.clone();
+        forged.block_hash = fork[1].hash;
+        assert!(forged.verify(&fork).is_err());
+    }
+}
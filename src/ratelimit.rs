@@ -0,0 +1,180 @@
+//! Limits on what peers can make us do: token buckets for how fast they
+//! can send, and slots for how many costly things can be going on at once.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+
+/// Holds up to `capacity` tokens, a second's worth unless said otherwise,
+/// refilled at a steady rate.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilled with `rate` tokens a second.
+    pub fn new(rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket::with_capacity(rate, rate, now)
+    }
+
+    /// A full bucket holding up to `capacity` tokens, refilled with `rate`
+    /// tokens a second.
+    pub fn with_capacity(rate: f64, capacity: f64, now: Instant) -> TokenBucket {
+        TokenBucket { rate, capacity, tokens: capacity, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated);
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+    }
+
+    /// Takes `amount` tokens, unless the bucket's empty. The bucket can go
+    /// into debt, so something bigger than its capacity still gets through
+    /// once, but then has to be paid back before anything else.
+    pub fn take(&mut self, amount: f64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+
+    /// Takes `amount` tokens whether or not there are any, for something
+    /// that's already happened.
+    pub fn spend(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens -= amount;
+    }
+
+    /// Gives back `amount` tokens spent on something that turned out not to
+    /// count, though never more than the bucket holds.
+    pub fn refund(&mut self, amount: f64, now: Instant) {
+        self.refill(now);
+        self.tokens = (self.tokens + amount).min(self.capacity);
+    }
+
+    /// How long until the bucket has tokens again, or `None` if it has some
+    /// now.
+    pub fn wait(&mut self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        if self.tokens > 0.0 {
+            return None;
+        }
+        // a touch over, so the wait is never spent for nothing
+        Some(Duration::from_secs_f64(-self.tokens / self.rate) + Duration::from_millis(1))
+    }
+}
+
+/// A fixed number of slots, each held by something costly while it's going
+/// on.
+#[derive(Clone)]
+pub struct Slots {
+    in_use: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl Slots {
+    pub fn new(limit: usize) -> Slots {
+        Slots { in_use: Arc::new(AtomicUsize::new(0)), limit }
+    }
+
+    /// A free slot, held until it's dropped.
+    pub fn acquire(&self) -> Option<Slot> {
+        let mut in_use = self.in_use.load(Ordering::SeqCst);
+        loop {
+            if in_use >= self.limit {
+                return None;
+            }
+            match self.in_use.compare_exchange(in_use, in_use + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(Slot(self.in_use.clone())),
+                Err(actual) => in_use = actual,
+            }
+        }
+    }
+}
+
+pub struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start);
+        for _ in 0..10 {
+            assert!(bucket.take(1.0, start));
+        }
+        assert!(!bucket.take(1.0, start));
+        assert!(bucket.take(1.0, start + ms(100)));
+        assert!(!bucket.take(1.0, start + ms(100)));
+        // a long wait still only fills it once
+        let later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(bucket.take(1.0, later));
+        }
+        assert!(!bucket.take(1.0, later));
+    }
+
+    #[test]
+    fn bucket_debt_has_to_be_paid_back() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, start);
+        assert!(bucket.take(30.0, start));
+        assert!(!bucket.take(1.0, start + ms(1000)));
+        let wait = bucket.wait(start + ms(1000)).unwrap();
+        assert!(wait > ms(1000) && wait < ms(1100));
+        assert!(bucket.wait(start + ms(2000) + ms(10)).is_none());
+    }
+
+    #[test]
+    fn spending_goes_into_debt_even_when_empty() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::with_capacity(1.0, 5.0, start);
+        assert!(bucket.wait(start).is_none());
+        bucket.spend(5.0, start);
+        bucket.spend(2.0, start);
+        let wait = bucket.wait(start).unwrap();
+        assert!(wait > ms(2000) && wait < ms(2100));
+        assert!(bucket.wait(start + wait).is_none());
+    }
+
+    #[test]
+    fn refunds_pay_off_debt_but_dont_overfill() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::with_capacity(1.0, 5.0, start);
+        bucket.spend(8.0, start);
+        bucket.refund(6.0, start);
+        assert!(bucket.wait(start).is_none());
+        bucket.refund(100.0, start);
+        bucket.spend(5.0, start);
+        assert!(bucket.wait(start).is_some());
+    }
+
+    #[test]
+    fn slots_free_up_when_dropped() {
+        let slots = Slots::new(2);
+        let first = slots.acquire().unwrap();
+        let _second = slots.clone().acquire().unwrap();
+        assert!(slots.acquire().is_none());
+        drop(first);
+        assert!(slots.acquire().is_some());
+    }
+}
@@ -0,0 +1,298 @@
+//! Keeping track of misbehaving peers. Each kind of misbehavior costs a
+//! peer some points, which wear off over time, and a peer that runs up
+//! `BAN_THRESHOLD` of them is banned for a while. Peers are scored by their
+//! node address when the connection is secured, and by their IP otherwise.
+//! Since a node address is just a key the peer made up, bans cover the IP
+//! too.
+
+use bincode::{serialize, deserialize, Infinite};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use time;
+
+use atomic::write_atomically;
+use keys::Address;
+
+
+/// Points at which a peer is banned.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// How many seconds it takes a point of a peer's score to wear off, so
+/// that only misbehaving steadily gets a peer banned.
+pub const POINT_DECAY_SECONDS: u64 = 60;
+
+/// How long bans last, unless the node says otherwise.
+pub const DEFAULT_BAN_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Misbehavior {
+    /// A chain that doesn't check out or that our ledger rejects.
+    InvalidChain,
+    /// A block that doesn't check out or that our ledger rejects.
+    InvalidBlock,
+    /// A frame that can't be decrypted, decompressed or deserialized. Could
+    /// just be a peer running another version, so it isn't an instant ban.
+    MalformedFrame,
+    /// Chains, blocks, addresses or account info we didn't ask for.
+    Unsolicited,
+    /// Running into its rate limits again and again.
+    Flooding,
+}
+
+impl Misbehavior {
+    pub fn penalty(self) -> u32 {
+        match self {
+            Misbehavior::InvalidChain => 50,
+            Misbehavior::InvalidBlock => 50,
+            Misbehavior::MalformedFrame => 20,
+            Misbehavior::Unsolicited => 10,
+            Misbehavior::Flooding => 10,
+        }
+    }
+}
+
+/// Who a score or ban applies to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum PeerKey {
+    Node(Address),
+    Ip(IpAddr),
+}
+
+impl PeerKey {
+    pub fn new(addr: SocketAddr, id: Option<Address>) -> PeerKey {
+        match id {
+            Some(id) => PeerKey::Node(id),
+            None => PeerKey::Ip(addr.ip()),
+        }
+    }
+
+    /// Parses a node address or an IP.
+    pub fn parse(s: &str) -> Result<PeerKey, String> {
+        match s.parse::<IpAddr>() {
+            Ok(ip) => Ok(PeerKey::Ip(ip)),
+            Err(_) => Address::from_hex(s).map(PeerKey::Node)
+                .map_err(|_| format!("{} is neither a node address nor an IP", s)),
+        }
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerKey::Node(ref address) => write!(f, "{}", address),
+            PeerKey::Ip(ref ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Score {
+    points: u32,
+    /// When the points were last worn down, in seconds since the epoch.
+    updated: u64,
+}
+
+impl Score {
+    /// The score at `now`, after some points have worn off.
+    fn at(self, now: u64) -> Score {
+        let worn = now.saturating_sub(self.updated) / POINT_DECAY_SECONDS;
+        Score {
+            points: self.points.saturating_sub(worn.min(u32::MAX as u64) as u32),
+            // keep what's left over towards the next point
+            updated: self.updated + worn * POINT_DECAY_SECONDS,
+        }
+    }
+}
+
+pub struct Reputation {
+    scores: HashMap<PeerKey, Score>,
+    /// When each ban ends, in seconds since the epoch.
+    bans: BTreeMap<PeerKey, u64>,
+    ban_seconds: u64,
+    /// Where the bans are saved, so they outlast the node.
+    path: Option<PathBuf>,
+}
+
+impl Reputation {
+    pub fn new(ban_seconds: u64) -> Reputation {
+        Reputation { scores: HashMap::new(), bans: BTreeMap::new(), ban_seconds, path: None }
+    }
+
+    /// Loads the bans saved at `path`, if there are any, and saves changes
+    /// to them there.
+    pub fn load(path: &Path, ban_seconds: u64) -> io::Result<Reputation> {
+        let mut reputation = Reputation::new(ban_seconds);
+        reputation.path = Some(path.to_path_buf());
+        match File::open(path) {
+            Ok(mut file) => {
+                let mut serialized = Vec::new();
+                file.read_to_end(&mut serialized)?;
+                reputation.bans = deserialize(&serialized)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Not a ban list"))?;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(reputation)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        match self.path {
+            Some(ref path) => write_atomically(path, &serialize(&self.bans, Infinite).unwrap()),
+            None => Ok(()),
+        }
+    }
+
+    /// Charges the peer at `addr` with node address `id` for
+    /// `misbehavior`, banning its IP and node address if that takes it over
+    /// the threshold. Returns whether that got it banned; peers already
+    /// banned aren't charged again.
+    pub fn misbehaved(&mut self, addr: SocketAddr, id: Option<Address>, misbehavior: Misbehavior) -> io::Result<bool> {
+        self.misbehaved_at(addr, id, misbehavior, now())
+    }
+
+    fn misbehaved_at(&mut self, addr: SocketAddr, id: Option<Address>, misbehavior: Misbehavior,
+                     now: u64) -> io::Result<bool> {
+        if !self.allows_at(addr, id, now) {
+            return Ok(false);
+        }
+        self.scores.retain(|_, score| {
+            *score = score.at(now);
+            score.points > 0
+        });
+        let key = PeerKey::new(addr, id);
+        let points = {
+            let score = self.scores.entry(key).or_insert(Score { points: 0, updated: now });
+            score.points = score.points.saturating_add(misbehavior.penalty());
+            score.points
+        };
+        if points < BAN_THRESHOLD {
+            return Ok(false);
+        }
+        self.scores.remove(&key);
+        self.bans.retain(|_, &mut until| until > now);
+        self.bans.insert(key, now + self.ban_seconds);
+        self.bans.insert(PeerKey::Ip(addr.ip()), now + self.ban_seconds);
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn is_banned(&self, key: &PeerKey) -> bool {
+        self.is_banned_at(key, now())
+    }
+
+    fn is_banned_at(&self, key: &PeerKey, now: u64) -> bool {
+        self.bans.get(key).is_some_and(|&until| until > now)
+    }
+
+    /// Whether a peer at `addr` with node address `id` may connect.
+    pub fn allows(&self, addr: SocketAddr, id: Option<Address>) -> bool {
+        self.allows_at(addr, id, now())
+    }
+
+    fn allows_at(&self, addr: SocketAddr, id: Option<Address>, now: u64) -> bool {
+        !self.is_banned_at(&PeerKey::Ip(addr.ip()), now)
+            && !id.is_some_and(|id| self.is_banned_at(&PeerKey::Node(id), now))
+    }
+
+    /// Bans that haven't run out, with when they end.
+    pub fn bans(&self) -> Vec<(PeerKey, u64)> {
+        let now = now();
+        self.bans.iter().filter(|&(_, &until)| until > now).map(|(key, until)| (*key, *until)).collect()
+    }
+
+    /// Lifts the ban on `key`, returning whether there was one.
+    pub fn unban(&mut self, key: &PeerKey) -> io::Result<bool> {
+        if self.bans.remove(key).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], 9000))
+    }
+
+    fn id(byte: u8) -> Option<Address> {
+        Some(Address([byte; 32]))
+    }
+
+    const START: u64 = 1_000_000;
+
+    #[test]
+    fn running_up_the_threshold_bans_node_and_ip() {
+        let mut reputation = Reputation::new(DEFAULT_BAN_SECONDS);
+        assert!(!reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidBlock, START).unwrap());
+        assert!(reputation.allows_at(addr(1), id(1), START));
+        assert!(reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, START).unwrap());
+        assert!(!reputation.allows_at(addr(1), id(1), START));
+        // a fresh key doesn't get around it, and neither does another port
+        assert!(!reputation.allows_at(addr(1), id(2), START));
+        assert!(!reputation.allows_at(SocketAddr::from(([10, 0, 0, 1], 9001)), None, START));
+        assert!(reputation.allows_at(addr(2), id(2), START));
+        // banned peers aren't charged again
+        assert!(!reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, START).unwrap());
+    }
+
+    #[test]
+    fn bans_run_out() {
+        let mut reputation = Reputation::new(60);
+        reputation.misbehaved_at(addr(1), None, Misbehavior::InvalidChain, START).unwrap();
+        assert!(reputation.misbehaved_at(addr(1), None, Misbehavior::InvalidChain, START).unwrap());
+        assert!(!reputation.allows_at(addr(1), None, START + 59));
+        assert!(reputation.allows_at(addr(1), None, START + 60));
+    }
+
+    #[test]
+    fn scores_wear_off() {
+        let mut reputation = Reputation::new(DEFAULT_BAN_SECONDS);
+        reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, START).unwrap();
+        let later = START + 10 * POINT_DECAY_SECONDS;
+        assert!(!reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, later).unwrap());
+        assert_eq!(reputation.scores[&PeerKey::Node(Address([1; 32]))].points, 90);
+        // scores that have worn off entirely are forgotten
+        reputation.misbehaved_at(addr(2), id(2), Misbehavior::Flooding, later + 100 * POINT_DECAY_SECONDS).unwrap();
+        assert_eq!(reputation.scores.len(), 1);
+    }
+
+    #[test]
+    fn partial_points_carry_over() {
+        let score = Score { points: 10, updated: START };
+        let worn = score.at(START + POINT_DECAY_SECONDS * 3 / 2);
+        assert_eq!(worn.points, 9);
+        assert_eq!(worn.at(START + POINT_DECAY_SECONDS * 2).points, 8);
+    }
+
+    #[test]
+    fn a_malformed_frame_alone_isnt_a_ban() {
+        let mut reputation = Reputation::new(DEFAULT_BAN_SECONDS);
+        assert!(!reputation.misbehaved_at(addr(1), id(1), Misbehavior::MalformedFrame, START).unwrap());
+        assert!(reputation.allows_at(addr(1), id(1), START));
+    }
+
+    #[test]
+    fn unbanning_lifts_just_that_ban() {
+        let mut reputation = Reputation::new(DEFAULT_BAN_SECONDS);
+        reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, START).unwrap();
+        reputation.misbehaved_at(addr(1), id(1), Misbehavior::InvalidChain, START).unwrap();
+        assert!(reputation.unban(&PeerKey::Ip(addr(1).ip())).unwrap());
+        assert!(!reputation.unban(&PeerKey::Ip(addr(1).ip())).unwrap());
+        assert!(reputation.allows_at(addr(1), id(2), START));
+        assert!(!reputation.allows_at(addr(1), id(1), START));
+    }
+}
@@ -0,0 +1,327 @@
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fmt;
+
+use keys::{Address, Signature, verify};
+use transaction::TimeLock;
+
+
+/// Most a script may cost to run, counting `Op::cost` for every op executed.
+pub const MAX_COST: u32 = 1000;
+
+/// Most items the stack may hold.
+pub const MAX_STACK: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Op {
+    Push(Vec<u8>),
+    Dup,
+    Drop,
+    Swap,
+    /// Replaces the top item with its SHA-256 hash.
+    Sha256,
+    /// Pops two items and pushes whether they're the same.
+    Equal,
+    /// Pops the top item and fails the script unless it's true.
+    Verify,
+    /// Pops a public key and then a signature, and pushes whether it's a
+    /// valid signature of the transaction by that key.
+    CheckSig,
+    /// Pushes whether the transaction's own time lock is of the same kind
+    /// and at least as late as this one. Blocks enforce the transaction's
+    /// lock, so this is what lets a script say "not before".
+    CheckLock(TimeLock),
+    Not,
+    And,
+    Or,
+    /// Pops a condition and runs up to the matching `Else` or `EndIf` only
+    /// if it's true.
+    If,
+    Else,
+    EndIf,
+}
+
+impl Op {
+    fn cost(&self) -> u32 {
+        match *self {
+            Op::CheckSig => 50,
+            Op::Sha256 => 10,
+            _ => 1,
+        }
+    }
+}
+
+/// A spending condition. Coins sent to a script's address can be spent by
+/// whoever supplies arguments that make it succeed.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Script(pub Vec<Op>);
+
+fn is_true(item: &[u8]) -> bool {
+    item.iter().any(|&byte| byte != 0)
+}
+
+fn boolean(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Script {
+    pub fn address(&self) -> Address {
+        let mut sha = Sha256::new();
+        sha.input(b"script");
+        sha.input(&serialize(self, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Address::keyless(output)
+    }
+
+    /// Parses the text form: whitespace-separated op names, hex data to
+    /// push, and `CHECKLOCK height:N` or `CHECKLOCK time:SECONDS`.
+    pub fn parse(s: &str) -> Result<Script, String> {
+        let mut ops = Vec::new();
+        let mut words = s.split_whitespace();
+        while let Some(word) = words.next() {
+            ops.push(match word.to_uppercase().as_str() {
+                "DUP" => Op::Dup,
+                "DROP" => Op::Drop,
+                "SWAP" => Op::Swap,
+                "SHA256" => Op::Sha256,
+                "EQUAL" => Op::Equal,
+                "VERIFY" => Op::Verify,
+                "CHECKSIG" => Op::CheckSig,
+                "CHECKLOCK" => match words.next() {
+                    Some(lock) => Op::CheckLock(TimeLock::parse(lock)?),
+                    None => return Err("CHECKLOCK needs a lock".to_string()),
+                },
+                "NOT" => Op::Not,
+                "AND" => Op::And,
+                "OR" => Op::Or,
+                "IF" => Op::If,
+                "ELSE" => Op::Else,
+                "ENDIF" => Op::EndIf,
+                _ => Op::Push(parse_hex(word)?),
+            });
+        }
+        Ok(Script(ops))
+    }
+
+    /// Runs the script with `args` as the initial stack, last one on top.
+    /// `message` is what `CheckSig` checks signatures against and
+    /// `time_lock` is the spending transaction's lock.
+    pub fn run(&self, args: &[Vec<u8>], message: &[u8], time_lock: Option<TimeLock>) -> Result<(), String> {
+        let mut stack: Vec<Vec<u8>> = args.to_vec();
+        // whether each enclosing IF/ELSE branch is being run
+        let mut branches: Vec<bool> = Vec::new();
+        let mut cost = 0;
+
+        for op in &self.0 {
+            cost += op.cost();
+            if cost > MAX_COST {
+                return Err("Script is too expensive".to_string());
+            }
+
+            let running = branches.iter().all(|&branch| branch);
+            match *op {
+                Op::If => {
+                    let condition = running && is_true(&pop(&mut stack)?);
+                    branches.push(condition);
+                    continue;
+                },
+                Op::Else => {
+                    let outer = branches.len() < 2 || branches[..branches.len() - 1].iter().all(|&branch| branch);
+                    match branches.last_mut() {
+                        // only flip if the enclosing branches are running,
+                        // otherwise both halves stay skipped
+                        Some(branch) => *branch = outer && !*branch,
+                        None => return Err("ELSE without IF".to_string()),
+                    }
+                    continue;
+                },
+                Op::EndIf => {
+                    if branches.pop().is_none() {
+                        return Err("ENDIF without IF".to_string());
+                    }
+                    continue;
+                },
+                _ if !running => continue,
+                _ => {},
+            }
+
+            match *op {
+                Op::Push(ref data) => stack.push(data.clone()),
+                Op::Dup => {
+                    let top = pop(&mut stack)?;
+                    stack.push(top.clone());
+                    stack.push(top);
+                },
+                Op::Drop => {
+                    pop(&mut stack)?;
+                },
+                Op::Swap => {
+                    let (b, a) = (pop(&mut stack)?, pop(&mut stack)?);
+                    stack.push(b);
+                    stack.push(a);
+                },
+                Op::Sha256 => {
+                    let mut sha = Sha256::new();
+                    sha.input(&pop(&mut stack)?);
+                    let mut output = vec![0; 32];
+                    sha.result(&mut output);
+                    stack.push(output);
+                },
+                Op::Equal => {
+                    let (b, a) = (pop(&mut stack)?, pop(&mut stack)?);
+                    stack.push(boolean(a == b));
+                },
+                Op::Verify => {
+                    if !is_true(&pop(&mut stack)?) {
+                        return Err("Script failed a VERIFY".to_string());
+                    }
+                },
+                Op::CheckSig => {
+                    let (key, signature) = (pop(&mut stack)?, pop(&mut stack)?);
+                    let valid = key.len() == 32 && signature.len() == 64 && {
+                        let mut address = Address([0; 32]);
+                        address.0.copy_from_slice(&key);
+                        let mut bytes = [0; 64];
+                        bytes.copy_from_slice(&signature);
+                        verify(message, &address, &Signature(bytes))
+                    };
+                    stack.push(boolean(valid));
+                },
+                Op::CheckLock(lock) => {
+                    let satisfied = match (lock, time_lock) {
+                        (TimeLock::Height(min), Some(TimeLock::Height(height))) => height >= min,
+                        (TimeLock::Timestamp(min), Some(TimeLock::Timestamp(timestamp))) => timestamp >= min,
+                        _ => false,
+                    };
+                    stack.push(boolean(satisfied));
+                },
+                Op::Not => {
+                    let a = pop(&mut stack)?;
+                    stack.push(boolean(!is_true(&a)));
+                },
+                Op::And => {
+                    let (b, a) = (pop(&mut stack)?, pop(&mut stack)?);
+                    stack.push(boolean(is_true(&a) && is_true(&b)));
+                },
+                Op::Or => {
+                    let (b, a) = (pop(&mut stack)?, pop(&mut stack)?);
+                    stack.push(boolean(is_true(&a) || is_true(&b)));
+                },
+                Op::If | Op::Else | Op::EndIf => unreachable!(),
+            }
+            if stack.len() > MAX_STACK {
+                return Err("Script stack overflow".to_string());
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err("IF without ENDIF".to_string());
+        }
+        match stack.last() {
+            Some(top) if is_true(top) => Ok(()),
+            _ => Err("Script didn't succeed".to_string()),
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    stack.pop().ok_or("Script stack underflow".to_string())
+}
+
+/// Parses an even number of hex digits.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("Expected hex data, got {:?}", s));
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("Expected hex data, got {:?}", s)))
+        .collect()
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<String> = self.0.iter().map(|op| match *op {
+            Op::Push(ref data) => hex(data),
+            Op::CheckLock(lock) => format!("CHECKLOCK {}", lock),
+            ref other => format!("{:?}", other).to_uppercase(),
+        }).collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+
+    fn run(script: &str, args: &[Vec<u8>]) -> Result<(), String> {
+        Script::parse(script).unwrap().run(args, b"message", None)
+    }
+
+    #[test]
+    fn branches_not_taken_are_skipped() {
+        assert!(run("IF 01 ELSE 00 ENDIF", &[vec![1]]).is_ok());
+        assert!(run("IF 01 ELSE 00 ENDIF", &[vec![]]).is_err());
+        // the inner IF has nothing to pop while its outer branch is skipped
+        assert!(run("IF IF 00 ELSE 00 ENDIF ELSE 01 ENDIF", &[vec![]]).is_ok());
+        assert!(run("IF 00 IF 00 ELSE 00 ENDIF ELSE 01 ENDIF", &[vec![1]]).is_err());
+        assert!(run("IF 00 ELSE IF 00 ELSE 01 ENDIF ENDIF", &[vec![], vec![]]).is_ok());
+        assert_eq!(run("01 ELSE", &[]), Err("ELSE without IF".to_string()));
+        assert_eq!(run("01 ENDIF", &[]), Err("ENDIF without IF".to_string()));
+        assert_eq!(run("01 IF 01", &[]), Err("IF without ENDIF".to_string()));
+    }
+
+    #[test]
+    fn scripts_are_limited_in_cost_and_stack() {
+        let hashes = vec!["SHA256"; (MAX_COST / Op::Sha256.cost()) as usize];
+        assert!(run(&format!("01 {}", hashes.join(" ")), &[]).is_err());
+        assert!(run(&hashes[1..].join(" "), &[vec![1]]).is_ok());
+
+        let pushes = vec!["01"; MAX_STACK];
+        assert!(run(&pushes.join(" "), &[]).is_ok());
+        assert_eq!(run(&pushes.join(" "), &[vec![1]]), Err("Script stack overflow".to_string()));
+    }
+
+    #[test]
+    fn checksig_takes_only_keys_and_signatures() {
+        let keypair = Keypair::generate().unwrap();
+        let key = keypair.address().0.to_vec();
+        let signature = keypair.sign(b"message").0.to_vec();
+        assert!(run("CHECKSIG", &[signature.clone(), key.clone()]).is_ok());
+        assert!(run("CHECKSIG", &[signature[..63].to_vec(), key.clone()]).is_err());
+        assert!(run("CHECKSIG", &[signature.clone(), key[..31].to_vec()]).is_err());
+        assert!(run("CHECKSIG", &[key.clone(), signature.clone()]).is_err());
+        assert_eq!(run("CHECKSIG", &[key]), Err("Script stack underflow".to_string()));
+    }
+
+    #[test]
+    fn checklock_needs_the_same_kind_of_lock() {
+        let script = Script::parse("CHECKLOCK height:10").unwrap();
+        assert!(script.run(&[], b"", Some(TimeLock::Height(10))).is_ok());
+        assert!(script.run(&[], b"", Some(TimeLock::Height(9))).is_err());
+        assert!(script.run(&[], b"", Some(TimeLock::Timestamp(u64::MAX))).is_err());
+        assert!(script.run(&[], b"", None).is_err());
+        let script = Script::parse("CHECKLOCK time:10").unwrap();
+        assert!(script.run(&[], b"", Some(TimeLock::Timestamp(10_000_000_000))).is_ok());
+        assert!(script.run(&[], b"", Some(TimeLock::Height(u64::MAX))).is_err());
+    }
+
+    #[test]
+    fn scripts_round_trip_through_text() {
+        let text = "DUP SHA256 00ff EQUAL VERIFY CHECKSIG IF CHECKLOCK height:5 ELSE CHECKLOCK time:60 NOT ENDIF AND OR SWAP DROP";
+        let script = Script::parse(text).unwrap();
+        assert_eq!(script.to_string(), text);
+        assert_eq!(Script::parse(&script.to_string()), Ok(script));
+        assert_eq!(Script::parse("dup 00FF").unwrap().to_string(), "DUP 00ff");
+        assert!(Script::parse("CHECKLOCK").is_err());
+        assert!(Script::parse("0").is_err());
+        assert!(Script::parse("PUSH").is_err());
+    }
+}
@@ -0,0 +1,545 @@
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::HashMap;
+
+use block::{Block, Hash32Byte};
+use contract::{BlockContext, Contract, Contracts, contract_address};
+use keys::Address;
+use names::{Names, Registration};
+use token::{Token, Tokens};
+use transaction::{OutPoint, Reward, Transaction, TransactionKind, TxOut, block_body};
+
+
+/// What a ledger knows about one address, enough for a wallet to build a
+/// payment from it.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AccountInfo {
+    pub address: Address,
+    pub balance: u64,
+    pub nonce: u64,
+    /// The address's unspent outputs, if the ledger tracks outputs.
+    pub unspent: Option<Vec<(OutPoint, TxOut)>>,
+}
+
+
+/// Ledger state that follows the chain block by block, and can step back
+/// when the chain reorganizes.
+pub trait Ledger: Send {
+    /// Number of blocks applied on top of genesis.
+    fn height(&self) -> u64;
+
+    /// Applies every transaction in `block`, or none of them if any is
+    /// invalid.
+    fn apply_block(&mut self, block: &Block) -> Result<(), String>;
+
+    /// Rolls back the most recently applied block.
+    fn undo_block(&mut self);
+
+    fn balance(&self, address: &Address) -> u64;
+
+    /// The nonce the next transaction from `address` must use.
+    fn nonce(&self, address: &Address) -> u64;
+
+    /// The unspent outputs belonging to `address`, or `None` if this ledger
+    /// doesn't track outputs.
+    fn unspent(&self, address: &Address) -> Option<Vec<(OutPoint, TxOut)>>;
+
+    fn account_info(&self, address: &Address) -> AccountInfo {
+        AccountInfo {
+            address: *address,
+            balance: self.balance(address),
+            nonce: self.nonce(address),
+            unspent: self.unspent(address),
+        }
+    }
+
+    /// Commits to everything the ledger tracks; blocks carry the root the
+    /// ledger must have after applying their transactions and reward.
+    fn state_root(&self) -> Hash32Byte;
+
+    fn contract(&self, _address: &Address) -> Option<Contract> {
+        None
+    }
+
+    fn token(&self, _name: &str) -> Option<Token> {
+        None
+    }
+
+    /// The current registration of `name`, if it has one that hasn't
+    /// expired.
+    fn resolve(&self, _name: &str) -> Option<Registration> {
+        None
+    }
+
+    /// A scratch copy of the current state, for working out which
+    /// transactions can go into the next block, which will have `context`.
+    fn draft(&self, context: BlockContext) -> Box<dyn Draft>;
+
+    /// Moves the ledger from the tip of `old_chain` to the tip of
+    /// `new_chain`. If any new block is invalid the ledger is left at the tip
+    /// of `old_chain`.
+    fn reorg(&mut self, old_chain: &[Block], new_chain: &[Block]) -> Result<(), String> {
+        let common = old_chain.iter().zip(new_chain.iter())
+            .take_while(|&(old, new)| old.hash == new.hash)
+            .count()
+            .max(1);
+
+        for _ in common..old_chain.len() {
+            self.undo_block();
+        }
+        for (applied, block) in new_chain[common..].iter().enumerate() {
+            if let Err(e) = self.apply_block(block) {
+                for _ in 0..applied {
+                    self.undo_block();
+                }
+                for block in &old_chain[common..] {
+                    self.apply_block(block).expect("Couldn't restore the old chain");
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait Draft {
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), String>;
+
+    /// Pays out the block's reward, after its transactions. `parent` is the
+    /// hash of the block before it.
+    fn apply_reward(&mut self, reward: &Reward, parent: &Hash32Byte) -> Result<(), String>;
+
+    /// The state root a block with what's been applied so far needs.
+    fn state_root(&self) -> Hash32Byte;
+}
+
+/// Parses a starting balance given on the command line as `ADDR:AMOUNT`.
+pub fn parse_allocation(s: &str) -> Result<(Address, u64), String> {
+    let mut parts = s.splitn(2, ':');
+    let address = Address::from_hex(parts.next().unwrap_or(""))?;
+    match parts.next().map(|amount| amount.parse::<u64>()) {
+        Some(Ok(amount)) => Ok((address, amount)),
+        _ => Err(format!("Invalid allocation amount in {}", s)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    /// Number of transactions this account has sent, which is also the nonce
+    /// its next transaction must use.
+    pub nonce: u64,
+}
+
+/// Balances and nonces of every account, and the contracts, tokens and
+/// names, at some point in the chain.
+#[derive(Clone, Default)]
+pub struct AccountState {
+    accounts: HashMap<Address, Account>,
+    contracts: Contracts,
+    tokens: Tokens,
+    names: Names,
+    /// The block transactions are being applied in.
+    context: BlockContext,
+}
+
+impl AccountState {
+    pub fn account(&self, address: &Address) -> Account {
+        self.accounts.get(address).cloned().unwrap_or_default()
+    }
+}
+
+impl Draft for AccountState {
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), String> {
+        transaction.validate()?;
+
+        let mut sender = self.account(&transaction.from);
+        if transaction.nonce != sender.nonce {
+            return Err(format!("Expected nonce {} from {}, got {}", sender.nonce, transaction.from, transaction.nonce));
+        }
+        sender.nonce += 1;
+
+        let amount = match transaction.kind {
+            TransactionKind::Transfer { amount, .. } => amount,
+            _ => 0,
+        };
+        let cost = amount.checked_add(transaction.fee).ok_or("Amount plus fee overflows")?;
+        if cost > sender.balance {
+            return Err(format!("{} can't pay {}, it only has {}", transaction.from, cost, sender.balance));
+        }
+
+        // checked before anything changes, so a transaction that fails
+        // leaves the state as it was
+        let mut credit = None;
+        match transaction.kind {
+            TransactionKind::Data(_) | TransactionKind::Notarize(_) | TransactionKind::Manifest(_) |
+            TransactionKind::Encrypted(_) => {},
+            TransactionKind::Deploy(ref code) => {
+                let address = contract_address(&transaction.from, transaction.nonce);
+                self.contracts.deploy(address, transaction.from, code.clone())?;
+            },
+            TransactionKind::Invoke { ref contract, ref function, ref input, fuel } => {
+                // the fee has paid for the fuel either way, so a call that fails
+                // still goes through, just without changing the contract
+                let _ = self.contracts.invoke(contract, transaction.from, function, input, fuel, self.context);
+            },
+            TransactionKind::CreateToken { ref name, supply, mintable } => {
+                self.tokens.create(name, transaction.from, supply, mintable)?;
+            },
+            TransactionKind::MintToken { ref name, to, amount } => {
+                self.tokens.mint(name, &transaction.from, to, amount)?;
+            },
+            TransactionKind::TransferToken { ref name, to, amount } => {
+                self.tokens.transfer(name, &transaction.from, to, amount)?;
+            },
+            TransactionKind::RegisterName { ref name, ref value } => {
+                self.names.register(name, transaction.from, value.clone(), self.context.height)?;
+            },
+            TransactionKind::RenewName(ref name) => {
+                self.names.renew(name, &transaction.from, self.context.height)?;
+            },
+            TransactionKind::TransferName { ref name, to } => {
+                self.names.transfer(name, &transaction.from, to, self.context.height)?;
+            },
+            TransactionKind::Transfer { to, amount } => {
+                let balance = if to == transaction.from { sender.balance - cost } else { self.account(&to).balance };
+                balance.checked_add(amount).ok_or_else(|| format!("{}'s balance would overflow", to))?;
+                credit = Some((to, amount));
+            },
+            TransactionKind::Spend { .. } => {
+                return Err("The account ledger doesn't support spending outputs".to_string());
+            },
+        }
+
+        sender.balance -= cost;
+        self.accounts.insert(transaction.from, sender);
+        if let Some((to, amount)) = credit {
+            self.accounts.entry(to).or_default().balance += amount;
+        }
+        Ok(())
+    }
+
+    fn apply_reward(&mut self, reward: &Reward, _parent: &Hash32Byte) -> Result<(), String> {
+        let balance = self.account(&reward.to).balance.checked_add(reward.amount)
+            .ok_or_else(|| format!("The reward overflows {}'s balance", reward.to))?;
+        self.accounts.entry(reward.to).or_default().balance = balance;
+        Ok(())
+    }
+
+    /// Hashes every account, in address order, with the roots of the
+    /// contracts, tokens and names.
+    fn state_root(&self) -> Hash32Byte {
+        let mut accounts: Vec<(&Address, &Account)> = self.accounts.iter().collect();
+        accounts.sort_by_key(|&(address, _)| *address);
+
+        let mut sha = Sha256::new();
+        sha.input(&serialize(&accounts, Infinite).unwrap());
+        sha.input(&self.contracts.state_root().0);
+        sha.input(&self.tokens.state_root().0);
+        sha.input(&self.names.state_root().0);
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+}
+
+/// The accounts, contracts, tokens and names a block touched, as they were
+/// before it was applied.
+struct BlockUndo {
+    accounts: Vec<(Address, Option<Account>)>,
+    contracts: Vec<(Address, Option<Contract>)>,
+    tokens: Vec<(String, Option<Token>)>,
+    names: Vec<(String, Option<Registration>)>,
+}
+
+/// The account-based ledger: every address has a balance and a nonce.
+pub struct AccountLedger {
+    state: AccountState,
+    undo: Vec<BlockUndo>,
+}
+
+impl AccountLedger {
+    /// The state right after the genesis block, with `allocations` as the
+    /// starting balances.
+    pub fn new(allocations: &[(Address, u64)]) -> Result<AccountLedger, String> {
+        let mut state = AccountState::default();
+        for &(address, amount) in allocations {
+            let account = state.accounts.entry(address).or_default();
+            account.balance = account.balance.checked_add(amount)
+                .ok_or_else(|| format!("Allocations to {} overflow", address))?;
+        }
+        Ok(AccountLedger {
+            state,
+            undo: Vec::new(),
+        })
+    }
+
+    pub fn account(&self, address: &Address) -> Account {
+        self.state.account(address)
+    }
+
+    fn revert(&mut self, undo: &mut BlockUndo) {
+        for (address, account) in undo.accounts.drain(..) {
+            match account {
+                Some(account) => self.state.accounts.insert(address, account),
+                None => self.state.accounts.remove(&address),
+            };
+        }
+        for (address, contract) in undo.contracts.drain(..) {
+            self.state.contracts.restore(address, contract);
+        }
+        for (name, token) in undo.tokens.drain(..) {
+            self.state.tokens.restore(name, token);
+        }
+        for (name, registration) in undo.names.drain(..) {
+            self.state.names.restore(name, registration);
+        }
+    }
+}
+
+impl Ledger for AccountLedger {
+    fn height(&self) -> u64 {
+        self.undo.len() as u64
+    }
+
+    fn apply_block(&mut self, block: &Block) -> Result<(), String> {
+        if block.block_num != self.height() + 1 {
+            return Err(format!("Expected block {}, got block {}", self.height() + 1, block.block_num));
+        }
+
+        let body = block_body(block);
+        body.validate_reward()?;
+        body.validate_time_locks(block)?;
+        body.validate_transactions_root(block)?;
+
+        let mut undo = BlockUndo { accounts: Vec::new(), contracts: Vec::new(), tokens: Vec::new(), names: Vec::new() };
+        if let Some(reward) = body.reward {
+            undo.accounts.push((reward.to, self.state.accounts.get(&reward.to).cloned()));
+        }
+        for transaction in &body.transactions {
+            let mut touched = vec![transaction.from];
+            let contract = match transaction.kind {
+                TransactionKind::Transfer { to, .. } => {
+                    touched.push(to);
+                    None
+                },
+                TransactionKind::Deploy(_) => Some(contract_address(&transaction.from, transaction.nonce)),
+                TransactionKind::Invoke { contract, .. } => Some(contract),
+                _ => None,
+            };
+            let token = match transaction.kind {
+                TransactionKind::CreateToken { ref name, .. } |
+                TransactionKind::MintToken { ref name, .. } |
+                TransactionKind::TransferToken { ref name, .. } => Some(name),
+                _ => None,
+            };
+            let registered = match transaction.kind {
+                TransactionKind::RegisterName { ref name, .. } |
+                TransactionKind::RenewName(ref name) |
+                TransactionKind::TransferName { ref name, .. } => Some(name),
+                _ => None,
+            };
+            for address in touched {
+                if !undo.accounts.iter().any(|&(a, _)| a == address) {
+                    undo.accounts.push((address, self.state.accounts.get(&address).cloned()));
+                }
+            }
+            if let Some(address) = contract {
+                if !undo.contracts.iter().any(|&(a, _)| a == address) {
+                    undo.contracts.push((address, self.state.contracts.get(&address).cloned()));
+                }
+            }
+            if let Some(name) = token {
+                if !undo.tokens.iter().any(|(n, _)| n == name) {
+                    undo.tokens.push((name.clone(), self.state.tokens.get(name).cloned()));
+                }
+            }
+            if let Some(name) = registered {
+                if !undo.names.iter().any(|(n, _)| n == name) {
+                    undo.names.push((name.clone(), self.state.names.get(name).cloned()));
+                }
+            }
+        }
+
+        self.state.context = BlockContext::of(block);
+        for transaction in &body.transactions {
+            if let Err(e) = self.state.apply_transaction(transaction) {
+                self.revert(&mut undo);
+                return Err(format!("Invalid transaction {:?} in block {}: {}", transaction.hash(), block.block_num, e));
+            }
+        }
+        if let Some(ref reward) = body.reward {
+            if let Err(e) = self.state.apply_reward(reward, &block.previous_hash) {
+                self.revert(&mut undo);
+                return Err(format!("Invalid reward in block {}: {}", block.block_num, e));
+            }
+        }
+        if block.state_root != self.state.state_root() {
+            self.revert(&mut undo);
+            return Err(format!("Block {} has the wrong state root", block.block_num));
+        }
+        self.undo.push(undo);
+        Ok(())
+    }
+
+    fn undo_block(&mut self) {
+        if let Some(mut undo) = self.undo.pop() {
+            self.revert(&mut undo);
+        }
+    }
+
+    fn balance(&self, address: &Address) -> u64 {
+        self.state.account(address).balance
+    }
+
+    fn nonce(&self, address: &Address) -> u64 {
+        self.state.account(address).nonce
+    }
+
+    fn unspent(&self, _address: &Address) -> Option<Vec<(OutPoint, TxOut)>> {
+        None
+    }
+
+    fn state_root(&self) -> Hash32Byte {
+        self.state.state_root()
+    }
+
+    fn contract(&self, address: &Address) -> Option<Contract> {
+        self.state.contracts.get(address).cloned()
+    }
+
+    fn token(&self, name: &str) -> Option<Token> {
+        self.state.tokens.get(name).cloned()
+    }
+
+    fn resolve(&self, name: &str) -> Option<Registration> {
+        self.state.names.resolve(name, self.height()).cloned()
+    }
+
+    fn draft(&self, context: BlockContext) -> Box<dyn Draft> {
+        let mut draft = self.state.clone();
+        draft.context = context;
+        Box::new(draft)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+    use transaction::{BlockBody, pack_block};
+
+    /// A block after `previous` with `transactions`, paying their fees to
+    /// `producer` and committing to the state they leave `ledger` in.
+    fn block_with(previous: &Block, ledger: &dyn Ledger, producer: Address, transactions: Vec<Transaction>) -> Block {
+        let context = BlockContext { height: previous.block_num + 1, timestamp: previous.timestamp + 1 };
+        let mut draft = ledger.draft(context);
+        for transaction in &transactions {
+            draft.apply_transaction(transaction).unwrap();
+        }
+        let body = BlockBody { reward: None, transactions };
+        let reward = Reward { to: producer, amount: body.fees().unwrap() };
+        draft.apply_reward(&reward, &previous.hash).unwrap();
+        let body = BlockBody { reward: Some(reward), ..body };
+        Block::new(previous, context.timestamp, draft.state_root(), body.transactions_root(), pack_block(&body).unwrap())
+    }
+
+    fn transfer(keypair: &Keypair, nonce: u64, fee: u64, to: Address, amount: u64) -> Transaction {
+        Transaction::new(keypair, nonce, fee, TransactionKind::Transfer { to, amount })
+    }
+
+    #[test]
+    fn blocks_apply_and_undo() {
+        let alice = Keypair::generate().unwrap();
+        let (bob, producer) = (Address([1; 32]), Address([2; 32]));
+        let mut ledger = AccountLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+        let root = ledger.state_root();
+
+        let first = block_with(&genesis, &ledger, producer, vec![transfer(&alice, 0, 5, bob, 100)]);
+        ledger.apply_block(&first).unwrap();
+        let second = block_with(&first, &ledger, producer,
+            vec![transfer(&alice, 1, 1, bob, 50), transfer(&alice, 2, 1, alice.address(), 10)]);
+        ledger.apply_block(&second).unwrap();
+        assert_eq!(ledger.height(), 2);
+        assert_eq!(ledger.balance(&alice.address()), 1000 - 105 - 52);
+        assert_eq!(ledger.balance(&bob), 150);
+        assert_eq!(ledger.balance(&producer), 7);
+        assert_eq!(ledger.nonce(&alice.address()), 3);
+
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), first.state_root);
+        assert_eq!(ledger.balance(&producer), 5);
+        assert_eq!(ledger.nonce(&alice.address()), 1);
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.balance(&alice.address()), 1000);
+        assert_eq!(ledger.balance(&bob), 0);
+        // nothing was there before, so nothing is left behind
+        assert_eq!(ledger.state.accounts.len(), 1);
+    }
+
+    #[test]
+    fn a_failing_block_changes_nothing() {
+        let alice = Keypair::generate().unwrap();
+        let bob = Address([1; 32]);
+        let mut ledger = AccountLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+        let root = ledger.state_root();
+
+        // the first transaction is fine, the second reuses its nonce
+        let body = BlockBody { reward: None, transactions: vec![transfer(&alice, 0, 0, bob, 100), transfer(&alice, 0, 0, bob, 1)] };
+        let bad = Block::new(&genesis, 1, root, body.transactions_root(), pack_block(&body).unwrap());
+        assert!(ledger.apply_block(&bad).is_err());
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.balance(&bob), 0);
+        assert_eq!(ledger.height(), 0);
+
+        // every transaction is fine but the block claims the wrong state
+        let good = block_with(&genesis, &ledger, bob, vec![transfer(&alice, 0, 3, bob, 100)]);
+        let wrong_root = Block::new(&genesis, good.timestamp, root, good.transactions_root, good.data.0);
+        assert!(ledger.apply_block(&wrong_root).is_err());
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.balance(&alice.address()), 1000);
+        ledger.apply_block(&good).unwrap();
+    }
+
+    #[test]
+    fn reorgs_switch_chains_or_stay_put() {
+        let alice = Keypair::generate().unwrap();
+        let (bob, carol) = (Address([1; 32]), Address([2; 32]));
+        let mut ledger = AccountLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+
+        let mut ours = vec![genesis.clone()];
+        ours.push(block_with(&genesis, &ledger, bob, vec![transfer(&alice, 0, 1, bob, 100)]));
+        ledger.apply_block(&ours[1]).unwrap();
+
+        // a longer chain paying carol instead, built on a fresh ledger
+        let mut other = AccountLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let mut theirs = vec![genesis.clone()];
+        for nonce in 0..2 {
+            let block = block_with(theirs.last().unwrap(), &other, carol, vec![transfer(&alice, nonce, 1, carol, 100)]);
+            other.apply_block(&block).unwrap();
+            theirs.push(block);
+        }
+
+        ledger.reorg(&ours, &theirs).unwrap();
+        assert_eq!(ledger.state_root(), other.state_root());
+        assert_eq!(ledger.balance(&bob), 0);
+        assert_eq!(ledger.balance(&carol), 202);
+
+        // back again, and then to a chain whose last block doesn't apply
+        ledger.reorg(&theirs, &ours).unwrap();
+        assert_eq!(ledger.state_root(), ours[1].state_root);
+        let mut broken = theirs.clone();
+        let last = broken.pop().unwrap();
+        broken.push(Block::new(&broken[1], last.timestamp, ours[1].state_root, last.transactions_root, last.data.0));
+        assert!(ledger.reorg(&ours, &broken).is_err());
+        assert_eq!(ledger.height(), 1);
+        assert_eq!(ledger.state_root(), ours[1].state_root);
+        assert_eq!(ledger.balance(&bob), 101);
+    }
+}
@@ -0,0 +1,117 @@
+//! Fungible tokens issued natively on the chain. A token has a unique name,
+//! an issuer, and either a fixed supply handed to the issuer when it's
+//! created or a supply the issuer can keep minting.
+
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::BTreeMap;
+
+use block::Hash32Byte;
+use keys::Address;
+
+
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// Names are 1 to `MAX_NAME_LENGTH` ASCII letters, digits, `-` or `_`, so
+/// they're easy to type and can't be confused with one another.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!("Token names must be between 1 and {} characters", MAX_NAME_LENGTH));
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Invalid token name {:?}", name));
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub issuer: Address,
+    /// Total amount in existence.
+    pub supply: u64,
+    /// Whether the issuer can create more.
+    pub mintable: bool,
+    pub balances: BTreeMap<Address, u64>,
+}
+
+impl Token {
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.balances.get(address).cloned().unwrap_or(0)
+    }
+}
+
+/// Every token, by name.
+#[derive(Clone, Default)]
+pub struct Tokens {
+    tokens: BTreeMap<String, Token>,
+}
+
+impl Tokens {
+    /// A hash committing to every token's supply and balances.
+    pub fn state_root(&self) -> Hash32Byte {
+        let mut sha = Sha256::new();
+        sha.input(&serialize(&self.tokens, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Token> {
+        self.tokens.get(name)
+    }
+
+    /// Puts a token back the way it was, for undoing blocks.
+    pub fn restore(&mut self, name: String, token: Option<Token>) {
+        match token {
+            Some(token) => self.tokens.insert(name, token),
+            None => self.tokens.remove(&name),
+        };
+    }
+
+    /// Creates a token with `supply` all belonging to `issuer`.
+    pub fn create(&mut self, name: &str, issuer: Address, supply: u64, mintable: bool) -> Result<(), String> {
+        validate_name(name)?;
+        if self.tokens.contains_key(name) {
+            return Err(format!("Token {} already exists", name));
+        }
+        let mut balances = BTreeMap::new();
+        if supply > 0 {
+            balances.insert(issuer, supply);
+        }
+        self.tokens.insert(name.to_string(), Token { issuer, supply, mintable, balances });
+        Ok(())
+    }
+
+    /// Creates `amount` more of a mintable token for `to`, if `issuer`
+    /// issued it.
+    pub fn mint(&mut self, name: &str, issuer: &Address, to: Address, amount: u64) -> Result<(), String> {
+        let token = self.tokens.get_mut(name).ok_or(format!("No token named {}", name))?;
+        if !token.mintable {
+            return Err(format!("Token {} has a fixed supply", name));
+        }
+        if token.issuer != *issuer {
+            return Err(format!("Only {} can mint {}", token.issuer, name));
+        }
+        token.supply = token.supply.checked_add(amount).ok_or("Token supply overflows")?;
+        *token.balances.entry(to).or_insert(0) += amount;
+        Ok(())
+    }
+
+    pub fn transfer(&mut self, name: &str, from: &Address, to: Address, amount: u64) -> Result<(), String> {
+        let token = self.tokens.get_mut(name).ok_or(format!("No token named {}", name))?;
+        let balance = token.balance(from);
+        if amount > balance {
+            return Err(format!("{} can't send {} {}, it only has {}", from, amount, name, balance));
+        }
+        if balance == amount {
+            token.balances.remove(from);
+        } else {
+            token.balances.insert(*from, balance - amount);
+        }
+        // can't overflow, since balances add up to the supply
+        *token.balances.entry(to).or_insert(0) += amount;
+        Ok(())
+    }
+}
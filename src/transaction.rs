@@ -0,0 +1,460 @@
+use base64;
+use bincode::{serialize, deserialize, serialized_size, Infinite};
+use crypto::sha2::Sha256;
+use crypto::digest::Digest;
+use std::fmt;
+
+use block::{Block, Hash32Byte};
+use chunks::Manifest;
+use contract::{MAX_FUEL, fuel_fee};
+use envelope::Envelope;
+use keys::{Address, Keypair, MultisigPolicy, Signature, verify};
+use notary::merkle_root;
+use script::Script;
+use names;
+use token::validate_name;
+
+
+/// Room in a block's `BlockData` for transactions, after the reward record
+/// and the length prefix bincode puts in front of the list.
+pub const BLOCK_CAPACITY: usize = 1024 - REWARD_SIZE - 8;
+
+/// Bytes taken up by `Some(Reward)`.
+const REWARD_SIZE: usize = 1 + 32 + 8;
+
+/// Refers to output `index` of the transaction with hash `transaction`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub transaction: Hash32Byte,
+    pub index: u32,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TxOut {
+    pub to: Address,
+    pub amount: u64,
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub enum TransactionKind {
+    /// Arbitrary data to be recorded on the chain.
+    Data(Vec<u8>),
+    /// Moves funds between accounts, for the account ledger.
+    Transfer { to: Address, amount: u64 },
+    /// Consumes unspent outputs belonging to the sender and creates new
+    /// ones, for the UTXO ledger.
+    Spend { inputs: Vec<OutPoint>, outputs: Vec<TxOut> },
+    /// Deploys a WebAssembly contract, for the account ledger. Its address
+    /// comes from the sender and nonce.
+    Deploy(Vec<u8>),
+    /// Calls an exported function of a contract with at most `fuel`.
+    Invoke { contract: Address, function: String, input: Vec<u8>, fuel: u64 },
+    /// Issues a new token called `name`, with `supply` going to the sender,
+    /// for the account ledger.
+    CreateToken { name: String, supply: u64, mintable: bool },
+    /// Creates more of a mintable token the sender issued.
+    MintToken { name: String, to: Address, amount: u64 },
+    TransferToken { name: String, to: Address, amount: u64 },
+    /// Registers an unclaimed or expired name in the name registry, for the
+    /// account ledger.
+    RegisterName { name: String, value: String },
+    RenewName(String),
+    TransferName { name: String, to: Address },
+    /// Records the SHA-256 digest of a document, proving it existed by the
+    /// time the block was made.
+    Notarize(Hash32Byte),
+    /// Lists the chunks of a payload stored in `Data` transactions.
+    Manifest(Manifest),
+    /// Data only the envelope's recipients can read.
+    Encrypted(Envelope),
+}
+
+/// The earliest block a transaction can be included in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum TimeLock {
+    /// The block's number must be at least this.
+    Height(u64),
+    /// The block's timestamp, in ns, must be at least this.
+    Timestamp(u64),
+}
+
+impl TimeLock {
+    /// Parses `height:N` or `time:SECONDS`, where `SECONDS` is since the
+    /// Unix epoch.
+    pub fn parse(s: &str) -> Result<TimeLock, String> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap();
+        let value = parts.next().and_then(|value| value.parse::<u64>().ok())
+            .ok_or(format!("Expected height:N or time:SECONDS, got {:?}", s))?;
+        match kind {
+            "height" => Ok(TimeLock::Height(value)),
+            "time" => value.checked_mul(1_000_000_000).map(TimeLock::Timestamp)
+                .ok_or(format!("Time {} is too far away", value)),
+            _ => Err(format!("Expected height:N or time:SECONDS, got {:?}", s)),
+        }
+    }
+
+    /// Whether a block at `height` with `timestamp` satisfies the lock.
+    pub fn is_satisfied(&self, height: u64, timestamp: u64) -> bool {
+        match *self {
+            TimeLock::Height(min) => height >= min,
+            TimeLock::Timestamp(min) => timestamp >= min,
+        }
+    }
+}
+
+impl fmt::Display for TimeLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeLock::Height(height) => write!(f, "height:{}", height),
+            TimeLock::Timestamp(timestamp) => write!(f, "time:{}", timestamp / 1_000_000_000),
+        }
+    }
+}
+
+/// Proof that the sender authorized a transaction.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub enum Witness {
+    Single(Signature),
+    /// Signatures from the keys in `policy`, which must hash to the
+    /// sender's address. Each is paired with its key's index in
+    /// `policy.keys`, and they're kept in index order.
+    Multisig { policy: MultisigPolicy, signatures: Vec<(u8, Signature)> },
+    /// Arguments that make `script`, which must hash to the sender's
+    /// address, succeed.
+    Script { script: Script, args: Vec<Vec<u8>> },
+}
+
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct Transaction {
+    pub from: Address,
+    pub nonce: u64,
+    /// Paid by the sender to whoever produces the block including this.
+    pub fee: u64,
+    /// Keeps the transaction out of blocks until then, if set.
+    pub time_lock: Option<TimeLock>,
+    pub kind: TransactionKind,
+    pub witness: Witness,
+}
+
+impl Transaction {
+    /// Creates a transaction signed by `keypair`. `nonce` must be the number
+    /// of transactions the sender has already had accepted.
+    pub fn new(keypair: &Keypair, nonce: u64, fee: u64, kind: TransactionKind) -> Transaction {
+        Transaction::new_time_locked(keypair, nonce, fee, None, kind)
+    }
+
+    /// Like `new`, but the transaction can't go into a block before
+    /// `time_lock`.
+    pub fn new_time_locked(keypair: &Keypair, nonce: u64, fee: u64, time_lock: Option<TimeLock>, kind: TransactionKind) -> Transaction {
+        let from = keypair.address();
+        let signature = keypair.sign(&signing_bytes(&from, nonce, fee, &time_lock, &kind));
+        Transaction {
+            from,
+            nonce,
+            fee,
+            time_lock,
+            kind,
+            witness: Witness::Single(signature),
+        }
+    }
+
+    /// Creates a transaction from the multisig account for `policy` with no
+    /// signatures yet. Co-signers add theirs with `cosign`.
+    pub fn new_multisig(policy: MultisigPolicy, nonce: u64, fee: u64, time_lock: Option<TimeLock>, kind: TransactionKind) -> Transaction {
+        Transaction {
+            from: policy.address(),
+            nonce,
+            fee,
+            time_lock,
+            kind,
+            witness: Witness::Multisig { policy, signatures: Vec::new() },
+        }
+    }
+
+    /// Creates a transaction from the address of `script`, with no
+    /// arguments yet. Set them once any signatures they need are made over
+    /// `signing_bytes`.
+    pub fn new_scripted(script: Script, nonce: u64, fee: u64, time_lock: Option<TimeLock>, kind: TransactionKind) -> Transaction {
+        Transaction {
+            from: script.address(),
+            nonce,
+            fee,
+            time_lock,
+            kind,
+            witness: Witness::Script { script, args: Vec::new() },
+        }
+    }
+
+    /// Adds `keypair`'s signature to a multisig transaction. Returns false
+    /// if it was already there.
+    pub fn cosign(&mut self, keypair: &Keypair) -> Result<bool, String> {
+        let message = self.signing_bytes();
+        match self.witness {
+            Witness::Single(_) | Witness::Script { .. } => Err("Only multisig transactions can be co-signed".to_string()),
+            Witness::Multisig { ref policy, ref mut signatures } => {
+                let index = match policy.keys.iter().position(|key| *key == keypair.address()) {
+                    Some(index) => index as u8,
+                    None => return Err(format!("{} isn't one of the account's keys", keypair.address())),
+                };
+                match signatures.binary_search_by_key(&index, |&(i, _)| i) {
+                    Ok(_) => Ok(false),
+                    Err(position) => {
+                        signatures.insert(position, (index, keypair.sign(&message)));
+                        Ok(true)
+                    },
+                }
+            },
+        }
+    }
+
+    /// Adds the signatures from another copy of the same multisig
+    /// transaction, returning how many were new.
+    pub fn merge_signatures(&mut self, other: &Transaction) -> Result<usize, String> {
+        if self.signing_bytes() != other.signing_bytes() {
+            return Err("Those are different transactions".to_string());
+        }
+        other.signatures_needed()?;
+        match (&mut self.witness, &other.witness) {
+            (Witness::Multisig { policy, signatures }, Witness::Multisig { policy: other_policy, signatures: others })
+                if policy == other_policy => {
+                let mut added = 0;
+                for &(index, ref signature) in others {
+                    if let Err(position) = signatures.binary_search_by_key(&index, |&(i, _)| i) {
+                        signatures.insert(position, (index, signature.clone()));
+                        added += 1;
+                    }
+                }
+                Ok(added)
+            },
+            _ => Err("Only copies of the same multisig transaction can be merged".to_string()),
+        }
+    }
+
+    /// Checks the signatures that are there and returns how many more are
+    /// needed before the transaction is fully signed.
+    pub fn signatures_needed(&self) -> Result<usize, String> {
+        let message = self.signing_bytes();
+        match self.witness {
+            Witness::Single(ref signature) => {
+                if verify(&message, &self.from, signature) {
+                    Ok(0)
+                } else {
+                    Err("Invalid signature".to_string())
+                }
+            },
+            Witness::Multisig { ref policy, ref signatures } => {
+                policy.validate()?;
+                if policy.address() != self.from {
+                    return Err("Multisig policy doesn't match the sender".to_string());
+                }
+                for (i, &(index, ref signature)) in signatures.iter().enumerate() {
+                    if i > 0 && signatures[i - 1].0 >= index {
+                        return Err("Multisig signatures are out of order".to_string());
+                    }
+                    match policy.keys.get(index as usize) {
+                        Some(key) if verify(&message, key, signature) => {},
+                        _ => return Err(format!("Invalid signature for key {}", index)),
+                    }
+                }
+                Ok((policy.threshold as usize).saturating_sub(signatures.len()))
+            },
+            Witness::Script { ref script, ref args } => {
+                if script.address() != self.from {
+                    return Err("Script doesn't match the sender".to_string());
+                }
+                script.run(args, &message, self.time_lock)?;
+                Ok(0)
+            },
+        }
+    }
+
+    pub fn hash(&self) -> Hash32Byte {
+        let mut sha = Sha256::new();
+        sha.input(&serialize(self, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+
+    /// Number of bytes this transaction takes up inside a block.
+    pub fn size(&self) -> usize {
+        serialized_size(self) as usize
+    }
+
+    /// Identifies the transaction independently of its signatures, so
+    /// partially signed copies of it can be matched up.
+    pub fn signing_hash(&self) -> Hash32Byte {
+        let mut sha = Sha256::new();
+        sha.input(&self.signing_bytes());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+
+    /// What signatures over the transaction sign: everything but the
+    /// witness.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        signing_bytes(&self.from, self.nonce, self.fee, &self.time_lock, &self.kind)
+    }
+
+    /// Whether the transaction can go into a block at `height` with
+    /// `timestamp`.
+    pub fn is_mature(&self, height: u64, timestamp: u64) -> bool {
+        self.time_lock.is_none_or(|lock| lock.is_satisfied(height, timestamp))
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        self.signatures_needed() == Ok(0)
+    }
+
+    /// Encodes the transaction as text, for passing partially signed
+    /// transactions between co-signers.
+    pub fn to_base64(&self) -> String {
+        base64::encode(&serialize(self, Infinite).unwrap())
+    }
+
+    pub fn from_base64(encoded: &str) -> Result<Transaction, String> {
+        let bytes = base64::decode(encoded.trim()).map_err(|e| e.to_string())?;
+        deserialize(&bytes).map_err(|_| "Not a transaction".to_string())
+    }
+
+    /// Checks everything about the transaction that doesn't depend on
+    /// ledger state.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.kind {
+            TransactionKind::Data(ref payload) if payload.is_empty() => {
+                return Err("Transaction has no payload".to_string());
+            },
+            TransactionKind::Transfer { amount: 0, .. } => {
+                return Err("Transfer of nothing".to_string());
+            },
+            TransactionKind::Deploy(ref code) if code.is_empty() => {
+                return Err("Deploy has no code".to_string());
+            },
+            TransactionKind::Invoke { fuel, .. } if fuel == 0 || fuel > MAX_FUEL => {
+                return Err(format!("Invocations need between 1 and {} fuel", MAX_FUEL));
+            },
+            TransactionKind::Invoke { fuel, .. } if self.fee < fuel_fee(fuel) => {
+                return Err(format!("Invocations with {} fuel need a fee of at least {}", fuel, fuel_fee(fuel)));
+            },
+            TransactionKind::CreateToken { ref name, .. } => validate_name(name)?,
+            TransactionKind::MintToken { ref name, amount, .. } |
+            TransactionKind::TransferToken { ref name, amount, .. } => {
+                validate_name(name)?;
+                if amount == 0 {
+                    return Err(format!("Transaction moves no {}", name));
+                }
+            },
+            TransactionKind::RegisterName { ref name, .. } |
+            TransactionKind::RenewName(ref name) |
+            TransactionKind::TransferName { ref name, .. } => names::validate_name(name)?,
+            TransactionKind::Manifest(ref manifest) => manifest.validate()?,
+            TransactionKind::Encrypted(ref envelope) => envelope.validate()?,
+            TransactionKind::Spend { ref inputs, ref outputs } => {
+                if inputs.is_empty() {
+                    return Err("Spend has no inputs".to_string());
+                }
+                for (i, input) in inputs.iter().enumerate() {
+                    if inputs[..i].contains(input) {
+                        return Err(format!("Spend uses {:?} twice", input));
+                    }
+                }
+                if outputs.iter().any(|output| output.amount == 0) {
+                    return Err("Spend creates an empty output".to_string());
+                }
+            },
+            _ => {},
+        }
+        if self.size() > BLOCK_CAPACITY {
+            return Err(format!("Transaction is {} bytes, but blocks only hold {}", self.size(), BLOCK_CAPACITY));
+        }
+        match self.signatures_needed()? {
+            0 => Ok(()),
+            needed => Err(format!("Transaction needs {} more signatures", needed)),
+        }
+    }
+}
+
+fn signing_bytes(from: &Address, nonce: u64, fee: u64, time_lock: &Option<TimeLock>, kind: &TransactionKind) -> Vec<u8> {
+    serialize(&(from, nonce, fee, time_lock, kind), Infinite).unwrap()
+}
+
+/// Pays the fees of a block's transactions to the block's producer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Reward {
+    pub to: Address,
+    pub amount: u64,
+}
+
+/// What's stored in a block's data.
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize, Clone)]
+pub struct BlockBody {
+    pub reward: Option<Reward>,
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockBody {
+    /// Sum of the transactions' fees, or `None` on overflow.
+    pub fn fees(&self) -> Option<u64> {
+        self.transactions.iter().try_fold(0u64, |total, transaction| total.checked_add(transaction.fee))
+    }
+
+    /// Checks that the reward doesn't pay out more than the fees.
+    pub fn validate_reward(&self) -> Result<(), String> {
+        let fees = self.fees().ok_or("Fees overflow")?;
+        match self.reward {
+            Some(reward) if reward.amount > fees => {
+                Err(format!("Reward of {} is more than the {} paid in fees", reward.amount, fees))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The merkle root of the transactions' hashes, for the block header.
+    pub fn transactions_root(&self) -> Hash32Byte {
+        let hashes: Vec<Hash32Byte> = self.transactions.iter().map(Transaction::hash).collect();
+        merkle_root(&hashes)
+    }
+
+    /// Checks that `block`'s header commits to these transactions.
+    pub fn validate_transactions_root(&self, block: &Block) -> Result<(), String> {
+        if block.transactions_root != self.transactions_root() {
+            return Err(format!("Block {} has the wrong transactions root", block.block_num));
+        }
+        Ok(())
+    }
+
+    /// Checks that every transaction's time lock has passed by `block`.
+    pub fn validate_time_locks(&self, block: &Block) -> Result<(), String> {
+        match self.transactions.iter().find(|transaction| !transaction.is_mature(block.block_num, block.timestamp)) {
+            Some(transaction) => Err(format!("Transaction {:?} is locked until {:?}",
+                transaction.hash(), transaction.time_lock.unwrap())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Packs a body into the data of a new block, or returns `None` if it
+/// doesn't fit.
+pub fn pack_block(body: &BlockBody) -> Option<[u8; 1024]> {
+    let serialized = serialize(body, Infinite).unwrap();
+    if serialized.len() > 1024 {
+        return None;
+    }
+    let mut data = [0; 1024];
+    data[..serialized.len()].copy_from_slice(&serialized);
+    Some(data)
+}
+
+/// The contents of a block. Blocks whose data isn't a `BlockBody` (such as
+/// the all-zero blocks from before transactions existed) are empty.
+pub fn block_body(block: &Block) -> BlockBody {
+    deserialize(&block.data.0).unwrap_or_default()
+}
+
+pub fn block_transactions(block: &Block) -> Vec<Transaction> {
+    block_body(block).transactions
+}
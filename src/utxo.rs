@@ -0,0 +1,379 @@
+use bincode::{serialize, Infinite};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::collections::HashMap;
+
+use block::{Block, Hash32Byte};
+use contract::BlockContext;
+use keys::Address;
+use state::{Draft, Ledger};
+use transaction::{OutPoint, Reward, Transaction, TransactionKind, TxOut, block_body};
+
+
+/// Every output that hasn't been spent yet.
+#[derive(Clone, Default)]
+pub struct UtxoSet {
+    unspent: HashMap<OutPoint, TxOut>,
+}
+
+impl UtxoSet {
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&TxOut> {
+        self.unspent.get(outpoint)
+    }
+
+    /// The unspent outputs belonging to `address`.
+    pub fn owned_by(&self, address: &Address) -> Vec<(OutPoint, TxOut)> {
+        self.unspent.iter()
+            .filter(|&(_, output)| output.to == *address)
+            .map(|(outpoint, output)| (*outpoint, *output))
+            .collect()
+    }
+
+    /// Applies a transaction, recording what it spent and created in `undo`.
+    fn apply(&mut self, transaction: &Transaction, undo: &mut BlockUndo) -> Result<(), String> {
+        transaction.validate()?;
+
+        let (inputs, outputs) = match transaction.kind {
+            // without inputs to spend, nothing would stop the same
+            // transaction from going into block after block
+            TransactionKind::Data(_) | TransactionKind::Notarize(_) | TransactionKind::Manifest(_) |
+            TransactionKind::Encrypted(_) => {
+                return Err("The UTXO ledger doesn't support data, which has no inputs to spend".to_string());
+            },
+            TransactionKind::Transfer { .. } => {
+                return Err("The UTXO ledger doesn't support account transfers".to_string());
+            },
+            TransactionKind::Deploy(_) | TransactionKind::Invoke { .. } => {
+                return Err("The UTXO ledger doesn't support contracts".to_string());
+            },
+            TransactionKind::CreateToken { .. } | TransactionKind::MintToken { .. } |
+            TransactionKind::TransferToken { .. } => {
+                return Err("The UTXO ledger doesn't support tokens".to_string());
+            },
+            TransactionKind::RegisterName { .. } | TransactionKind::RenewName(_) |
+            TransactionKind::TransferName { .. } => {
+                return Err("The UTXO ledger doesn't support the name registry".to_string());
+            },
+            TransactionKind::Spend { ref inputs, ref outputs } => (inputs, outputs),
+        };
+
+        let mut total_in: u64 = 0;
+        for input in inputs {
+            match self.unspent.get(input) {
+                Some(output) if output.to == transaction.from => {
+                    total_in = total_in.checked_add(output.amount).ok_or("Input overflow")?;
+                },
+                Some(_) => return Err(format!("{:?} doesn't belong to {}", input, transaction.from)),
+                None => return Err(format!("{:?} is spent or doesn't exist", input)),
+            }
+        }
+        let mut total_out: u64 = 0;
+        for output in outputs {
+            total_out = total_out.checked_add(output.amount).ok_or("Output overflow")?;
+        }
+        total_out = total_out.checked_add(transaction.fee).ok_or("Output overflow")?;
+        if total_out != total_in {
+            return Err(format!("Inputs worth {} don't match outputs plus fee of {}", total_in, total_out));
+        }
+
+        let hash = transaction.hash();
+        for input in inputs {
+            let output = self.unspent.remove(input).unwrap();
+            undo.spent.push((*input, output));
+        }
+        for (index, output) in outputs.iter().enumerate() {
+            let outpoint = OutPoint { transaction: hash, index: index as u32 };
+            self.unspent.insert(outpoint, *output);
+            undo.created.push(outpoint);
+        }
+        Ok(())
+    }
+
+    /// Pays out a block's reward as an output of a pretend transaction
+    /// named after the block's parent, since the block's own hash commits
+    /// to the state the reward is part of.
+    fn pay(&mut self, reward: &Reward, parent: &Hash32Byte, undo: &mut BlockUndo) {
+        let outpoint = OutPoint { transaction: *parent, index: 0 };
+        self.unspent.insert(outpoint, TxOut { to: reward.to, amount: reward.amount });
+        undo.created.push(outpoint);
+    }
+
+    fn revert(&mut self, undo: &mut BlockUndo) {
+        // restore spent outputs first, so outputs both created and spent
+        // within the block end up gone
+        for (outpoint, output) in undo.spent.drain(..) {
+            self.unspent.insert(outpoint, output);
+        }
+        for outpoint in undo.created.drain(..) {
+            self.unspent.remove(&outpoint);
+        }
+    }
+}
+
+impl Draft for UtxoSet {
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), String> {
+        self.apply(transaction, &mut BlockUndo::default())
+    }
+
+    fn apply_reward(&mut self, reward: &Reward, parent: &Hash32Byte) -> Result<(), String> {
+        self.pay(reward, parent, &mut BlockUndo::default());
+        Ok(())
+    }
+
+    /// Hashes every unspent output, in outpoint order.
+    fn state_root(&self) -> Hash32Byte {
+        let mut unspent: Vec<(&OutPoint, &TxOut)> = self.unspent.iter().collect();
+        unspent.sort_by_key(|&(outpoint, _)| (outpoint.transaction.0, outpoint.index));
+
+        let mut sha = Sha256::new();
+        sha.input(&serialize(&unspent, Infinite).unwrap());
+
+        let mut output = [0; 32];
+        sha.result(&mut output);
+        Hash32Byte(output)
+    }
+}
+
+/// What a block spent and created, so it can be undone.
+#[derive(Default)]
+struct BlockUndo {
+    spent: Vec<(OutPoint, TxOut)>,
+    created: Vec<OutPoint>,
+}
+
+/// The UTXO ledger: funds live in outputs which are each spent exactly once.
+pub struct UtxoLedger {
+    unspent: UtxoSet,
+    undo: Vec<BlockUndo>,
+}
+
+impl UtxoLedger {
+    /// The unspent outputs right after the genesis block. Each allocation
+    /// becomes an output of a pretend transaction whose hash is all zeroes.
+    /// Spends neither create nor destroy funds, so as long as the
+    /// allocations add up no balance can overflow.
+    pub fn new(allocations: &[(Address, u64)]) -> Result<UtxoLedger, String> {
+        let mut unspent = UtxoSet::default();
+        let mut total: u64 = 0;
+        for (index, &(to, amount)) in allocations.iter().enumerate() {
+            total = total.checked_add(amount).ok_or("Allocations overflow")?;
+            let outpoint = OutPoint { transaction: Hash32Byte([0; 32]), index: index as u32 };
+            unspent.unspent.insert(outpoint, TxOut { to, amount });
+        }
+        Ok(UtxoLedger {
+            unspent,
+            undo: Vec::new(),
+        })
+    }
+
+    pub fn unspent(&self) -> &UtxoSet {
+        &self.unspent
+    }
+}
+
+impl Ledger for UtxoLedger {
+    fn height(&self) -> u64 {
+        self.undo.len() as u64
+    }
+
+    fn apply_block(&mut self, block: &Block) -> Result<(), String> {
+        if block.block_num != self.height() + 1 {
+            return Err(format!("Expected block {}, got block {}", self.height() + 1, block.block_num));
+        }
+
+        let body = block_body(block);
+        body.validate_reward()?;
+        body.validate_time_locks(block)?;
+        body.validate_transactions_root(block)?;
+
+        let mut undo = BlockUndo::default();
+        for transaction in &body.transactions {
+            if let Err(e) = self.unspent.apply(transaction, &mut undo) {
+                self.unspent.revert(&mut undo);
+                return Err(format!("Invalid transaction {:?} in block {}: {}", transaction.hash(), block.block_num, e));
+            }
+        }
+        if let Some(ref reward) = body.reward {
+            self.unspent.pay(reward, &block.previous_hash, &mut undo);
+        }
+        if block.state_root != self.state_root() {
+            self.unspent.revert(&mut undo);
+            return Err(format!("Block {} has the wrong state root", block.block_num));
+        }
+        self.undo.push(undo);
+        Ok(())
+    }
+
+    fn undo_block(&mut self) {
+        if let Some(mut undo) = self.undo.pop() {
+            self.unspent.revert(&mut undo);
+        }
+    }
+
+    fn balance(&self, address: &Address) -> u64 {
+        self.unspent.owned_by(address).iter()
+            .try_fold(0u64, |total, &(_, output)| total.checked_add(output.amount))
+            .expect("Balances add up to at most the allocations")
+    }
+
+    /// Outputs can only be spent once, so transactions don't need nonces.
+    fn nonce(&self, _address: &Address) -> u64 {
+        0
+    }
+
+    fn unspent(&self, address: &Address) -> Option<Vec<(OutPoint, TxOut)>> {
+        Some(self.unspent.owned_by(address))
+    }
+
+    fn state_root(&self) -> Hash32Byte {
+        self.unspent.state_root()
+    }
+
+    fn draft(&self, _context: BlockContext) -> Box<dyn Draft> {
+        Box::new(self.unspent.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::Keypair;
+    use transaction::{BlockBody, pack_block};
+
+    /// A block after `previous` with `transactions`, paying their fees to
+    /// `producer` and committing to the state they leave `ledger` in.
+    fn block_with(previous: &Block, ledger: &dyn Ledger, producer: Address, transactions: Vec<Transaction>) -> Block {
+        let context = BlockContext { height: previous.block_num + 1, timestamp: previous.timestamp + 1 };
+        let mut draft = ledger.draft(context);
+        for transaction in &transactions {
+            draft.apply_transaction(transaction).unwrap();
+        }
+        let body = BlockBody { reward: None, transactions };
+        let reward = Reward { to: producer, amount: body.fees().unwrap() };
+        draft.apply_reward(&reward, &previous.hash).unwrap();
+        let body = BlockBody { reward: Some(reward), ..body };
+        Block::new(previous, context.timestamp, draft.state_root(), body.transactions_root(), pack_block(&body).unwrap())
+    }
+
+    fn spend(keypair: &Keypair, inputs: Vec<OutPoint>, fee: u64, outputs: Vec<(Address, u64)>) -> Transaction {
+        let outputs = outputs.into_iter().map(|(to, amount)| TxOut { to, amount }).collect();
+        Transaction::new(keypair, 0, fee, TransactionKind::Spend { inputs, outputs })
+    }
+
+    /// The first allocation's output.
+    fn allocated() -> OutPoint {
+        OutPoint { transaction: Hash32Byte([0; 32]), index: 0 }
+    }
+
+    #[test]
+    fn spends_apply_and_undo() {
+        let alice = Keypair::generate().unwrap();
+        let bob = Keypair::generate().unwrap();
+        let (carol, producer) = (Address([1; 32]), Address([2; 32]));
+        let mut ledger = UtxoLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+        let root = ledger.state_root();
+
+        let paid = spend(&alice, vec![allocated()], 5, vec![(bob.address(), 300), (alice.address(), 695)]);
+        let first = block_with(&genesis, &ledger, producer, vec![paid.clone()]);
+        ledger.apply_block(&first).unwrap();
+        let bobs = OutPoint { transaction: paid.hash(), index: 0 };
+        let second = block_with(&first, &ledger, producer, vec![spend(&bob, vec![bobs], 1, vec![(carol, 299)])]);
+        ledger.apply_block(&second).unwrap();
+        assert_eq!(ledger.balance(&alice.address()), 695);
+        assert_eq!(ledger.balance(&bob.address()), 0);
+        assert_eq!(ledger.balance(&carol), 299);
+        assert_eq!(ledger.balance(&producer), 6);
+
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), first.state_root);
+        assert_eq!(ledger.balance(&bob.address()), 300);
+        assert_eq!(ledger.balance(&producer), 5);
+        ledger.undo_block();
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.balance(&alice.address()), 1000);
+        assert_eq!(ledger.unspent().unspent.len(), 1);
+    }
+
+    #[test]
+    fn a_failing_block_changes_nothing() {
+        let alice = Keypair::generate().unwrap();
+        let (bob, carol) = (Address([1; 32]), Address([2; 32]));
+        let mut ledger = UtxoLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+        let root = ledger.state_root();
+
+        // the second transaction spends what the first already did
+        let body = BlockBody { reward: None, transactions: vec![
+            spend(&alice, vec![allocated()], 0, vec![(bob, 1000)]),
+            spend(&alice, vec![allocated()], 0, vec![(carol, 1000)]),
+        ] };
+        let bad = Block::new(&genesis, 1, root, body.transactions_root(), pack_block(&body).unwrap());
+        assert!(ledger.apply_block(&bad).is_err());
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.balance(&bob), 0);
+        assert_eq!(ledger.balance(&alice.address()), 1000);
+
+        // every transaction is fine but the block claims the wrong state
+        let good = block_with(&genesis, &ledger, bob, vec![spend(&alice, vec![allocated()], 3, vec![(carol, 997)])]);
+        let wrong_root = Block::new(&genesis, good.timestamp, root, good.transactions_root, good.data.0);
+        assert!(ledger.apply_block(&wrong_root).is_err());
+        assert_eq!(ledger.state_root(), root);
+        assert_eq!(ledger.height(), 0);
+        ledger.apply_block(&good).unwrap();
+    }
+
+    #[test]
+    fn reorgs_switch_chains_or_stay_put() {
+        let alice = Keypair::generate().unwrap();
+        let (bob, carol) = (Address([1; 32]), Address([2; 32]));
+        let mut ledger = UtxoLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let genesis = Block::genesis(ledger.state_root());
+
+        let ours = vec![genesis.clone(), block_with(&genesis, &ledger, bob, vec![spend(&alice, vec![allocated()], 1, vec![(bob, 999)])])];
+        ledger.apply_block(&ours[1]).unwrap();
+
+        // a longer chain paying carol instead, built on a fresh ledger
+        let mut other = UtxoLedger::new(&[(alice.address(), 1000)]).unwrap();
+        let paid = spend(&alice, vec![allocated()], 1, vec![(carol, 100), (alice.address(), 899)]);
+        let mut theirs = vec![genesis.clone(), block_with(&genesis, &other, carol, vec![paid.clone()])];
+        other.apply_block(&theirs[1]).unwrap();
+        let change = OutPoint { transaction: paid.hash(), index: 1 };
+        let block = block_with(&theirs[1], &other, carol, vec![spend(&alice, vec![change], 1, vec![(carol, 898)])]);
+        other.apply_block(&block).unwrap();
+        theirs.push(block);
+
+        ledger.reorg(&ours, &theirs).unwrap();
+        assert_eq!(ledger.state_root(), other.state_root());
+        assert_eq!(ledger.balance(&bob), 0);
+        assert_eq!(ledger.balance(&carol), 1000);
+
+        // back again, and then to a chain whose last block doesn't apply
+        ledger.reorg(&theirs, &ours).unwrap();
+        assert_eq!(ledger.state_root(), ours[1].state_root);
+        let mut broken = theirs.clone();
+        let last = broken.pop().unwrap();
+        broken.push(Block::new(&broken[1], last.timestamp, ours[1].state_root, last.transactions_root, last.data.0));
+        assert!(ledger.reorg(&ours, &broken).is_err());
+        assert_eq!(ledger.height(), 1);
+        assert_eq!(ledger.state_root(), ours[1].state_root);
+        assert_eq!(ledger.balance(&bob), 1000);
+    }
+
+    #[test]
+    fn allocations_must_add_up() {
+        assert!(UtxoLedger::new(&[(Address([1; 32]), u64::MAX), (Address([2; 32]), 1)]).is_err());
+        let ledger = UtxoLedger::new(&[(Address([1; 32]), u64::MAX - 1), (Address([1; 32]), 1)]).unwrap();
+        assert_eq!(ledger.balance(&Address([1; 32])), u64::MAX);
+    }
+
+    #[test]
+    fn transactions_without_inputs_are_refused() {
+        // it would be valid again in every later block
+        let keypair = Keypair::generate().unwrap();
+        let ledger = UtxoLedger::new(&[(keypair.address(), 100)]).unwrap();
+        let mut draft = ledger.draft(BlockContext { height: 1, timestamp: 1 });
+        let data = Transaction::new(&keypair, 0, 0, TransactionKind::Data(vec![1, 2, 3]));
+        assert!(draft.apply_transaction(&data).is_err());
+    }
+}
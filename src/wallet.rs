@@ -0,0 +1,275 @@
+use std::cmp::Reverse;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+use bincode::{serialize, deserialize, Infinite};
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use rand::{OsRng, Rng};
+
+use atomic::write_atomically;
+use hd::{ExtendedKey, mnemonic_to_entropy, mnemonic_to_seed};
+use keys::{Address, Keypair, MultisigPolicy};
+use state::AccountInfo;
+use transaction::{TimeLock, Transaction, TransactionKind, TxOut, Witness};
+
+
+const KEYSTORE_VERSION: u32 = 2;
+
+/// Limits on the scrypt parameters a keystore may ask for. Ours use
+/// `log_n` 14, `r` 8 and `p` 1; anything far beyond that is corrupt, and
+/// would take too much memory or time to try.
+const MAX_LOG_N: u8 = 20;
+const MAX_R: u32 = 32;
+const MAX_P: u32 = 16;
+
+/// Keys derived from a seed phrase live at `ACCOUNT_PATH/i'`.
+pub const ACCOUNT_PATH: &str = "m/44'/0'/0'";
+
+/// On-disk form of a wallet. The key seeds are encrypted with
+/// ChaCha20-Poly1305 under a key derived from the password with scrypt.
+#[derive(Serialize, Deserialize)]
+struct Keystore {
+    version: u32,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: Vec<u8>,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+/// What a version 2 keystore encrypts. Version 1 keystores only held
+/// `imported`.
+#[derive(Serialize, Deserialize)]
+struct Secrets {
+    mnemonic: Option<String>,
+    derived: u32,
+    imported: Vec<[u8; 32]>,
+}
+
+fn derive_key(password: &str, keystore: &Keystore) -> io::Result<[u8; 32]> {
+    // ScryptParams::new panics on parameters it can't use
+    if keystore.log_n == 0 || keystore.log_n > MAX_LOG_N || keystore.r == 0 || keystore.r > MAX_R
+        || keystore.p == 0 || keystore.p > MAX_P {
+        return Err(invalid_data("Corrupt keystore"));
+    }
+    let mut key = [0u8; 32];
+    let params = ScryptParams::new(keystore.log_n, keystore.r, keystore.p);
+    scrypt(password.as_bytes(), &keystore.salt, &params, &mut key);
+    Ok(key)
+}
+
+/// Asks for the wallet password on the terminal.
+pub fn read_password() -> String {
+    print!("Wallet password: ");
+    io::stdout().flush().unwrap();
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password).expect("Couldn't read password");
+    password.trim_end_matches(&['\r', '\n'][..]).to_string()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A set of keys, stored in an encrypted keystore file. A wallet made from
+/// a seed phrase derives its keys from it, so the phrase alone is enough to
+/// get them back; keys added to a wallet without one are random.
+pub struct Wallet {
+    mnemonic: Option<String>,
+    account: Option<ExtendedKey>,
+    derived: u32,
+    keys: Vec<Keypair>,
+}
+
+impl Wallet {
+    pub fn new() -> Wallet {
+        Wallet { mnemonic: None, account: None, derived: 0, keys: Vec::new() }
+    }
+
+    /// Makes a wallet from a seed phrase, deriving its first `count` keys.
+    pub fn from_mnemonic(mnemonic: &str, count: u32) -> Result<Wallet, String> {
+        mnemonic_to_entropy(mnemonic)?;
+        let mnemonic = mnemonic.split_whitespace().collect::<Vec<_>>().join(" ");
+        let seed = mnemonic_to_seed(&mnemonic, "");
+        let account = ExtendedKey::master(&seed).derive_path(ACCOUNT_PATH)?;
+        let mut wallet = Wallet { mnemonic: Some(mnemonic), account: Some(account), derived: 0, keys: Vec::new() };
+        for _ in 0..count {
+            wallet.derive_next();
+        }
+        Ok(wallet)
+    }
+
+    pub fn mnemonic(&self) -> Option<&str> {
+        self.mnemonic.as_deref()
+    }
+
+    fn derive_next(&mut self) -> Option<Address> {
+        let key = self.account.as_ref()?.child(self.derived).keypair();
+        let address = key.address();
+        // derived keys come first so their position matches their index
+        self.keys.insert(self.derived as usize, key);
+        self.derived += 1;
+        Some(address)
+    }
+
+    pub fn keys(&self) -> &[Keypair] {
+        &self.keys
+    }
+
+    pub fn addresses(&self) -> Vec<Address> {
+        self.keys.iter().map(Keypair::address).collect()
+    }
+
+    pub fn key_for(&self, address: &Address) -> Option<&Keypair> {
+        self.keys.iter().find(|key| key.address() == *address)
+    }
+
+    /// Adds a key and returns its address. The key is the next derived one
+    /// if the wallet has a seed phrase, otherwise it's random.
+    pub fn generate_key(&mut self) -> io::Result<Address> {
+        if let Some(address) = self.derive_next() {
+            return Ok(address);
+        }
+        let key = Keypair::generate()?;
+        let address = key.address();
+        self.keys.push(key);
+        Ok(address)
+    }
+
+    /// Adds signatures to a multisig transaction from every key in the
+    /// wallet that belongs to the account, returning how many were added.
+    pub fn cosign(&self, transaction: &mut Transaction) -> usize {
+        let policy = match transaction.witness {
+            Witness::Multisig { ref policy, .. } => policy.clone(),
+            Witness::Single(_) | Witness::Script { .. } => return 0,
+        };
+        self.keys.iter()
+            .filter(|key| policy.keys.contains(&key.address()))
+            .filter(|key| transaction.cosign(key) == Ok(true))
+            .count()
+    }
+
+    pub fn load(path: &Path, password: &str) -> io::Result<Wallet> {
+        let mut serialized = Vec::new();
+        File::open(path)?.read_to_end(&mut serialized)?;
+        let keystore: Keystore = deserialize(&serialized)
+            .map_err(|_| invalid_data("Not a keystore file"))?;
+        if keystore.version != 1 && keystore.version != KEYSTORE_VERSION {
+            return Err(invalid_data("Unsupported keystore version"));
+        }
+        if keystore.nonce.len() != 8 || keystore.tag.len() != 16 {
+            return Err(invalid_data("Corrupt keystore"));
+        }
+
+        let key = derive_key(password, &keystore)?;
+        let mut plaintext = vec![0; keystore.ciphertext.len()];
+        let mut cipher = ChaCha20Poly1305::new(&key, &keystore.nonce, &[]);
+        if !cipher.decrypt(&keystore.ciphertext, &mut plaintext, &keystore.tag) {
+            return Err(invalid_data("Wrong password or corrupt keystore"));
+        }
+
+        let secrets = if keystore.version == 1 {
+            Secrets { mnemonic: None, derived: 0, imported: deserialize(&plaintext).map_err(|_| invalid_data("Corrupt keystore"))? }
+        } else {
+            deserialize(&plaintext).map_err(|_| invalid_data("Corrupt keystore"))?
+        };
+        let mut wallet = match secrets.mnemonic {
+            Some(ref mnemonic) => Wallet::from_mnemonic(mnemonic, secrets.derived).map_err(|e| invalid_data(&e))?,
+            None => Wallet::new(),
+        };
+        wallet.keys.extend(secrets.imported.iter().map(Keypair::from_seed));
+        Ok(wallet)
+    }
+
+    pub fn save(&self, path: &Path, password: &str) -> io::Result<()> {
+        let mut rng = OsRng::new()?;
+        let mut keystore = Keystore {
+            version: KEYSTORE_VERSION,
+            log_n: 14,
+            r: 8,
+            p: 1,
+            salt: vec![0; 16],
+            nonce: vec![0; 8],
+            ciphertext: Vec::new(),
+            tag: vec![0; 16],
+        };
+        rng.fill_bytes(&mut keystore.salt);
+        rng.fill_bytes(&mut keystore.nonce);
+
+        let secrets = Secrets {
+            mnemonic: self.mnemonic.clone(),
+            derived: self.derived,
+            imported: self.keys[self.derived as usize..].iter().map(Keypair::seed).collect(),
+        };
+        let plaintext = serialize(&secrets, Infinite).unwrap();
+        let key = derive_key(password, &keystore)?;
+        keystore.ciphertext = vec![0; plaintext.len()];
+        let mut cipher = ChaCha20Poly1305::new(&key, &keystore.nonce, &[]);
+        cipher.encrypt(&plaintext, &mut keystore.ciphertext, &mut keystore.tag);
+
+        write_atomically(path, &serialize(&keystore, Infinite).unwrap())
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Wallet {
+        Wallet::new()
+    }
+}
+
+/// Builds a signed payment of `amount` plus `fee` from `keypair` to `to`.
+/// `account` is what the ledger knows about the sender; its nonce should
+/// already count any of the sender's transactions that are still pending.
+pub fn build_payment(keypair: &Keypair, account: &AccountInfo, to: Address, amount: u64, fee: u64,
+                     time_lock: Option<TimeLock>) -> Result<Transaction, String> {
+    let kind = payment_kind(account, to, amount, fee)?;
+    Ok(Transaction::new_time_locked(keypair, account.nonce, fee, time_lock, kind))
+}
+
+/// Builds an unsigned payment from a multisig account, signed by whichever
+/// of the wallet's keys belong to it.
+pub fn build_multisig_payment(wallet: &Wallet, policy: MultisigPolicy, account: &AccountInfo, to: Address, amount: u64, fee: u64,
+                              time_lock: Option<TimeLock>) -> Result<Transaction, String> {
+    let kind = payment_kind(account, to, amount, fee)?;
+    let mut transaction = Transaction::new_multisig(policy, account.nonce, fee, time_lock, kind);
+    wallet.cosign(&mut transaction);
+    Ok(transaction)
+}
+
+/// What paying `amount` plus `fee` out of `account` looks like on its
+/// ledger. Any change goes back to the account.
+pub fn payment_kind(account: &AccountInfo, to: Address, amount: u64, fee: u64) -> Result<TransactionKind, String> {
+    let cost = amount.checked_add(fee).ok_or("Amount plus fee overflows")?;
+    if cost > account.balance {
+        return Err(format!("Can't pay {}, balance is only {}", cost, account.balance));
+    }
+
+    Ok(match account.unspent {
+        None => TransactionKind::Transfer { to, amount },
+        Some(ref unspent) => {
+            // largest first, so a payment needs as few inputs as it can
+            let mut unspent = unspent.clone();
+            unspent.sort_by_key(|&(_, output)| Reverse(output.amount));
+            let mut inputs = Vec::new();
+            let mut total = 0;
+            for &(outpoint, output) in &unspent {
+                if total >= cost {
+                    break;
+                }
+                inputs.push(outpoint);
+                total += output.amount;
+            }
+            let mut outputs = vec![TxOut { to, amount }];
+            if total > cost {
+                outputs.push(TxOut { to: account.address, amount: total - cost });
+            }
+            TransactionKind::Spend { inputs, outputs }
+        },
+    })
+}
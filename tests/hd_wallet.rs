@@ -0,0 +1,85 @@
+extern crate naivechain_rs;
+
+use naivechain_rs::hd::{ExtendedKey, entropy_to_mnemonic, mnemonic_to_entropy, mnemonic_to_seed};
+use naivechain_rs::wallet::Wallet;
+
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+}
+
+// From the BIP39 reference vectors, all with the passphrase "TREZOR".
+const BIP39_VECTORS: &[(&str, &str, &str)] = &[
+    ("00000000000000000000000000000000",
+     "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+     "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"),
+    ("7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+     "legal winner thank year wave sausage worth useful legal winner thank yellow",
+     "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607"),
+    ("80808080808080808080808080808080",
+     "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+     "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8"),
+    ("ffffffffffffffffffffffffffffffff",
+     "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+     "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069"),
+];
+
+#[test]
+fn bip39_vectors() {
+    for &(entropy, mnemonic, seed) in BIP39_VECTORS {
+        let entropy = unhex(entropy);
+        assert_eq!(entropy_to_mnemonic(&entropy), mnemonic);
+        assert_eq!(mnemonic_to_entropy(mnemonic).unwrap(), entropy);
+        assert_eq!(hex(&mnemonic_to_seed(mnemonic, "TREZOR")), seed);
+    }
+}
+
+#[test]
+fn bad_mnemonics_are_rejected() {
+    // last word changed, so the checksum is wrong
+    assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+    assert!(mnemonic_to_entropy("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon notaword").is_err());
+    assert!(mnemonic_to_entropy("abandon about").is_err());
+}
+
+// SLIP-0010 ed25519 test vector 1.
+#[test]
+fn slip10_vectors() {
+    let seed = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    let vectors = [
+        ("m",
+         "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+         "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+         "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed"),
+        ("m/0'",
+         "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+         "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+         "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c"),
+        ("m/0'/1'",
+         "a320425f77d1b5c2505a6b1b27382b37368ee640e3557c315416801243552f14",
+         "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+         "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187"),
+    ];
+    let master = ExtendedKey::master(&seed);
+    for &(path, chain_code, key, public) in &vectors {
+        let derived = master.derive_path(path).unwrap();
+        assert_eq!(hex(&derived.chain_code), chain_code);
+        assert_eq!(hex(&derived.key), key);
+        assert_eq!(derived.keypair().address().to_string(), public);
+    }
+}
+
+#[test]
+fn restored_wallet_has_the_same_keys() {
+    let mnemonic = BIP39_VECTORS[1].1;
+    let mut wallet = Wallet::from_mnemonic(mnemonic, 1).unwrap();
+    wallet.generate_key().unwrap();
+
+    let restored = Wallet::from_mnemonic(mnemonic, 2).unwrap();
+    assert_eq!(wallet.addresses(), restored.addresses());
+    assert_eq!(restored.mnemonic(), Some(mnemonic));
+}